@@ -39,6 +39,7 @@ use crate::commit_syncers_lib::submodule_repos_with_content_ids;
 use crate::commit_syncers_lib::SubmoduleExpansionContentIds;
 use crate::git_submodules::InMemoryRepo;
 use crate::git_submodules::SubmoduleExpansionData;
+use crate::git_submodules::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 use crate::reporting::CommitSyncContext;
 use crate::sync_config_version_utils::get_mapping_change_version;
 use crate::sync_config_version_utils::get_mapping_change_version_from_hg_extra;
@@ -276,6 +277,12 @@ impl<'a, R: Repo> CommitInMemorySyncer<'a, R> {
                 small_repo_id: self.small_repo_id(),
                 large_repo: self.large_repo,
                 dangling_submodule_pointers,
+                validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                submodule_fsnode_id_cache: Default::default(),
+                check_case_insensitive_collisions: false,
+                sparse_profile_excluded_paths: HashSet::new(),
+                strict_metadata_pointer_check: false,
+                skip_missing_recursive_deps: false,
             }),
             SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
         };
@@ -388,6 +395,12 @@ impl<'a, R: Repo> CommitInMemorySyncer<'a, R> {
                         small_repo_id: self.small_repo_id(),
                         large_repo: self.large_repo,
                         dangling_submodule_pointers,
+                        validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                        submodule_fsnode_id_cache: Default::default(),
+                        check_case_insensitive_collisions: false,
+                        sparse_profile_excluded_paths: HashSet::new(),
+                        strict_metadata_pointer_check: false,
+                        skip_missing_recursive_deps: false,
                     }),
                     SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
                 };
@@ -549,6 +562,12 @@ impl<'a, R: Repo> CommitInMemorySyncer<'a, R> {
                     small_repo_id: self.small_repo_id(),
                     large_repo: self.large_repo,
                     dangling_submodule_pointers,
+                    validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                    submodule_fsnode_id_cache: Default::default(),
+                    check_case_insensitive_collisions: false,
+                    sparse_profile_excluded_paths: HashSet::new(),
+                    strict_metadata_pointer_check: false,
+                    skip_missing_recursive_deps: false,
                 }),
                 SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
             };
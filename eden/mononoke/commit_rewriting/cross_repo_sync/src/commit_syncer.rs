@@ -77,6 +77,7 @@ use crate::commit_syncers_lib::CommitSyncRepos;
 use crate::commit_syncers_lib::SyncedAncestorsVersions;
 use crate::git_submodules::InMemoryRepo;
 use crate::git_submodules::SubmoduleExpansionData;
+use crate::git_submodules::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 use crate::reporting;
 use crate::reporting::log_rewrite;
 use crate::reporting::set_scuba_logger_fields;
@@ -880,6 +881,12 @@ where
                     .as_str(),
                 small_repo_id,
                 dangling_submodule_pointers,
+                validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                submodule_fsnode_id_cache: Default::default(),
+                check_case_insensitive_collisions: false,
+                sparse_profile_excluded_paths: HashSet::new(),
+                strict_metadata_pointer_check: false,
+                skip_missing_recursive_deps: false,
             }),
             SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
         };
@@ -1019,6 +1026,12 @@ where
                     .as_str(),
                 small_repo_id,
                 dangling_submodule_pointers,
+                validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                submodule_fsnode_id_cache: Default::default(),
+                check_case_insensitive_collisions: false,
+                sparse_profile_excluded_paths: HashSet::new(),
+                strict_metadata_pointer_check: false,
+                skip_missing_recursive_deps: false,
             }),
             SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
         };
@@ -14,6 +14,7 @@ mod utils;
 mod validation;
 
 pub use expand::SubmoduleExpansionData;
+pub use expand::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 pub(crate) use expand::SubmodulePath;
 pub use in_memory_repo::InMemoryRepo;
 pub use sync::sync_commit_with_submodule_expansion;
@@ -28,5 +29,7 @@ pub(crate) use utils::root_fsnode_id_from_submodule_git_commit;
 pub(crate) use validation::validate_working_copy_of_expansion_with_recursive_submodules;
 
 pub use crate::git_submodules::utils::RepoProvider;
+pub use crate::git_submodules::validation::stream_submodule_expansion_validation;
 pub use crate::git_submodules::validation::SubmoduleExpansionValidationToken;
+pub use crate::git_submodules::validation::SubmoduleValidationOutcome;
 pub use crate::git_submodules::validation::ValidSubmoduleExpansionBonsai;
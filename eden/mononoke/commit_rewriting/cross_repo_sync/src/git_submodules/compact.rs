@@ -114,6 +114,7 @@ pub(crate) async fn compact_all_submodule_expansion_file_changes<'a, R: Repo>(
         sm_exp_data.clone(),
         bonsai,
         forward_sync_mover.clone(),
+        true, // recursive
     )
     .timed()
     .await
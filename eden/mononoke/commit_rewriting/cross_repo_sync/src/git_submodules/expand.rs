@@ -10,6 +10,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
 use std::sync::Arc;
+use std::sync::Mutex;
 
 use anyhow::anyhow;
 use anyhow::ensure;
@@ -36,6 +37,7 @@ use mononoke_types::ContentId;
 use mononoke_types::FileChange;
 use mononoke_types::FileContents;
 use mononoke_types::FileType;
+use mononoke_types::FsnodeId;
 use mononoke_types::GitLfs;
 use mononoke_types::NonRootMPath;
 use mononoke_types::RepositoryId;
@@ -97,8 +99,49 @@ pub struct SubmoduleExpansionData<'a, R: Repo> {
     /// The expansion of these commits will contain a single text file informing
     /// that the expansion belongs to a dangling submodule pointer.
     pub dangling_submodule_pointers: Vec<GitSha1>,
+    /// Maximum number of recursive submodule expansions that can be validated
+    /// concurrently. Defaults to `DEFAULT_VALIDATION_CONCURRENCY_LIMIT`, but can
+    /// be lowered on hosts where deriving fsnodes and reading blobstores for many
+    /// submodules at once would oversubscribe I/O.
+    pub validation_concurrency_limit: usize,
+    /// Cache of `root_fsnode_id_from_submodule_git_commit` results, keyed by
+    /// the submodule repo's id and the git commit hash being looked up.
+    /// Validating a single large repo commit can look up the same submodule
+    /// commit more than once, e.g. when it's referenced from more than one
+    /// recursive submodule, so sharing this cache across a whole validation
+    /// run avoids re-deriving the same `FsnodeId`.
+    pub submodule_fsnode_id_cache: Arc<Mutex<HashMap<(RepositoryId, GitSha1), FsnodeId>>>,
+    /// Whether to also report submodule/expansion entries that differ only
+    /// by case as validation errors. This is disabled by default because
+    /// Mononoke itself is case-sensitive, but should be enabled for repos
+    /// that may be checked out on a case-insensitive filesystem, where such
+    /// entries would collide with each other.
+    pub check_case_insensitive_collisions: bool,
+    /// Paths, relative to the small repo's root (i.e. starting with the
+    /// submodule path passed to `validate_submodule_expansion`), that the
+    /// large repo's sparse profile intentionally excludes from the expansion.
+    /// Validation tolerates these being absent from the expansion instead of
+    /// treating them as a broken expansion.
+    pub sparse_profile_excluded_paths: HashSet<NonRootMPath>,
+    /// When `true`, also re-derives the submodule commit's fsnode id
+    /// uncached and compares it against the value used for the fast path
+    /// (`submodule_fsnode_id == expansion_fsnode_id`), instead of trusting
+    /// whatever `submodule_fsnode_id_cache` returned. This guards against a
+    /// stale cache entry making the fast path pass even though the metadata
+    /// file's git hash no longer derives to the expected fsnode. Disabled by
+    /// default because it defeats the purpose of the cache; intended for use
+    /// when validating repos where cache correctness itself is in question.
+    pub strict_metadata_pointer_check: bool,
+    /// When `true`, a recursive submodule whose repo dependency isn't loaded
+    /// in `submodule_deps` is logged as a warning and skipped instead of
+    /// failing validation. Disabled by default; intended for partial setups
+    /// where not every recursive submodule's repo is available yet.
+    pub skip_missing_recursive_deps: bool,
 }
 
+/// Default value of `SubmoduleExpansionData::validation_concurrency_limit`.
+pub const DEFAULT_VALIDATION_CONCURRENCY_LIMIT: usize = 100;
+
 /// Used to distinguish file changes that came from the original bonsai or
 /// were generated by the submodule expansion logic.
 /// This is needed to make sure that if the original bonsai makes changes to
@@ -151,6 +151,7 @@ pub async fn sync_commit_with_submodule_expansion<'a, R: Repo>(
                     sm_exp_data,
                     rewritten_bonsai,
                     movers.mover,
+                    true, // recursive
                 )
                 .timed()
                 .await
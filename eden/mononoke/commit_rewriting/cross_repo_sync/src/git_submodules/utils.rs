@@ -309,11 +309,54 @@ pub(crate) async fn root_fsnode_id_from_submodule_git_commit(
         .repo_derived_data()
         .derive::<RootFsnodeId>(ctx, cs_id)
         .await
-        .context("Failed to derive RootFsnodeId")?;
+        .with_context(|| {
+            format!(
+                "Fsnode not derived for commit {git_hash} (changeset {cs_id}) in submodule repo {0}. Backfill RootFsnodeId derived data for this repo before validating its expansion.",
+                repo.repo_identity().name()
+            )
+        })?;
 
     Ok(submodule_root_fsnode_id.into_fsnode_id())
 }
 
+/// Cached wrapper around `root_fsnode_id_from_submodule_git_commit`, using
+/// `sm_exp_data`'s `submodule_fsnode_id_cache` to avoid re-deriving the
+/// fsnode id of a submodule commit that's already been looked up earlier in
+/// the same validation run.
+pub(crate) async fn cached_root_fsnode_id_from_submodule_git_commit<'a, R: Repo>(
+    ctx: &CoreContext,
+    sm_exp_data: &SubmoduleExpansionData<'a, R>,
+    repo: &R,
+    git_hash: GitSha1,
+) -> Result<FsnodeId> {
+    let cache_key = (repo.repo_identity().id(), git_hash);
+
+    if let Some(fsnode_id) = sm_exp_data
+        .submodule_fsnode_id_cache
+        .lock()
+        .unwrap()
+        .get(&cache_key)
+    {
+        return Ok(*fsnode_id);
+    }
+
+    let fsnode_id = root_fsnode_id_from_submodule_git_commit(
+        ctx,
+        repo,
+        git_hash,
+        &sm_exp_data.dangling_submodule_pointers,
+    )
+    .await?;
+
+    sm_exp_data
+        .submodule_fsnode_id_cache
+        .lock()
+        .unwrap()
+        .insert(cache_key, fsnode_id);
+
+    Ok(fsnode_id)
+}
+
 /// Build a new submodule dependency map to expand/validate recursive submodules
 /// under a given submodule.
 /// It removes the path of the given submodule from all the entries that are
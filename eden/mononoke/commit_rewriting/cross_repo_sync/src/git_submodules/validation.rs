@@ -10,7 +10,6 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
 
-use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 use async_recursion::async_recursion;
@@ -20,8 +19,10 @@ use cloned::cloned;
 use context::CoreContext;
 use derived_data::macro_export::BonsaiDerivable;
 use either::Either;
+use filestore::FetchKey;
 use fsnodes::RootFsnodeId;
 use futures::stream;
+use futures::stream::BoxStream;
 use futures::stream::TryStreamExt;
 use futures::StreamExt;
 use futures_stats::TimedFutureExt;
@@ -32,18 +33,25 @@ use mononoke_types::fsnode::Fsnode;
 use mononoke_types::fsnode::FsnodeDirectory;
 use mononoke_types::fsnode::FsnodeEntry;
 use mononoke_types::fsnode::FsnodeFile;
+use mononoke_types::hash::GitSha1;
 use mononoke_types::BonsaiChangeset;
+use mononoke_types::ContentId;
 use mononoke_types::FileChange;
 use mononoke_types::FileType;
+use mononoke_types::path::MPath;
 use mononoke_types::FsnodeId;
 use mononoke_types::MPathElement;
 use mononoke_types::NonRootMPath;
 use movers::Mover;
+use repo_blobstore::RepoBlobstoreRef;
 use scuba_ext::FutureStatsScubaExt;
+use sorted_vector_map::SortedVectorMap;
+use thiserror::Error;
 
 use crate::git_submodules::expand::SubmoduleExpansionData;
 use crate::git_submodules::expand::SubmodulePath;
 use crate::git_submodules::utils::build_recursive_submodule_deps;
+use crate::git_submodules::utils::cached_root_fsnode_id_from_submodule_git_commit;
 use crate::git_submodules::utils::content_id_of_file_with_type;
 use crate::git_submodules::utils::get_git_hash_from_submodule_file;
 use crate::git_submodules::utils::get_x_repo_submodule_metadata_file_path;
@@ -54,8 +62,128 @@ use crate::git_submodules::utils::x_repo_submodule_metadata_file_basename;
 use crate::reporting::log_debug;
 use crate::reporting::log_error;
 use crate::reporting::log_trace;
+use crate::reporting::log_warning;
 use crate::types::Repo;
 
+/// Errors that can occur while validating that a bonsai changeset in the large
+/// repo is a valid expansion of its submodule(s).
+#[derive(Debug, Error)]
+pub(crate) enum SubmoduleValidationError {
+    #[error("Mover failed to provide submodule path {0} in the large repo")]
+    MoverPathNotMapped(NonRootMPath),
+
+    #[error("Submodule paths {0} and {1} both map to {2} in the large repo")]
+    MoverPathCollision(NonRootMPath, NonRootMPath, NonRootMPath),
+
+    #[error(
+        "Expansion of submodule {submodule_path} changed without updating its metadata file {metadata_file_path}"
+    )]
+    MetadataFileNotUpdated {
+        submodule_path: NonRootMPath,
+        metadata_file_path: NonRootMPath,
+    },
+
+    #[error(
+        "Submodule metadata file is being deleted without removing the entire submodule expansion"
+    )]
+    MetadataFileDeletedWithoutExpansion,
+
+    #[error("Path of submodule expansion in large repo contains a file, not a directory")]
+    ExpansionPathIsFile,
+
+    #[error("No fsnode entry found in submodule expansion path in large repo")]
+    ExpansionPathNotFound,
+
+    #[error("Path {0} is in submodule manifest but not in expansion")]
+    PathMissingFromExpansion(MPathElement),
+
+    #[error("Found files in the expansion that are not in the submodule")]
+    UnexpectedFilesInExpansion,
+
+    #[error("Path present in submodule manifest can't be a file in expansion")]
+    ExpectedDirectoryInExpansion,
+
+    #[error("{entry_kind} present in {location} are unaccounted for: {}", paths.join(", "))]
+    UnaccountedEntries {
+        entry_kind: &'static str,
+        location: &'static str,
+        /// Basenames of the unaccounted-for entries, e.g. an orphaned
+        /// submodule metadata file left behind when its expansion directory
+        /// was removed without also removing the metadata file.
+        paths: Vec<String>,
+    },
+
+    #[error("Path {0} should be a GitSubmodule file in the submodule's manifest")]
+    ExpectedGitSubmoduleFile(MPathElement),
+
+    #[error(
+        "Directory {0} differs between the submodule and its expansion, but contains no submodule file or subdirectory, so the difference can't be a missing submodule expansion"
+    )]
+    DirectoryDiffersWithoutSubmoduleExpansion(MPathElement),
+
+    #[error("Submodule entry for path {path} has to be a submodule file, but is a {actual_file_type:?} file instead")]
+    NotAGitSubmoduleFile {
+        path: MPathElement,
+        actual_file_type: FileType,
+    },
+
+    #[error(
+        "Metadata file {metadata_basename} not found in path {expansion_path} where expansion should be"
+    )]
+    MetadataFileNotFound {
+        metadata_basename: MPathElement,
+        expansion_path: MPathElement,
+    },
+
+    #[error("Recursive submodule not loaded for path {0}")]
+    RecursiveSubmoduleNotLoaded(NonRootMPath),
+
+    #[error("Submodule metadata file {0} is empty")]
+    EmptyMetadataFile(NonRootMPath),
+
+    #[error(
+        "Symlink expansion of submodule {submodule_path} should point to commit {expected}, but points to {actual}"
+    )]
+    SymlinkExpansionTargetMismatch {
+        submodule_path: NonRootMPath,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(
+        "Submodule {submodule_path} expansion changed, and a metadata file was changed at {actual_metadata_file_path}, but the expected metadata file path is {expected_metadata_file_path}. The `x_repo_submodule_metadata_file_prefix` in the small repo sync config is likely misconfigured."
+    )]
+    MetadataFilePrefixMismatch {
+        submodule_path: NonRootMPath,
+        expected_metadata_file_path: NonRootMPath,
+        actual_metadata_file_path: NonRootMPath,
+    },
+
+    #[error(
+        "Submodule entry {submodule_path} and expansion entry {expansion_path} differ only by case, which would collide on a case-insensitive filesystem"
+    )]
+    CaseInsensitiveCollision {
+        submodule_path: MPathElement,
+        expansion_path: MPathElement,
+    },
+
+    #[error(
+        "Submodule metadata file {metadata_file_path}'s content id {content_id} is missing from the large repo blobstore"
+    )]
+    MetadataFileBlobMissing {
+        metadata_file_path: NonRootMPath,
+        content_id: ContentId,
+    },
+
+    #[error(
+        "Submodule metadata file {metadata_file_path} points to git commit {git_hash}, but re-deriving its fsnode id without the cache returned a different fsnode than the cached lookup used to validate this expansion"
+    )]
+    StaleMetadataPointer {
+        metadata_file_path: NonRootMPath,
+        git_hash: GitSha1,
+    },
+}
+
 /// A wrapper over BonsaiChangeset that can only be created by running submodule
 /// expansion validation on a bonsai.
 /// This type will be used as input of any functions that require a bonsai
@@ -80,13 +208,24 @@ impl ValidSubmoduleExpansionBonsai {
         // TODO(T179533620): fetch mover from commit sync config, instead of
         // requiring it to be provided by callers.
         mover: Mover,
+        // Whether to validate recursive submodules as well. Set to false for
+        // fast pre-checks that only need to confirm the immediate submodule's
+        // expansion matches, without descending into its own submodules.
+        recursive: bool,
     ) -> Result<ValidSubmoduleExpansionBonsai> {
         // For every submodule dependency, get all changes in their directories.
 
+        // Make sure the mover doesn't map two different submodule paths to the
+        // same path in the large repo, which would make validation below check
+        // the wrong submodule's expansion against the path.
+        ensure_no_mover_path_collisions(sm_exp_data.submodule_deps.keys(), &mover)?;
+
         // Iterate over the submodule dependency paths.
         // Create a map grouping the file changes per submodule dependency.
 
-        let bonsai_res: Result<BonsaiChangeset> =
+        let total_submodules = sm_exp_data.submodule_deps.len();
+
+        let (stats, bonsai_res): (_, Result<BonsaiChangeset>) =
             stream::iter(sm_exp_data.submodule_deps.iter().map(anyhow::Ok))
                 .try_fold(bonsai, |bonsai, (submodule_path, submodule_repo)| {
                     cloned!(mover, sm_exp_data);
@@ -98,6 +237,7 @@ impl ValidSubmoduleExpansionBonsai {
                             submodule_path,
                             submodule_repo.as_ref(),
                             mover,
+                            recursive,
                         )
                         .timed()
                         .await
@@ -109,17 +249,62 @@ impl ValidSubmoduleExpansionBonsai {
                         .with_context(|| format!("Validation of submodule {submodule_path} failed"))
                     }
                 })
+                .timed()
                 .await;
 
         if let Err(err) = &bonsai_res {
             log_error(ctx, format!("Submodule validation failed: {err:#?}"));
         }
 
+        let mut summary_scuba = ctx.scuba().clone();
+        summary_scuba
+            .add_future_stats(&stats)
+            .add("total_submodules", total_submodules)
+            .add("failed_submodules", if bonsai_res.is_err() { 1 } else { 0 });
+        summary_scuba.log_with_msg("Validated all submodule expansions", None);
+
         bonsai_res.map(|bonsai| {
             ValidSubmoduleExpansionBonsai(bonsai, SubmoduleExpansionValidationToken(()))
         })
     }
 
+    /// Validate a single submodule's expansion in a bonsai **from the large
+    /// repo**, given the already-derived fsnode id of that expansion.
+    ///
+    /// This is a narrower version of `validate_all_submodule_expansions`, for
+    /// callers (e.g. tooling that already walked the large repo's manifest)
+    /// that already have `expansion_fsnode_id` at hand and want to avoid
+    /// paying for `RootFsnodeId` derivation again just to validate one
+    /// submodule.
+    pub async fn validate_one_submodule_expansion_with_fsnode_id<'a, R: Repo>(
+        ctx: &'a CoreContext,
+        sm_exp_data: SubmoduleExpansionData<'a, R>,
+        bonsai: BonsaiChangeset,
+        submodule_path: &'a NonRootMPath,
+        submodule_repo: &'a R,
+        mover: Mover,
+        recursive: bool,
+        expansion_fsnode_id: FsnodeId,
+    ) -> Result<ValidSubmoduleExpansionBonsai> {
+        let bonsai = validate_submodule_expansion_with_fsnode_id(
+            ctx,
+            sm_exp_data,
+            bonsai,
+            submodule_path,
+            submodule_repo,
+            mover,
+            recursive,
+            expansion_fsnode_id,
+        )
+        .await
+        .with_context(|| format!("Validation of submodule {submodule_path} failed"))?;
+
+        Ok(ValidSubmoduleExpansionBonsai(
+            bonsai,
+            SubmoduleExpansionValidationToken(()),
+        ))
+    }
+
     pub fn into_inner(self) -> BonsaiChangeset {
         self.0
     }
@@ -128,6 +313,96 @@ impl ValidSubmoduleExpansionBonsai {
     }
 }
 
+/// Result of validating a single submodule's expansion, as yielded by
+/// `stream_submodule_expansion_validation`.
+#[derive(Debug)]
+pub struct SubmoduleValidationOutcome {
+    pub submodule_path: NonRootMPath,
+    pub result: Result<()>,
+}
+
+/// Like `ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions`,
+/// but returns a stream that yields one `SubmoduleValidationOutcome` as soon
+/// as each submodule's validation completes, instead of waiting for every
+/// submodule to be validated before returning anything. Meant for callers
+/// that want to report progress (e.g. a UI) while validation is still
+/// running on the remaining submodules.
+///
+/// Whether the bonsai as a whole is valid can still be derived from the
+/// stream, by checking that every yielded outcome's `result` is `Ok`.
+pub fn stream_submodule_expansion_validation<'a, R: Repo>(
+    ctx: &'a CoreContext,
+    sm_exp_data: SubmoduleExpansionData<'a, R>,
+    bonsai: BonsaiChangeset,
+    mover: Mover,
+    recursive: bool,
+) -> Result<BoxStream<'a, Result<SubmoduleValidationOutcome>>> {
+    // Check this eagerly, since it validates across all submodule paths at
+    // once and can't be attributed to any single item of the stream below.
+    ensure_no_mover_path_collisions(sm_exp_data.submodule_deps.keys(), &mover)?;
+
+    let stream = stream::iter(sm_exp_data.submodule_deps.iter())
+        .map(move |(submodule_path, submodule_repo)| {
+            cloned!(mover, sm_exp_data, bonsai);
+            async move {
+                let result = validate_submodule_expansion(
+                    ctx,
+                    sm_exp_data,
+                    bonsai,
+                    submodule_path,
+                    submodule_repo.as_ref(),
+                    mover,
+                    recursive,
+                )
+                .timed()
+                .await
+                .log_future_stats(
+                    ctx.scuba().clone(),
+                    "Validating submodule expansion",
+                    format!("Submodule path: {submodule_path}"),
+                )
+                .with_context(|| format!("Validation of submodule {submodule_path} failed"))
+                .map(|_bonsai| ());
+
+                anyhow::Ok(SubmoduleValidationOutcome {
+                    submodule_path: submodule_path.clone(),
+                    result,
+                })
+            }
+        })
+        .buffer_unordered(sm_exp_data.validation_concurrency_limit)
+        .boxed();
+
+    Ok(stream)
+}
+
+/// Apply the mover to every submodule path and error out if two different
+/// submodule paths end up mapped to the same path in the large repo. If that
+/// happened, `validate_submodule_expansion` would compare a submodule's
+/// expansion against the wrong git commit.
+fn ensure_no_mover_path_collisions<'a>(
+    submodule_paths: impl Iterator<Item = &'a NonRootMPath>,
+    mover: &Mover,
+) -> Result<()> {
+    let mut synced_paths: HashMap<NonRootMPath, &'a NonRootMPath> = HashMap::new();
+
+    for submodule_path in submodule_paths {
+        let synced_path = mover(submodule_path)?
+            .ok_or_else(|| SubmoduleValidationError::MoverPathNotMapped(submodule_path.clone()))?;
+
+        if let Some(colliding_path) = synced_paths.insert(synced_path.clone(), submodule_path) {
+            return Err(SubmoduleValidationError::MoverPathCollision(
+                colliding_path.clone(),
+                submodule_path.clone(),
+                synced_path,
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
 /// Validate that a bonsai in the large repo is valid for a given submodule repo
 /// repo.
 /// Among other things, it will assert that
@@ -149,6 +424,70 @@ async fn validate_submodule_expansion<'a, R: Repo>(
     submodule_path: &'a NonRootMPath,
     submodule_repo: &'a R,
     mover: Mover,
+    // Whether to validate recursive submodules as well. See
+    // `ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions`.
+    recursive: bool,
+) -> Result<BonsaiChangeset> {
+    validate_submodule_expansion_impl(
+        ctx,
+        sm_exp_data,
+        bonsai,
+        submodule_path,
+        submodule_repo,
+        mover,
+        recursive,
+        None,
+    )
+    .await
+}
+
+/// Same as `validate_submodule_expansion`, but allows the caller to pass in
+/// the fsnode id of the submodule's expansion in the large repo, instead of
+/// having it derived here. Useful for callers that already derived the large
+/// repo's manifest for this bonsai (e.g. to walk the whole tree) and want to
+/// avoid deriving it again just to validate this submodule.
+async fn validate_submodule_expansion_with_fsnode_id<'a, R: Repo>(
+    ctx: &'a CoreContext,
+    sm_exp_data: SubmoduleExpansionData<'a, R>,
+    // Bonsai from the large repo
+    bonsai: BonsaiChangeset,
+    submodule_path: &'a NonRootMPath,
+    submodule_repo: &'a R,
+    mover: Mover,
+    // Whether to validate recursive submodules as well. See
+    // `ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions`.
+    recursive: bool,
+    // Already-derived fsnode id of the submodule's expansion in the large
+    // repo, at the path this submodule is expanded to by `mover`.
+    expansion_fsnode_id: FsnodeId,
+) -> Result<BonsaiChangeset> {
+    validate_submodule_expansion_impl(
+        ctx,
+        sm_exp_data,
+        bonsai,
+        submodule_path,
+        submodule_repo,
+        mover,
+        recursive,
+        Some(expansion_fsnode_id),
+    )
+    .await
+}
+
+async fn validate_submodule_expansion_impl<'a, R: Repo>(
+    ctx: &'a CoreContext,
+    sm_exp_data: SubmoduleExpansionData<'a, R>,
+    // Bonsai from the large repo
+    bonsai: BonsaiChangeset,
+    submodule_path: &'a NonRootMPath,
+    submodule_repo: &'a R,
+    mover: Mover,
+    // Whether to validate recursive submodules as well. See
+    // `ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions`.
+    recursive: bool,
+    // Fsnode id of the submodule's expansion in the large repo, if already
+    // known. If `None`, it will be derived from `bonsai`.
+    precomputed_expansion_fsnode_id: Option<FsnodeId>,
 ) -> Result<BonsaiChangeset> {
     log_debug(
         ctx,
@@ -172,19 +511,18 @@ async fn validate_submodule_expansion<'a, R: Repo>(
 
     // Submodule path in the large repo, after calling the mover(e.g. to prepend
     // the small repo's path).
-    let synced_submodule_path = mover(submodule_path)?.ok_or(anyhow!(
-        "Mover failed to provide submodule path in the large repo"
-    ))?;
+    let synced_submodule_path = mover(submodule_path)?
+        .ok_or_else(|| SubmoduleValidationError::MoverPathNotMapped(submodule_path.clone()))?;
+
+    // `synced_submodule_path` is a `NonRootMPath`, which can never represent
+    // the repo root, so it's always a proper subdirectory of the large repo.
+    // This is relied upon by `is_prefix_of`/`find_entry` below.
 
     // TODO(gustavoavena): make this more efficient using `range`
     let submodule_expansion_changed = bonsai
         .file_changes()
         .any(|(p, _fc)| synced_submodule_path.is_prefix_of(p));
 
-    // TODO(T179533620): confirm that the submodule expansion actually
-    // exists in this path OR stop using submodule dependencies from all
-    // commit sync config versions in history (T184633369)
-
     let synced_submodule_path = SubmodulePath(synced_submodule_path);
 
     let metadata_file_path = get_x_repo_submodule_metadata_file_path(
@@ -201,16 +539,42 @@ async fn validate_submodule_expansion<'a, R: Repo>(
         None => {
             if !submodule_expansion_changed {
                 // Metadata file didn't change but its submodule expansion also
-                // wasn't changed.
-                // Return early in this case to avoid deriving fsnodes for
-                // the large repo bonsai
+                // wasn't changed. Confirm the expansion actually exists at
+                // this path before returning early: if it doesn't, the mover
+                // is mapping this submodule to a path that was never
+                // expanded in the large repo (e.g. a stale submodule
+                // dependency kept around from an older commit sync config
+                // version), and this would otherwise silently pass
+                // validation without ever comparing anything.
+                confirm_submodule_expansion_exists(ctx, &sm_exp_data, &bonsai, &synced_submodule_path)
+                    .await?;
                 return Ok(bonsai);
             }
 
+            // Sanity check: the expansion changed, but no file change was
+            // found at the metadata file path we just computed from the
+            // configured prefix. If one of the changed paths actually looks
+            // like this submodule's metadata file (same parent directory,
+            // `.<prefix>-<basename>` naming shape) but isn't at the path we
+            // computed, `x_repo_submodule_metadata_file_prefix` is most
+            // likely misconfigured, and silently falling through below would
+            // make validation pass despite the metadata file we just read
+            // being the wrong one (or no metadata file at all).
+            if let Some(actual_metadata_file_path) =
+                find_mismatched_metadata_file_path(fc_map, &synced_submodule_path, &metadata_file_path)
+            {
+                return Err(SubmoduleValidationError::MetadataFilePrefixMismatch {
+                    submodule_path: submodule_path.clone(),
+                    expected_metadata_file_path: metadata_file_path.clone(),
+                    actual_metadata_file_path,
+                }
+                .into());
+            }
+
             // Check if the submodule metadata file existed in any of the
             // parents. If it did, it means that a submodule expansion is
             // being modified without properly updating the metadata file.
-            let submodule_metadata_file_exists = stream::iter(bonsai.parents())
+            let submodule_metadata_file_parent_content_ids = stream::iter(bonsai.parents())
                 .map(|cs_id| {
                     content_id_of_file_with_type(
                         ctx,
@@ -223,7 +587,15 @@ async fn validate_submodule_expansion<'a, R: Repo>(
                 .buffer_unordered(10)
                 .boxed()
                 .try_collect::<Vec<_>>()
-                .await?
+                .timed()
+                .await
+                .log_future_stats(
+                    ctx.scuba().clone(),
+                    "Checking if submodule metadata file existed in parents",
+                    format!("Metadata file path: {}", &metadata_file_path),
+                )?;
+
+            let submodule_metadata_file_exists = submodule_metadata_file_parent_content_ids
                 .into_iter()
                 // If a content id is returned, the submodule metadata file
                 // existed in the parent changeset
@@ -232,9 +604,11 @@ async fn validate_submodule_expansion<'a, R: Repo>(
             // This means that the metadata file wasn't modified
             if submodule_metadata_file_exists {
                 // Submodule expansion changed, but the metadata file wasn't updated
-                return Err(anyhow!(
-                    "Expansion of submodule {submodule_path} changed without updating its metadata file {metadata_file_path}"
-                ));
+                return Err(SubmoduleValidationError::MetadataFileNotUpdated {
+                    submodule_path: submodule_path.clone(),
+                    metadata_file_path: metadata_file_path.clone(),
+                }
+                .into());
             };
 
             // Path that might have been a submodule expansion before was
@@ -265,17 +639,60 @@ async fn validate_submodule_expansion<'a, R: Repo>(
 
     let large_repo = sm_exp_data.large_repo.clone();
 
+    // Check that the metadata file's content blob actually exists in the
+    // large repo blobstore before reading it, so a dangling content id (e.g.
+    // from a corrupted or partially-synced blobstore) produces a clear error
+    // naming the offending path and content id, instead of the generic
+    // fetch failure `filestore::fetch_concat` would otherwise surface.
+    if !filestore::exists(
+        large_repo.repo_blobstore(),
+        ctx,
+        &FetchKey::Canonical(metadata_file_content_id),
+    )
+    .await
+    .context("Failed to check existence of submodule metadata file blob")?
+    {
+        return Err(SubmoduleValidationError::MetadataFileBlobMissing {
+            metadata_file_path: metadata_file_path.clone(),
+            content_id: metadata_file_content_id,
+        }
+        .into());
+    }
+
+    // Check for the empty/whitespace-only metadata file case explicitly, so
+    // that callers get a clear error naming the offending path instead of
+    // the confusing parse error that `git_hash_from_submodule_metadata_file`
+    // would produce trying to parse a git hash out of no (or blank) content.
+    let metadata_file_content =
+        filestore::fetch_concat(large_repo.repo_blobstore(), ctx, metadata_file_content_id)
+            .timed()
+            .await
+            .log_future_stats(
+                ctx.scuba().clone(),
+                "Reading submodule metadata file",
+                format!("Metadata file path: {}", &metadata_file_path),
+            )
+            .context("Failed to fetch content of submodule metadata file")?;
+    if std::str::from_utf8(&metadata_file_content)
+        .map(|s| s.trim().is_empty())
+        .unwrap_or(false)
+    {
+        return Err(
+            SubmoduleValidationError::EmptyMetadataFile(metadata_file_path.clone()).into(),
+        );
+    }
+
     let git_hash =
         git_hash_from_submodule_metadata_file(ctx, &large_repo, metadata_file_content_id).await?;
 
     // This is the root fsnode from the submodule at the commit the submodule
     // metadata file points to.
 
-    let submodule_fsnode_id = root_fsnode_id_from_submodule_git_commit(
+    let submodule_fsnode_id = cached_root_fsnode_id_from_submodule_git_commit(
         ctx,
+        &sm_exp_data,
         submodule_repo,
         git_hash,
-        &sm_exp_data.dangling_submodule_pointers,
     )
     .timed()
     .await
@@ -289,22 +706,63 @@ async fn validate_submodule_expansion<'a, R: Repo>(
     // STEP 3: Get the fsnode from the expansion of the submodule in the large
     // repo and compare it with the fsnode from the submodule commit.
 
-    let expansion_fsnode_id = get_submodule_expansion_fsnode_id(
-        ctx,
-        sm_exp_data.clone(),
-        &bonsai,
-        &synced_submodule_path,
-    )
-    .timed()
-    .await
-    .log_future_stats(
-        ctx.scuba().clone(),
-        "Get submodule expansion fsnode id",
-        format!("Synced submodule path: {}", &synced_submodule_path),
-    )
-    .context("Failed to get submodule expansion fsnode id")?;
+    let expansion_entry = match precomputed_expansion_fsnode_id {
+        Some(fsnode_id) => SubmoduleExpansionEntry::Directory(fsnode_id),
+        None => get_submodule_expansion_entry(
+            ctx,
+            sm_exp_data.clone(),
+            &bonsai,
+            &synced_submodule_path,
+        )
+        .timed()
+        .await
+        .log_future_stats(
+            ctx.scuba().clone(),
+            "Get submodule expansion fsnode id",
+            format!("Synced submodule path: {}", &synced_submodule_path),
+        )
+        .context("Failed to get submodule expansion fsnode id")?,
+    };
+
+    let expansion_fsnode_id = match expansion_entry {
+        SubmoduleExpansionEntry::Directory(fsnode_id) => fsnode_id,
+        SubmoduleExpansionEntry::Symlink(content_id) => {
+            return validate_symlink_submodule_expansion(
+                ctx,
+                &sm_exp_data,
+                content_id,
+                git_hash,
+                submodule_path,
+                bonsai,
+            )
+            .await;
+        }
+    };
 
     if submodule_fsnode_id == expansion_fsnode_id {
+        if sm_exp_data.strict_metadata_pointer_check {
+            // The fast path above trusts whatever `submodule_fsnode_id_cache`
+            // returned for `git_hash`. Re-derive it without the cache to
+            // confirm the metadata pointer's fsnode isn't stale before
+            // accepting the fast path.
+            let uncached_submodule_fsnode_id = root_fsnode_id_from_submodule_git_commit(
+                ctx,
+                submodule_repo,
+                git_hash,
+                &sm_exp_data.dangling_submodule_pointers,
+            )
+            .await
+            .context("Failed to re-derive submodule fsnode id for strict metadata pointer check")?;
+
+            if uncached_submodule_fsnode_id != submodule_fsnode_id {
+                return Err(SubmoduleValidationError::StaleMetadataPointer {
+                    metadata_file_path: metadata_file_path.clone(),
+                    git_hash,
+                }
+                .into());
+            }
+        }
+
         // If fsnodes are an exact match, there are no recursive submodules and the
         // working copy is the same.
         log_trace(
@@ -314,6 +772,14 @@ async fn validate_submodule_expansion<'a, R: Repo>(
         return Ok(bonsai);
     };
 
+    if !recursive {
+        log_trace(
+            ctx,
+            "Skipping validation of recursive submodules, as requested",
+        );
+        return Ok(bonsai);
+    }
+
     // Build a new submodule deps map, removing the prefix of the submodule path
     // being validated, so it can be used to validate any recursive submodule
     // being expanded in it.
@@ -329,6 +795,7 @@ async fn validate_submodule_expansion<'a, R: Repo>(
         submodule_repo,
         expansion_fsnode_id,
         submodule_fsnode_id,
+        MPath::from(submodule_path.clone()),
     )
     .timed()
     .await
@@ -383,6 +850,14 @@ async fn _ensure_submodule_expansion_deletion<'a, R: Repo>(
         return Ok(bonsai);
     }
 
+    // A root commit has no parents, so there's no prior expansion for it to
+    // have inherited and no metadata file it could be deleting. Treat "no
+    // parents" as trivially satisfying the deletion check rather than
+    // relying on `try_all` over an empty stream to do it implicitly.
+    if bonsai.parents().next().is_none() {
+        return Ok(bonsai);
+    }
+
     // Get all the files under the submodule expansion path in the parent
     // changesets.
     // A `FileChange::Deletion` should exist in the bonsai for all of these
@@ -419,31 +894,121 @@ async fn _ensure_submodule_expansion_deletion<'a, R: Repo>(
         .await?;
 
     if !entire_submodule_expansion_was_deleted {
-        return Err(anyhow!(
-            "Submodule metadata file is being deleted without removing the entire submodule expansion"
-        ));
+        return Err(SubmoduleValidationError::MetadataFileDeletedWithoutExpansion.into());
     }
 
     Ok(bonsai)
 }
 
-/// Get the fsnode of a submodule expansion in the large repo.
+/// Confirm that a submodule's expansion actually exists in the large repo at
+/// `synced_submodule_path`, for the case where this commit doesn't touch the
+/// expansion or its metadata file at all, so its tree is identical to
+/// whatever the first parent has there.
+///
+/// Without this check, a submodule dependency that the mover maps to a path
+/// that was never actually expanded in the large repo (e.g. a stale entry
+/// kept around from an older commit sync config version) would silently
+/// pass validation, since there would be nothing to compare against.
+async fn confirm_submodule_expansion_exists<'a, R: Repo>(
+    ctx: &'a CoreContext,
+    sm_exp_data: &SubmoduleExpansionData<'a, R>,
+    bonsai: &BonsaiChangeset,
+    synced_submodule_path: &NonRootMPath,
+) -> Result<()> {
+    let parent_cs_id = match bonsai.parents().next() {
+        Some(cs_id) => cs_id,
+        // A root commit can't inherit an expansion from a parent, and this
+        // is only reached when the expansion wasn't touched by this commit
+        // either, so there's nothing it could have expanded to.
+        None => return Err(SubmoduleValidationError::ExpansionPathNotFound.into()),
+    };
+
+    let large_repo = &sm_exp_data.large_repo;
+    let parent_root_fsnode_id = large_repo
+        .repo_derived_data()
+        .derive::<RootFsnodeId>(ctx, parent_cs_id)
+        .await
+        .context("Failed to derive parent fsnode to confirm submodule expansion exists")?
+        .into_fsnode_id();
+
+    let expansion_entry = parent_root_fsnode_id
+        .find_entry(
+            ctx.clone(),
+            large_repo.repo_blobstore_arc(),
+            synced_submodule_path.clone().into(),
+        )
+        .await
+        .context("Getting fsnode entry to confirm submodule expansion exists")?;
+
+    match expansion_entry {
+        Some(Entry::Tree(_)) => Ok(()),
+        Some(Entry::Leaf(_)) => Err(SubmoduleValidationError::ExpansionPathIsFile.into()),
+        None => Err(SubmoduleValidationError::ExpansionPathNotFound.into()),
+    }
+}
+
+/// Look for a changed path that has the naming shape of this submodule's
+/// x-repo metadata file (i.e. sits in the same parent directory and has a
+/// `.<prefix>-<submodule_basename>` basename) but isn't
+/// `expected_metadata_file_path`, the path actually computed from the
+/// configured prefix. Finding one means `x_repo_submodule_metadata_file_prefix`
+/// is most likely misconfigured, since the real lookup by
+/// `expected_metadata_file_path` would otherwise silently miss it.
+fn find_mismatched_metadata_file_path(
+    fc_map: &SortedVectorMap<NonRootMPath, FileChange>,
+    synced_submodule_path: &NonRootMPath,
+    expected_metadata_file_path: &NonRootMPath,
+) -> Option<NonRootMPath> {
+    let (expected_parent_dir, submodule_basename) = synced_submodule_path.split_dirname();
+    let basename_suffix = format!("-{submodule_basename}");
+
+    fc_map.keys().find_map(|path| {
+        if path == expected_metadata_file_path {
+            return None;
+        }
+
+        let (parent_dir, basename) = path.split_dirname();
+        if parent_dir != expected_parent_dir {
+            return None;
+        }
+
+        let basename = basename.to_string();
+        if basename.starts_with('.') && basename.ends_with(&basename_suffix) {
+            Some(path.clone())
+        } else {
+            None
+        }
+    })
+}
+
+/// The fsnode entry found at a submodule's expansion path in the large repo.
+/// Usually the expansion is a directory containing the submodule's full
+/// working copy, but some configurations instead expand a submodule as a
+/// symlink pointing at a content-addressed location, in which case there's
+/// no working copy to compare against directly.
+enum SubmoduleExpansionEntry {
+    Directory(FsnodeId),
+    Symlink(ContentId),
+}
+
+/// Get the fsnode entry of a submodule expansion in the large repo.
 /// It will be used to compare it with the one from the submodule commit
 /// being expanded.
-async fn get_submodule_expansion_fsnode_id<'a, R: Repo>(
+async fn get_submodule_expansion_entry<'a, R: Repo>(
     ctx: &'a CoreContext,
     sm_exp_data: SubmoduleExpansionData<'a, R>,
     // Bonsai from the large repo
     bonsai: &'a BonsaiChangeset,
     synced_submodule_path: &NonRootMPath,
-) -> Result<FsnodeId> {
+) -> Result<SubmoduleExpansionEntry> {
     let large_repo = sm_exp_data.large_repo.clone();
 
     let large_repo_blobstore = large_repo.repo_blobstore_arc();
     let large_repo_derived_data = large_repo.repo_derived_data();
 
     // Get the root fsnodes from the parent commits, so the one from this commit
-    // can be derived.
+    // can be derived. For a root commit this is simply empty, and
+    // `derive_single` below derives the fsnode from the bonsai alone.
     let parent_root_fsnodes = stream::iter(bonsai.parents())
         .then(|cs_id| large_repo_derived_data.derive::<RootFsnodeId>(ctx, cs_id))
         .boxed()
@@ -486,21 +1051,202 @@ async fn get_submodule_expansion_fsnode_id<'a, R: Repo>(
         .await
         .context("Getting fsnode entry for submodule expansion in target repo")?;
 
-    let expansion_fsnode_id = match expansion_fsnode_entry {
-        Some(Entry::Tree(fsnode_id)) => fsnode_id,
+    let expansion_entry = match expansion_fsnode_entry {
+        Some(Entry::Tree(fsnode_id)) => SubmoduleExpansionEntry::Directory(fsnode_id),
+        Some(Entry::Leaf(fsnode_file)) if *fsnode_file.file_type() == FileType::Symlink => {
+            SubmoduleExpansionEntry::Symlink(*fsnode_file.content_id())
+        }
         Some(Entry::Leaf(_)) => {
-            return Err(anyhow!(
-                "Path of submodule expansion in large repo contains a file, not a directory"
-            ));
+            return Err(SubmoduleValidationError::ExpansionPathIsFile.into());
         }
         None => {
-            return Err(anyhow!(
-                "No fsnode entry found in submodule expansion path in large repo"
-            ));
+            return Err(SubmoduleValidationError::ExpansionPathNotFound.into());
         }
     };
 
-    Ok(expansion_fsnode_id)
+    Ok(expansion_entry)
+}
+
+/// Validate a submodule that's expanded in the large repo as a symlink
+/// pointing at a content-addressed location, rather than a full working
+/// copy directory. The symlink's target is expected to end with the
+/// hex-encoded git hash of the commit the submodule metadata file points to,
+/// so that it addresses exactly that commit's content.
+///
+/// There's no working copy to recurse into here, so recursive submodules
+/// aren't validated in this case.
+async fn validate_symlink_submodule_expansion<'a, R: Repo>(
+    ctx: &'a CoreContext,
+    sm_exp_data: &SubmoduleExpansionData<'a, R>,
+    symlink_content_id: ContentId,
+    git_hash: GitSha1,
+    submodule_path: &'a NonRootMPath,
+    bonsai: BonsaiChangeset,
+) -> Result<BonsaiChangeset> {
+    let large_repo = &sm_exp_data.large_repo;
+
+    let symlink_target =
+        filestore::fetch_concat(large_repo.repo_blobstore(), ctx, symlink_content_id)
+            .await
+            .context("Failed to fetch content of submodule expansion symlink")?;
+    let symlink_target = std::str::from_utf8(&symlink_target)
+        .context("Submodule expansion symlink target is not valid UTF-8")?;
+
+    let expected = git_hash.to_string();
+    let points_to_expected_commit = symlink_target
+        .rsplit('/')
+        .next()
+        .is_some_and(|basename| basename == expected);
+
+    if !points_to_expected_commit {
+        return Err(SubmoduleValidationError::SymlinkExpansionTargetMismatch {
+            submodule_path: submodule_path.clone(),
+            expected,
+            actual: symlink_target.to_string(),
+        }
+        .into());
+    }
+
+    log_trace(
+        ctx,
+        "Root submodule expansion symlink points to the expected submodule commit",
+    );
+
+    Ok(bonsai)
+}
+
+/// Subentries that differ between a submodule's root fsnode and its expansion
+/// in the large repo, after dropping entries that are an exact match on both
+/// sides. A path that changed (rather than being added/removed) shows up in
+/// both `submodule_only` and `expansion_only`, since the two sides' entries
+/// for that path aren't equal.
+struct FsnodeManifestDiff {
+    submodule_only: HashMap<MPathElement, FsnodeEntry>,
+    expansion_only: HashMap<MPathElement, FsnodeEntry>,
+}
+
+/// Diff the subentries of a submodule's root fsnode against its expansion in
+/// the large repo, classifying them into entries that are only on the
+/// submodule side and entries that are only on the expansion side. Entries
+/// that match exactly on both sides are dropped, since they need no further
+/// validation.
+fn compare_fsnode_manifests(submodule_fsnode: Fsnode, expansion_fsnode: Fsnode) -> FsnodeManifestDiff {
+    let all_expansion_entries: HashSet<(MPathElement, FsnodeEntry)> =
+        expansion_fsnode.into_subentries().into_iter().collect();
+
+    let all_submodule_entries: HashSet<(MPathElement, FsnodeEntry)> =
+        submodule_fsnode.into_subentries().into_iter().collect();
+
+    let submodule_only = all_submodule_entries
+        .difference(&all_expansion_entries)
+        .cloned()
+        .collect();
+
+    let expansion_only = all_expansion_entries
+        .difference(&all_submodule_entries)
+        .cloned()
+        .collect();
+
+    FsnodeManifestDiff {
+        submodule_only,
+        expansion_only,
+    }
+}
+
+/// Render a `FsnodeManifestDiff` as a compact, human-readable diff suitable
+/// for debug logging: `+` for a path only in the expansion, `-` for a path
+/// only in the submodule, and `~` for a path present on both sides but with
+/// differing content, one line per path in sorted order.
+fn format_fsnode_manifest_diff(diff: &FsnodeManifestDiff) -> String {
+    let mut paths: Vec<&MPathElement> = diff
+        .submodule_only
+        .keys()
+        .chain(diff.expansion_only.keys())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let marker = match (
+                diff.submodule_only.contains_key(path),
+                diff.expansion_only.contains_key(path),
+            ) {
+                (true, true) => "~",
+                (true, false) => "-",
+                (false, true) => "+",
+                (false, false) => unreachable!("path came from one of the two maps"),
+            };
+            format!("{marker} {path}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Look for entries that are only on the submodule side and entries that are
+/// only on the expansion side whose basenames are identical except for case.
+/// Mononoke itself is case-sensitive, so such entries are otherwise treated
+/// as an addition on one side and a removal on the other, but they would
+/// collide with each other if the large repo's expansion is ever checked out
+/// on a case-insensitive filesystem.
+fn check_case_insensitive_collisions(
+    submodule_only: &HashMap<MPathElement, FsnodeEntry>,
+    expansion_only: &HashMap<MPathElement, FsnodeEntry>,
+) -> Result<()> {
+    for submodule_path in submodule_only.keys() {
+        for expansion_path in expansion_only.keys() {
+            let submodule_bytes: &[u8] = submodule_path.as_ref();
+            let expansion_bytes: &[u8] = expansion_path.as_ref();
+            if submodule_path != expansion_path
+                && submodule_bytes.eq_ignore_ascii_case(expansion_bytes)
+            {
+                return Err(SubmoduleValidationError::CaseInsensitiveCollision {
+                    submodule_path: submodule_path.clone(),
+                    expansion_path: expansion_path.clone(),
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Assert that there are no unexpected files/directories in the submodule
+/// manifest or expansion manifests, and log/display these entries, along
+/// with their basenames, if they're there. Used for e.g. metadata files left
+/// behind in the expansion when their submodule's expansion directory was
+/// removed without also removing the metadata file.
+fn check_for_unexpected_entries<T>(
+    ctx: &CoreContext,
+    entries: HashMap<MPathElement, T>,
+    entry_kind: &'static str,
+    location: &'static str,
+) -> Result<()>
+where
+    T: std::fmt::Debug,
+{
+    if entries.is_empty() {
+        // No unexpected entries
+        return Ok(());
+    }
+
+    let unexpected_entries = entries.keys().sorted().collect::<Vec<_>>();
+    log_error(
+        ctx,
+        format!(
+            "{entry_kind} unaccounted for in {location}: {:#?}",
+            unexpected_entries
+        ),
+    );
+
+    Err(SubmoduleValidationError::UnaccountedEntries {
+        entry_kind,
+        location,
+        paths: unexpected_entries.into_iter().map(|p| p.to_string()).collect(),
+    }
+    .into())
 }
 
 /// This will take the fsnode of a submodule expansion and the fsnode from the
@@ -518,6 +1264,12 @@ pub(crate) async fn validate_working_copy_of_expansion_with_recursive_submodules
     submodule_repo: &'a R,
     expansion_fsnode_id: FsnodeId,
     submodule_fsnode_id: FsnodeId,
+    // Path, relative to the small repo's root (i.e. starting with the
+    // top-level submodule's path), of the fsnode being validated at this
+    // level of the recursion. Used to resolve
+    // `sm_exp_data.sparse_profile_excluded_paths`, which are also relative
+    // to that root.
+    current_path: MPath,
 ) -> Result<()>
 where
     R: Repo,
@@ -542,39 +1294,57 @@ where
         .await
         .context("Failed to load fsnode")?;
 
-    // STEP 1: get all the entries in each fsnode.
-    let all_expansion_entries: HashSet<(MPathElement, FsnodeEntry)> =
-        expansion_fsnode.into_subentries().into_iter().collect();
+    // STEP 1: get all the entries in each fsnode, and diff them to find which
+    // ones are an exact match on both sides (and thus already pass validation).
+    let diff = compare_fsnode_manifests(submodule_fsnode, expansion_fsnode);
 
-    let all_submodule_entries: HashSet<(MPathElement, FsnodeEntry)> =
-        submodule_fsnode.into_subentries().into_iter().collect();
+    if !diff.submodule_only.is_empty() || !diff.expansion_only.is_empty() {
+        log_debug(
+            ctx,
+            format!(
+                "Fsnode manifest diff for submodule {0}:\n{1}",
+                submodule_repo.repo_identity().name(),
+                format_fsnode_manifest_diff(&diff)
+            ),
+        );
+    }
 
-    // Remove all the entries that are exact match in both sides, which means
-    // they pass validation.
-    let submodule_only_entries = all_submodule_entries
-        .difference(&all_expansion_entries)
-        .cloned();
+    let FsnodeManifestDiff {
+        submodule_only: submodule_only_entries,
+        expansion_only: expansion_only_entries,
+    } = diff;
 
-    let expansion_only_entries: HashMap<MPathElement, FsnodeEntry> = all_expansion_entries
-        .difference(&all_submodule_entries)
-        .cloned()
-        .collect();
+    if sm_exp_data.check_case_insensitive_collisions {
+        check_case_insensitive_collisions(&submodule_only_entries, &expansion_only_entries)?;
+    }
+
+    let submodule_only_entries = submodule_only_entries.into_iter();
 
     // At this point we only have the entries that are not exact match
 
     // STEP 2: assert that there are no paths are in the submodule manifest
-    // that are NOT in the expansion's manifest. This should never happen.
-    // In the process, split all the submodule manifest entries into files and
-    // directories, because the validation is different for each one.
+    // that are NOT in the expansion's manifest, unless they've been
+    // explicitly excluded from the expansion by the large repo's sparse
+    // profile. In the process, split all the submodule manifest entries into
+    // files and directories, because the validation is different for each
+    // one.
     let (submodule_dirs, submodule_files): (HashMap<_, _>, HashMap<_, _>) = submodule_only_entries
         .into_iter()
-        .map(|(path, entry)| {
+        .filter_map(|(path, entry)| {
             if !expansion_only_entries.contains_key(&path) {
-                return Err(anyhow!(
-                    "Path {path} is in submodule manifest but not in expansion"
-                ));
+                let full_path = current_path.join_into_non_root_mpath(&path);
+                if sm_exp_data.sparse_profile_excluded_paths.contains(&full_path) {
+                    log_debug(
+                        ctx,
+                        format!(
+                            "Path {full_path} is excluded from the expansion by the large repo's sparse profile, tolerating its absence"
+                        ),
+                    );
+                    return None;
+                }
+                return Some(Err(SubmoduleValidationError::PathMissingFromExpansion(path).into()));
             };
-            Ok((path, entry))
+            Some(Ok((path, entry)))
         })
         .process_results(|iter| {
             iter.partition_map(|(path, entry)| match entry {
@@ -612,9 +1382,7 @@ where
                 "Unexpected files in the expansion that are not in the submodule: {unexpected_paths:#?}",
             ),
         );
-        return Err(anyhow!(
-            "Found files in the expansion that are not in the submodule",
-        ));
+        return Err(SubmoduleValidationError::UnexpectedFilesInExpansion.into());
     }
 
     // The paths are are present in both, but their content doesn't match can be
@@ -624,9 +1392,9 @@ where
         .into_iter()
         .map(|(path, entry)| match entry {
             FsnodeEntry::Directory(fsnode_file) => Ok((path, fsnode_file)),
-            FsnodeEntry::File(_) => Err(anyhow!(
-                "Path present in submodule manifest can't be a file in expansion"
-            )),
+            FsnodeEntry::File(_) => {
+                Err(SubmoduleValidationError::ExpectedDirectoryInExpansion.into())
+            }
         })
         .collect::<Result<HashMap<_, _>>>()?;
 
@@ -647,6 +1415,11 @@ where
     //
     // **All the files and directories from both the expansion and the submodule
     // manifest should be consumed (thus accounted for) in this step**.
+    // Keep track of the directory entries being processed at this level, so
+    // it can be checked afterwards whether every submodule dep provided for
+    // this level was actually reachable from one of them.
+    let expansion_directory_paths: Vec<MPathElement> = expansion_directories.keys().cloned().collect();
+
     let EntryValidationData {
         remaining_sm_dirs: final_submodule_dirs,
         remaining_sm_files: final_submodule_files,
@@ -662,7 +1435,7 @@ where
             },
             |iteration_data: EntryValidationData<R>,
              (exp_path, exp_directory): (MPathElement, FsnodeDirectory)| {
-                cloned!(sm_exp_data, adjusted_submodule_deps);
+                cloned!(sm_exp_data, adjusted_submodule_deps, current_path);
                 borrowed!(submodule_repo);
 
                 async move {
@@ -674,6 +1447,7 @@ where
                         iteration_data,
                         exp_path.clone(),
                         exp_directory,
+                        current_path,
                     )
                     .timed()
                     .await
@@ -716,37 +1490,6 @@ where
         ),
     );
 
-    /// Helper to assert that there are no unexpected files/directories in
-    /// the submodule manifest or expansion manifests, and log/display these
-    /// entries if they're there.
-    fn check_for_unexpected_entries<T>(
-        ctx: &CoreContext,
-        entries: HashMap<MPathElement, T>,
-        entry_kind: &str,
-        location: &str,
-    ) -> Result<()>
-    where
-        T: std::fmt::Debug,
-    {
-        if entries.is_empty() {
-            // No unexpected entries
-            return Ok(());
-        }
-
-        let unexpected_entries = entries.keys().sorted().collect::<Vec<_>>();
-        log_error(
-            ctx,
-            format!(
-                "{entry_kind} unaccounted for in {location}: {:#?}",
-                unexpected_entries
-            ),
-        );
-
-        Err(anyhow!(
-            "{entry_kind} present in {location} are unaccounted for"
-        ))
-    }
-
     // STEP 5: ensure that all the paths in the submodule manifest were accounted
     // for.
     check_for_unexpected_entries(
@@ -763,6 +1506,11 @@ where
     // fetched to expand their submodule.
     check_for_unexpected_entries(ctx, final_expansion_only_files, "Files", "expansion")?;
 
+    // Submodule deps that were never reachable from any of the directory
+    // entries processed above are very likely misconfigured, so warn about
+    // them instead of silently ignoring them.
+    warn_about_unused_submodule_deps(ctx, &adjusted_submodule_deps, &expansion_directory_paths);
+
     // STEP 6: actually perform the recursive validation calls
     stream::iter(entries_to_validate)
         .map(|entry_to_validate| {
@@ -772,6 +1520,7 @@ where
                 submodule_repo,
                 expansion_fsnode_id,
                 submodule_repo_fsnode_id,
+                current_path,
             } = entry_to_validate;
 
             async move {
@@ -782,17 +1531,60 @@ where
                     &submodule_repo,
                     expansion_fsnode_id,
                     submodule_repo_fsnode_id,
+                    current_path,
                 )
                 .await
             }
         })
-        .buffer_unordered(100)
+        .buffer_unordered(sm_exp_data.validation_concurrency_limit)
         .try_collect::<()>()
         .await?;
 
     Ok(())
 }
 
+/// Returns the paths, among the keys of `adjusted_submodule_deps`, that don't
+/// fall under any of the directory entries that were actually processed at
+/// this level of the expansion. A dep that's never reached this way is either
+/// for a submodule that doesn't exist in this part of the expansion or has a
+/// misconfigured path.
+fn unused_submodule_deps<R>(
+    adjusted_submodule_deps: &HashMap<NonRootMPath, Arc<R>>,
+    expansion_directory_paths: &[MPathElement],
+) -> Vec<NonRootMPath> {
+    adjusted_submodule_deps
+        .keys()
+        .filter(|dep_path| {
+            !expansion_directory_paths.iter().any(|exp_path| {
+                Into::<NonRootMPath>::into(exp_path.clone()).is_prefix_of(*dep_path)
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Warn about any submodule dep whose path doesn't fall under any of the
+/// directory entries that were actually processed at this level of the
+/// expansion. This doesn't fail validation, because it's surfacing a
+/// potentially misconfigured dependency, not an actual mismatch between the
+/// submodule and its expansion.
+fn warn_about_unused_submodule_deps<R>(
+    ctx: &CoreContext,
+    adjusted_submodule_deps: &HashMap<NonRootMPath, Arc<R>>,
+    expansion_directory_paths: &[MPathElement],
+) {
+    let unused_deps = unused_submodule_deps(adjusted_submodule_deps, expansion_directory_paths);
+
+    if !unused_deps.is_empty() {
+        log_warning(
+            ctx,
+            format!(
+                "Submodule deps not matched against any entry in the expansion: {unused_deps:#?}",
+            ),
+        );
+    }
+}
+
 // All the entries need to be processed sequentially, but we can store all
 // the necessary arguments for a recursive validation call in this struct,
 // so the actual validation calls can be done concurrently.
@@ -803,6 +1595,8 @@ struct EntriesToValidate<R: Repo> {
     submodule_repo: Arc<R>,
     expansion_fsnode_id: FsnodeId,
     submodule_repo_fsnode_id: FsnodeId,
+    /// Path, relative to the small repo's root, of this entry.
+    current_path: MPath,
 }
 
 /// Stores all the data for an iteration of the validation fold.
@@ -836,6 +1630,9 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
     entry_validation_res: EntryValidationData<R>,
     exp_path: MPathElement,
     exp_directory: FsnodeDirectory,
+    // Path, relative to the small repo's root, of the directory being
+    // processed at this level of the recursion.
+    current_path: MPath,
 ) -> Result<EntryValidationData<R>> {
     let EntryValidationData {
         mut remaining_sm_dirs,
@@ -855,12 +1652,42 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
         // This path in the expansion corresponds to a directory
         // in the submodule manifest.
         // This means that it must contain an expansion inside it,
-        // so we just call the validation for it.
+        // so we just call the validation for it. But first, rule out a
+        // plain content difference: if this directory has neither a
+        // submodule file nor a subdirectory anywhere directly inside it,
+        // it can't possibly contain an expansion further down, so it's
+        // just a directory that diverged from its expansion for unrelated
+        // reasons. Catch that here with a clear error, rather than letting
+        // it fall through to the generic "unaccounted entries" error at
+        // the end of this level's validation.
+        let submodule_repo_blobstore = submodule_repo.repo_blobstore_arc();
+        let submodule_subdir_fsnode: Fsnode = submodule_dir
+            .id()
+            .load(ctx, &submodule_repo_blobstore)
+            .await
+            .context("Failed to load fsnode for submodule directory")?;
+
+        let could_contain_expansion =
+            submodule_subdir_fsnode
+                .list()
+                .any(|(_, entry)| match entry {
+                    FsnodeEntry::Directory(_) => true,
+                    FsnodeEntry::File(file) => *file.file_type() == FileType::GitSubmodule,
+                });
+
+        if !could_contain_expansion {
+            return Err(
+                SubmoduleValidationError::DirectoryDiffersWithoutSubmoduleExpansion(exp_path)
+                    .into(),
+            );
+        }
+
         entries_to_validate.push(EntriesToValidate {
             rec_submodule_repo_deps,
             submodule_repo: submodule_repo.clone().into(),
             expansion_fsnode_id: exp_dir_fsnode_id,
             submodule_repo_fsnode_id: *submodule_dir.id(),
+            current_path: current_path.join_element(Some(&exp_path)),
         });
 
         return Ok(EntryValidationData {
@@ -876,15 +1703,17 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
     // This means that this path is a recursive submodule expansion,
     // so we load this submodule repo, get its manifest and
     // call the working copy validation for its expansion.
-    let submodule_file = remaining_sm_files.remove(&exp_path).ok_or(anyhow!(
-        "Path should be a GitSubmodule file in tha submodule's manifest"
-    ))?;
+    let submodule_file = remaining_sm_files
+        .remove(&exp_path)
+        .ok_or_else(|| SubmoduleValidationError::ExpectedGitSubmoduleFile(exp_path.clone()))?;
 
     // The file has to be of type GitSubmodule
     if *submodule_file.file_type() != FileType::GitSubmodule {
-        return Err(anyhow!(
-            "Submodule entry for the same path has to be a submodule file"
-        ));
+        return Err(SubmoduleValidationError::NotAGitSubmoduleFile {
+            path: exp_path.clone(),
+            actual_file_type: *submodule_file.file_type(),
+        }
+        .into());
     };
 
     // If this path is an expansion, there MUST BE a submodule
@@ -896,11 +1725,10 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
 
     let metadata_file = remaining_md_files
         .remove(&expected_metadata_basename)
-        .ok_or(
-            anyhow!(
-                "Metadata file {expected_metadata_basename} not found in path {exp_path} where expansion should be"
-            ),
-        )?;
+        .ok_or_else(|| SubmoduleValidationError::MetadataFileNotFound {
+            metadata_basename: expected_metadata_basename.clone(),
+            expansion_path: exp_path.clone(),
+        })?;
 
     // Get the git hash from the metata file , which represents
     // a pointer to the recursive submodule's commit being expanded.
@@ -923,16 +1751,34 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
 
     let non_root_path: NonRootMPath = Into::<NonRootMPath>::into(exp_path.clone());
 
-    let recursive_submodule_repo = adjusted_submodule_deps
-        .get(&non_root_path)
-        .ok_or(anyhow!("Recursive submodule not loaded"))?
-        .clone();
+    let recursive_submodule_repo = match adjusted_submodule_deps.get(&non_root_path) {
+        Some(repo) => repo.clone(),
+        None if sm_exp_data.skip_missing_recursive_deps => {
+            log_warning(
+                ctx,
+                format!(
+                    "Recursive submodule repo not loaded for path {non_root_path}, skipping its validation"
+                ),
+            );
+
+            let result = EntryValidationData {
+                remaining_sm_dirs,
+                remaining_sm_files,
+                remaining_md_files,
+                entries_to_validate,
+            };
+            return Ok(result);
+        }
+        None => {
+            return Err(SubmoduleValidationError::RecursiveSubmoduleNotLoaded(non_root_path).into());
+        }
+    };
 
-    let rec_submodule_fsnode_id: FsnodeId = root_fsnode_id_from_submodule_git_commit(
+    let rec_submodule_fsnode_id: FsnodeId = cached_root_fsnode_id_from_submodule_git_commit(
         ctx,
+        &sm_exp_data,
         recursive_submodule_repo.as_ref(),
         exp_metadata_git_hash,
-        &sm_exp_data.dangling_submodule_pointers,
     )
     .await?;
 
@@ -942,6 +1788,7 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
         submodule_repo: recursive_submodule_repo,
         expansion_fsnode_id: exp_dir_fsnode_id,
         submodule_repo_fsnode_id: rec_submodule_fsnode_id,
+        current_path: current_path.join_element(Some(&exp_path)),
     });
 
     let result = EntryValidationData {
@@ -952,3 +1799,258 @@ async fn validate_expansion_directory_against_submodule_manifest_entry<'a, R: Re
     };
     Ok(result)
 }
+
+#[cfg(test)]
+mod test {
+    use fbinit::FacebookInit;
+    use maplit::hashmap;
+    use maplit::hashset;
+    use mononoke_macros::mononoke;
+    use mononoke_types::fsnode::FsnodeSummary;
+    use mononoke_types::hash::Blake2;
+    use mononoke_types::hash::Sha1;
+    use mononoke_types::hash::Sha256;
+    use mononoke_types::ContentId;
+    use mononoke_types_mocks::hash;
+    use sorted_vector_map::sorted_vector_map;
+
+    use super::*;
+
+    fn test_file(content_hash: Blake2) -> FsnodeEntry {
+        FsnodeEntry::File(FsnodeFile::new(
+            ContentId::new(content_hash),
+            FileType::Regular,
+            10,
+            Sha1::from_byte_array([0x11; 20]),
+            Sha256::from_byte_array([0x11; 32]),
+        ))
+    }
+
+    #[mononoke::test]
+    fn test_compare_fsnode_manifests() -> Result<()> {
+        let summary = FsnodeSummary {
+            simple_format_sha1: Sha1::from_byte_array([0x11; 20]),
+            simple_format_sha256: Sha256::from_byte_array([0x11; 32]),
+            child_files_count: 0,
+            child_files_total_size: 0,
+            child_dirs_count: 0,
+            descendant_files_count: 0,
+            descendant_files_total_size: 0,
+        };
+
+        let unchanged = MPathElement::new("unchanged".into())?;
+        let submodule_added = MPathElement::new("submodule_added".into())?;
+        let expansion_added = MPathElement::new("expansion_added".into())?;
+        let changed = MPathElement::new("changed".into())?;
+
+        let submodule_fsnode = Fsnode::new(
+            sorted_vector_map! {
+                unchanged.clone() => test_file(hash::ONES),
+                submodule_added.clone() => test_file(hash::TWOS),
+                changed.clone() => test_file(hash::THREES),
+            },
+            summary.clone(),
+        );
+
+        let expansion_fsnode = Fsnode::new(
+            sorted_vector_map! {
+                unchanged => test_file(hash::ONES),
+                expansion_added.clone() => test_file(hash::FOURS),
+                changed.clone() => test_file(hash::FIVES),
+            },
+            summary,
+        );
+
+        let diff = compare_fsnode_manifests(submodule_fsnode, expansion_fsnode);
+
+        assert_eq!(
+            diff.submodule_only.keys().cloned().collect::<HashSet<_>>(),
+            hashset! { submodule_added, changed.clone() },
+        );
+        assert_eq!(
+            diff.expansion_only.keys().cloned().collect::<HashSet<_>>(),
+            hashset! { expansion_added, changed },
+        );
+
+        Ok(())
+    }
+
+    #[mononoke::test]
+    fn test_format_fsnode_manifest_diff() -> Result<()> {
+        let summary = FsnodeSummary {
+            simple_format_sha1: Sha1::from_byte_array([0x11; 20]),
+            simple_format_sha256: Sha256::from_byte_array([0x11; 32]),
+            child_files_count: 0,
+            child_files_total_size: 0,
+            child_dirs_count: 0,
+            descendant_files_count: 0,
+            descendant_files_total_size: 0,
+        };
+
+        let unchanged = MPathElement::new("unchanged".into())?;
+        let submodule_added = MPathElement::new("submodule_added".into())?;
+        let expansion_added = MPathElement::new("expansion_added".into())?;
+        let changed = MPathElement::new("changed".into())?;
+
+        let submodule_fsnode = Fsnode::new(
+            sorted_vector_map! {
+                unchanged.clone() => test_file(hash::ONES),
+                submodule_added.clone() => test_file(hash::TWOS),
+                changed.clone() => test_file(hash::THREES),
+            },
+            summary.clone(),
+        );
+
+        let expansion_fsnode = Fsnode::new(
+            sorted_vector_map! {
+                unchanged => test_file(hash::ONES),
+                expansion_added.clone() => test_file(hash::FOURS),
+                changed.clone() => test_file(hash::FIVES),
+            },
+            summary,
+        );
+
+        let diff = compare_fsnode_manifests(submodule_fsnode, expansion_fsnode);
+
+        assert_eq!(
+            format_fsnode_manifest_diff(&diff),
+            "~ changed\n+ expansion_added\n- submodule_added",
+        );
+
+        Ok(())
+    }
+
+    #[mononoke::test]
+    fn test_check_case_insensitive_collisions() -> Result<()> {
+        let file_upper = MPathElement::new("File".into())?;
+        let file_lower = MPathElement::new("file".into())?;
+
+        let submodule_only = hashmap! { file_upper.clone() => test_file(hash::ONES) };
+        let expansion_only = hashmap! { file_lower.clone() => test_file(hash::TWOS) };
+
+        let err = check_case_insensitive_collisions(&submodule_only, &expansion_only)
+            .expect_err("should detect case-only collision");
+        match err.downcast_ref::<SubmoduleValidationError>() {
+            Some(SubmoduleValidationError::CaseInsensitiveCollision {
+                submodule_path,
+                expansion_path,
+            }) => {
+                assert_eq!(submodule_path, &file_upper);
+                assert_eq!(expansion_path, &file_lower);
+            }
+            other => panic!("expected CaseInsensitiveCollision, got {other:?}"),
+        }
+
+        // Entries that aren't just a case variant of each other are not flagged.
+        let unrelated_only = hashmap! { MPathElement::new("other".into())? => test_file(hash::THREES) };
+        check_case_insensitive_collisions(&submodule_only, &unrelated_only)?;
+
+        Ok(())
+    }
+
+    #[mononoke::test]
+    fn test_no_mover_path_collisions() -> Result<()> {
+        let foo = NonRootMPath::new("foo")?;
+        let bar = NonRootMPath::new("bar")?;
+        let identity: Mover = Arc::new(|path: &NonRootMPath| Ok(Some(path.clone())));
+
+        ensure_no_mover_path_collisions(vec![&foo, &bar].into_iter(), &identity)
+    }
+
+    #[mononoke::test]
+    fn test_mover_path_collision_detected() -> Result<()> {
+        let foo = NonRootMPath::new("foo")?;
+        let bar = NonRootMPath::new("bar")?;
+        let same_target = NonRootMPath::new("shared/target")?;
+        let collider: Mover = {
+            cloned!(same_target);
+            Arc::new(move |_path: &NonRootMPath| Ok(Some(same_target.clone())))
+        };
+
+        let err = ensure_no_mover_path_collisions(vec![&foo, &bar].into_iter(), &collider)
+            .expect_err("should detect collision");
+        match err.downcast_ref::<SubmoduleValidationError>() {
+            Some(SubmoduleValidationError::MoverPathCollision(first, second, target)) => {
+                assert_eq!(first, &foo);
+                assert_eq!(second, &bar);
+                assert_eq!(target, &same_target);
+            }
+            other => panic!("expected MoverPathCollision, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[mononoke::test]
+    fn test_mover_path_not_mapped() -> Result<()> {
+        let foo = NonRootMPath::new("foo")?;
+        let unmapped: Mover = Arc::new(|_path: &NonRootMPath| Ok(None));
+
+        let err = ensure_no_mover_path_collisions(vec![&foo].into_iter(), &unmapped)
+            .expect_err("should detect unmapped path");
+        match err.downcast_ref::<SubmoduleValidationError>() {
+            Some(SubmoduleValidationError::MoverPathNotMapped(path)) => {
+                assert_eq!(path, &foo);
+            }
+            other => panic!("expected MoverPathNotMapped, got {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[mononoke::test]
+    fn test_unused_submodule_deps() -> Result<()> {
+        // These paths are relative to the level of recursion being validated,
+        // as they would be after going through `build_recursive_submodule_deps`.
+        let repo_b_dep = NonRootMPath::new("repo_b")?;
+        let nested_repo_c_dep = NonRootMPath::new("nested/repo_c")?;
+        let unrelated_dep = NonRootMPath::new("unrelated_repo")?;
+        let adjusted_submodule_deps = hashmap! {
+            repo_b_dep.clone() => Arc::new(()),
+            nested_repo_c_dep.clone() => Arc::new(()),
+            unrelated_dep.clone() => Arc::new(()),
+        };
+
+        // `repo_b` and `nested` (which `nested/repo_c` falls under) were both
+        // processed as directory entries in the expansion, but nothing under
+        // `unrelated_repo` was, e.g. because it was never expanded anywhere in
+        // this commit.
+        let expansion_directory_paths = vec![
+            MPathElement::new("repo_b".into())?,
+            MPathElement::new("nested".into())?,
+        ];
+
+        assert_eq!(
+            unused_submodule_deps(&adjusted_submodule_deps, &expansion_directory_paths),
+            vec![unrelated_dep],
+        );
+
+        Ok(())
+    }
+
+    #[mononoke::fbinit_test]
+    fn test_check_for_unexpected_entries_orphaned_metadata_file(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+
+        let orphaned_metadata_file = MPathElement::new("repo_b-mononoke".into())?;
+        let entries = hashmap! {
+            orphaned_metadata_file.clone() => test_file(hash::ONES),
+        };
+
+        let err = check_for_unexpected_entries(&ctx, entries, "Files", "expansion")
+            .expect_err("should detect orphaned metadata file");
+        match err.downcast_ref::<SubmoduleValidationError>() {
+            Some(SubmoduleValidationError::UnaccountedEntries {
+                entry_kind,
+                location,
+                paths,
+            }) => {
+                assert_eq!(*entry_kind, "Files");
+                assert_eq!(*location, "expansion");
+                assert_eq!(paths, &vec![orphaned_metadata_file.to_string()]);
+            }
+            other => panic!("expected UnaccountedEntries, got {other:?}"),
+        }
+
+        // No unexpected entries means no error.
+        check_for_unexpected_entries::<FsnodeEntry>(&ctx, hashmap! {}, "Files", "expansion")
+    }
+}
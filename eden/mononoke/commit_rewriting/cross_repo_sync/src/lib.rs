@@ -53,8 +53,11 @@ pub use git_submodules::get_all_submodule_deps_from_repo_pair;
 pub use git_submodules::InMemoryRepo;
 pub use git_submodules::RepoProvider;
 pub use git_submodules::SubmoduleExpansionData;
+pub use git_submodules::stream_submodule_expansion_validation;
 pub use git_submodules::SubmoduleExpansionValidationToken;
+pub use git_submodules::SubmoduleValidationOutcome;
 pub use git_submodules::ValidSubmoduleExpansionBonsai;
+pub use git_submodules::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 pub use reporting::log_debug;
 pub use reporting::log_error;
 pub use reporting::log_info;
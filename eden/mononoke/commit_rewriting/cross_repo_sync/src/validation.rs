@@ -70,6 +70,7 @@ use crate::types::Target;
 use crate::InMemoryRepo;
 use crate::SubmoduleDeps;
 use crate::SubmoduleExpansionData;
+use crate::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 use crate::Syncers;
 
 // NOTE: Occurrences of Option<NonRootMPath> in this file have not been replaced with MPath since such a
@@ -175,6 +176,12 @@ pub async fn verify_working_copy_with_version<'a, R: Repo>(
             small_repo_id: small_repo.repo_identity().id(),
             large_repo: large_in_memory_repo,
             dangling_submodule_pointers,
+            validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
         }),
         SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
     };
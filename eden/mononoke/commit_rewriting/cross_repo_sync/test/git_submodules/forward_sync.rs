@@ -9,6 +9,7 @@
 
 //! Tests for handling git submodules in x-repo sync
 
+use std::collections::HashSet;
 use std::str::FromStr;
 
 use anyhow::anyhow;
@@ -16,8 +17,13 @@ use anyhow::Context;
 use anyhow::Result;
 use blobstore::Loadable;
 use context::CoreContext;
+use cross_repo_sync::submodule_metadata_file_prefix_and_dangling_pointers;
 use cross_repo_sync::CandidateSelectionHint;
 use cross_repo_sync::CommitSyncContext;
+use cross_repo_sync::InMemoryRepo;
+use cross_repo_sync::SubmoduleDeps;
+use cross_repo_sync::SubmoduleExpansionData;
+use cross_repo_sync::ValidSubmoduleExpansionBonsai;
 use fbinit::FacebookInit;
 use maplit::btreemap;
 use mononoke_macros::mononoke;
@@ -26,6 +32,7 @@ use mononoke_types::ChangesetId;
 use mononoke_types::FileType;
 use mononoke_types::NonRootMPath;
 use repo_blobstore::RepoBlobstoreRef;
+use repo_identity::RepoIdentityRef;
 use tests_utils::CreateCommitContext;
 
 use crate::check_mapping;
@@ -294,6 +301,93 @@ async fn test_recursive_submodule_expansion_basic(fb: FacebookInit) -> Result<()
     Ok(())
 }
 
+/// Validation should still succeed when submodule expansions are validated with
+/// a `validation_concurrency_limit` of 1, i.e. one submodule expansion at a time,
+/// instead of the default concurrency.
+#[mononoke::fbinit_test]
+async fn test_recursive_submodule_expansion_validation_low_concurrency(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_c, repo_c_cs_map) = build_repo_c(fb).await?;
+    let c_master_git_sha1 = git_sha1_from_changeset(&ctx, &repo_c, repo_c_cs_map["C_B"]).await?;
+
+    let repo_c_submodule_path_in_repo_b = NonRootMPath::new("submodules/repo_c")?;
+    let (repo_b, _repo_b_cs_map) =
+        build_repo_b_with_c_submodule(fb, c_master_git_sha1, &repo_c_submodule_path_in_repo_b)
+            .await?;
+
+    let repo_c_submodule_path =
+        NonRootMPath::new(REPO_B_SUBMODULE_PATH)?.join(&repo_c_submodule_path_in_repo_b);
+    let SubmoduleSyncTestData {
+        small_repo_info: (_small_repo, _small_repo_cs_map),
+        large_repo_info: (large_repo, _large_repo_master),
+        commit_syncer,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![
+            (NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone()),
+            (repo_c_submodule_path, repo_c.clone()),
+        ],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let large_repo_cs_id = master_cs_id(&ctx, &large_repo).await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            commit_syncer.get_live_commit_sync_config().clone(),
+        )
+        .await?;
+
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .context("Validation with validation_concurrency_limit set to 1 should succeed")?;
+
+    Ok(())
+}
+
 // ------------------------- Deletions ----------------------------
 
 /// Deleting an entire submodule in the small repo (i.e. small_repo) should delete
@@ -1327,6 +1421,64 @@ async fn test_adding_submodule_on_existing_directory(fb: FacebookInit) -> Result
 
 // ------------------ Unexpected state / Error handling ------------------
 
+/// Once a submodule has been fully deleted (expansion and metadata file
+/// together, in the same commit), the commit syncer's submodule deps still
+/// list it, because those come from the commit sync config rather than from
+/// what's actually present in a given commit. A later commit that doesn't
+/// touch that path at all used to validate successfully by taking the early
+/// return for "nothing changed here", without ever checking whether the
+/// path it would have expanded to still exists. Now it should fail, because
+/// there's nothing left to validate against.
+#[mononoke::fbinit_test]
+async fn test_validation_fails_when_expansion_missing_after_submodule_deletion(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, _repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        small_repo_info: (small_repo, small_repo_cs_map),
+        commit_syncer,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    const DELETE_MESSAGE: &str = "Delete repo_b submodule in small_repo";
+    let delete_cs_id = CreateCommitContext::new(&ctx, &small_repo, vec![small_repo_cs_map["A_C"]])
+        .set_message(DELETE_MESSAGE)
+        .delete_file(REPO_B_SUBMODULE_PATH)
+        .commit()
+        .await?;
+
+    sync_to_master(ctx.clone(), &commit_syncer, delete_cs_id)
+        .await
+        .context("Failed to sync submodule deletion")?
+        .ok_or_else(|| anyhow!("No commit was synced"))?;
+
+    const UNRELATED_MESSAGE: &str = "Unrelated change after submodule deletion";
+    let unrelated_cs_id = CreateCommitContext::new(&ctx, &small_repo, vec![delete_cs_id])
+        .set_message(UNRELATED_MESSAGE)
+        .add_file("unrelated_file", "Unrelated file contents")
+        .commit()
+        .await?;
+
+    let sync_result = sync_to_master(ctx.clone(), &commit_syncer, unrelated_cs_id).await;
+
+    assert!(sync_result.is_err_and(|err| {
+        err.chain().any(|e| {
+            e.to_string()
+                .contains("No fsnode entry found in submodule expansion path in large repo")
+        })
+    }));
+
+    Ok(())
+}
+
 /// Test that sync fails if submodule dependency repo is not available.
 #[mononoke::fbinit_test]
 async fn test_submodule_expansion_crashes_when_dep_not_available(fb: FacebookInit) -> Result<()> {
@@ -348,6 +348,65 @@ pub(crate) async fn build_repo_b_with_c_submodule(
     Ok((repo, cs_map))
 }
 
+/// Builds repo B like `build_repo_b`, but with a plain (non-submodule)
+/// subdirectory containing a single file, so tests can exercise a
+/// directory that diverges from its expansion without containing any
+/// submodule expansion.
+pub(crate) async fn build_repo_b_with_plain_subdirectory(
+    fb: FacebookInit,
+) -> Result<(TestRepo, BTreeMap<String, ChangesetId>)> {
+    let ctx = CoreContext::test_mock(fb);
+
+    const DAG: &str = r#"
+      B_A-B_B
+
+      # message: B_A "first commit in submodule B"
+      # message: B_B "second commit in submodule B"
+      # modify: B_B "some_dir/file1" "original content"
+      # bookmark: B_B master
+  "#;
+
+    let repo = build_mononoke_git_mirror_repo(fb, "repo_b", 2).await?;
+    let (cs_map, _) = extend_from_dag_with_actions(&ctx, &repo, DAG).await?;
+
+    Ok((repo, cs_map))
+}
+
+/// Builds repo B like `build_repo_b`, but with `RootFsnodeId` derivation
+/// disabled, so tests can simulate a submodule repo that's missing fsnodes
+/// derived data.
+pub(crate) async fn build_repo_b_without_fsnodes_derived_data(
+    fb: FacebookInit,
+) -> Result<(TestRepo, BTreeMap<String, ChangesetId>)> {
+    let ctx = CoreContext::test_mock(fb);
+
+    const DAG: &str = r#"
+      B_A-B_B
+
+      # message: B_A "first commit in submodule B"
+      # message: B_B "second commit in submodule B"
+      # bookmark: B_B master
+  "#;
+
+    let mut available_configs = submodule_repo_derived_data_types_config();
+    for cfg in available_configs.values_mut() {
+        cfg.types.remove(&DerivableType::Fsnodes);
+    }
+
+    let repo = TestRepoFactory::new(fb)?
+        .with_name("repo_b")
+        .with_id(RepositoryId::new(2))
+        .with_config_override(|cfg| {
+            cfg.derived_data_config.available_configs = available_configs;
+            cfg.pushrebase.flags.casefolding_check = false;
+        })
+        .build()
+        .await?;
+    let (cs_map, _) = extend_from_dag_with_actions(&ctx, &repo, DAG).await?;
+
+    Ok((repo, cs_map))
+}
+
 /// Builds repo C, which will be used as a submodule dependency of repo A.
 pub(crate) async fn build_repo_c(
     fb: FacebookInit,
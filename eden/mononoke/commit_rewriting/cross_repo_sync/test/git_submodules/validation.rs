@@ -9,15 +9,43 @@
 
 //! Tests for handling git submodules in x-repo sync
 
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Context;
 use anyhow::Result;
+use blobstore::Loadable;
+use bulk_derivation::BulkDerivation;
 use context::CoreContext;
+use cross_repo_sync::submodule_metadata_file_prefix_and_dangling_pointers;
+use cross_repo_sync::stream_submodule_expansion_validation;
 use cross_repo_sync::verify_working_copy;
 use cross_repo_sync::verify_working_copy_with_version;
+use cross_repo_sync::InMemoryRepo;
 use cross_repo_sync::Source;
+use cross_repo_sync::SubmoduleDeps;
+use cross_repo_sync::SubmoduleExpansionData;
+use cross_repo_sync::SubmoduleValidationOutcome;
 use cross_repo_sync::Target;
+use cross_repo_sync::ValidSubmoduleExpansionBonsai;
 use fbinit::FacebookInit;
+use fsnodes::RootFsnodeId;
+use futures::TryStreamExt;
+use maplit::hashset;
 use mononoke_macros::mononoke;
+use mononoke_types::hash::Blake2;
+use mononoke_types::hash::GitSha1;
+use mononoke_types::ContentId;
+use mononoke_types::FileChange;
+use mononoke_types::FileType;
+use mononoke_types::FsnodeId;
+use mononoke_types::GitLfs;
 use mononoke_types::NonRootMPath;
+use mononoke_types::RepositoryId;
+use repo_blobstore::RepoBlobstoreRef;
+use repo_identity::RepoIdentityRef;
 use tests_utils::CreateCommitContext;
 
 use crate::git_submodules::git_submodules_test_utils::*;
@@ -102,3 +130,1541 @@ async fn test_verify_working_copy_with_submodules_simple_error_case(
     );
     Ok(())
 }
+
+/// If a submodule metadata file exists but its content is empty (or
+/// whitespace-only), validation should fail with a clear error naming the
+/// metadata file's path, instead of a confusing git hash parse error.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_empty_metadata_file(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, _repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let metadata_file_path = NonRootMPath::new("small_repo/submodules/.x-repo-submodule-repo_b")?;
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Empty the submodule metadata file")
+        .add_file(metadata_file_path.to_string().as_str(), "")
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("empty metadata file should fail validation");
+
+    assert!(
+        format!("{err:#}").contains(&metadata_file_path.to_string()),
+        "expected error to mention {}, got: {}",
+        metadata_file_path,
+        err
+    );
+
+    Ok(())
+}
+
+/// If a submodule metadata file's content id is dangling (not present in the
+/// large repo blobstore, e.g. because of a corrupted or partially-synced
+/// blobstore), validation should fail with a clear error naming the
+/// metadata file's path and content id, instead of a generic fetch error.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_dangling_metadata_file_content_id(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, _repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let metadata_file_path = NonRootMPath::new("small_repo/submodules/.x-repo-submodule-repo_b")?;
+    // A content id that was never uploaded to the large repo blobstore.
+    let dangling_content_id = ContentId::new(Blake2::from_byte_array([0xab; 32]));
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Point the submodule metadata file at a dangling content id")
+        .add_file_change(
+            metadata_file_path.to_string().as_str(),
+            FileChange::tracked(dangling_content_id, FileType::Regular, 40, None, GitLfs::FullContent),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("dangling metadata file content id should fail validation");
+
+    assert!(
+        format!("{err:#}").contains(&metadata_file_path.to_string()),
+        "expected error to mention {}, got: {}",
+        metadata_file_path,
+        err
+    );
+    assert!(
+        format!("{err:#}").contains(&dangling_content_id.to_string()),
+        "expected error to mention the dangling content id {}, got: {}",
+        dangling_content_id,
+        err
+    );
+
+    Ok(())
+}
+
+/// If the submodule repo's `RootFsnodeId` derived data isn't available for
+/// the commit being validated (e.g. it hasn't been backfilled for that
+/// repo), validation should fail with an error naming the submodule repo and
+/// commit, instead of an opaque derived data error.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_missing_submodule_derived_data(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, _repo_b_cs_map) = build_repo_b_without_fsnodes_derived_data(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let bonsai = large_repo_master
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("missing submodule derived data should fail validation");
+
+    assert!(
+        format!("{err:#}").contains("Fsnode not derived"),
+        "expected error to mention the missing fsnode derivation, got: {}",
+        err
+    );
+    assert!(
+        format!("{err:#}").contains(repo_b.repo_identity().name()),
+        "expected error to mention the submodule repo name, got: {}",
+        err
+    );
+
+    Ok(())
+}
+
+/// If the submodule expansion changes and a file that looks like the
+/// metadata file (same directory, `.<prefix>-<basename>` naming shape) was
+/// changed, but it's not at the path computed from the configured prefix,
+/// that's a sign `x_repo_submodule_metadata_file_prefix` is misconfigured.
+/// Validation should fail with a clear error instead of silently treating
+/// the expansion change as if it had no metadata file at all.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_misconfigured_metadata_file_prefix(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, _repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let expected_metadata_file_path =
+        NonRootMPath::new("small_repo/submodules/.x-repo-submodule-repo_b")?;
+    let wrong_prefix_metadata_file_path =
+        NonRootMPath::new("small_repo/submodules/.wrong-prefix-repo_b")?;
+
+    // Change the expansion, and write the metadata file under the wrong
+    // prefix instead of the one the sync config actually expects.
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Change expansion and write metadata file under the wrong prefix")
+        .delete_file("small_repo/submodules/repo_b/B_A".to_string().as_str())
+        .add_file(
+            wrong_prefix_metadata_file_path.to_string().as_str(),
+            "deadbeef",
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("metadata file written under the wrong prefix should fail validation");
+
+    assert!(
+        format!("{err:#}").contains(&expected_metadata_file_path.to_string()),
+        "expected error to mention the expected metadata file path {}, got: {}",
+        expected_metadata_file_path,
+        err
+    );
+    assert!(
+        format!("{err:#}").contains(&wrong_prefix_metadata_file_path.to_string()),
+        "expected error to mention the actual (wrongly prefixed) metadata file path {}, got: {}",
+        wrong_prefix_metadata_file_path,
+        err
+    );
+
+    Ok(())
+}
+
+/// When the large repo checks out submodule expansions through a sparse
+/// profile, some submodule files may legitimately be missing from the
+/// expansion. `sparse_profile_excluded_paths` should let validation tolerate
+/// exactly those paths being absent, while still failing for any other
+/// missing path.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_sparse_profile_excluded_path(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let excluded_submodule_path = NonRootMPath::new("submodules/repo_b/B_A")?;
+    let repo_b_git_hash =
+        git_sha1_from_changeset(&ctx, &repo_b, *repo_b_cs_map.get("B_B").unwrap()).await?;
+    // The metadata file is rewritten with the same (unchanged) git hash, so
+    // validation reaches the manifest comparison instead of failing because
+    // the expansion changed without updating the metadata file.
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Delete a file that the sparse profile excludes from the expansion")
+        .delete_file("small_repo/submodules/repo_b/B_A".to_string().as_str())
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let build_sm_exp_data = |sparse_profile_excluded_paths: HashSet<NonRootMPath>| match submodule_deps
+    {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo.clone(),
+            dangling_submodule_pointers: dangling_submodule_pointers.clone(),
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths,
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            panic!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    // Without the exclusion, the missing file should still fail validation.
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(HashSet::new()),
+        bonsai.clone(),
+        mover.clone(),
+        true, // recursive
+    )
+    .await
+    .expect_err("missing file should fail validation when not sparse-excluded");
+    assert!(
+        format!("{err:#}").contains("is in submodule manifest but not in expansion"),
+        "expected a path-missing-from-expansion error, got: {}",
+        err
+    );
+
+    // With the file's path excluded, validation should pass.
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(hashset! { excluded_submodule_path }),
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .context("sparse-excluded file should be tolerated as missing from the expansion")?;
+
+    Ok(())
+}
+
+/// If a path that should contain a (possibly recursive) submodule expansion
+/// turns out to be a regular file rather than a `GitSubmodule` file in the
+/// submodule's own manifest, validation should fail with a clear error
+/// naming the path and the unexpected file type.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_non_submodule_file_type(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    // Turn the expansion's "B_A" file into a directory. Repo B's own
+    // manifest still has "B_A" as a regular file, so validation will try to
+    // treat it as a (recursive) submodule file, and it isn't one. The
+    // metadata file is rewritten with the same (unchanged) git hash, so
+    // validation doesn't stop at the expansion changing without it.
+    let repo_b_git_hash =
+        git_sha1_from_changeset(&ctx, &repo_b, *repo_b_cs_map.get("B_B").unwrap()).await?;
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Turn B_A into a directory in the expansion")
+        .delete_file("small_repo/submodules/repo_b/B_A".to_string().as_str())
+        .add_file("small_repo/submodules/repo_b/B_A/nested", "nested file")
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("file masquerading as a submodule expansion directory should fail validation");
+
+    assert!(
+        format!("{err:#}").contains("B_A"),
+        "expected error to mention the path, got: {}",
+        err
+    );
+    assert!(
+        format!("{err:#}").contains("Regular"),
+        "expected error to mention the actual file type, got: {}",
+        err
+    );
+
+    Ok(())
+}
+
+/// Some configurations expand a submodule as a symlink pointing at a
+/// content-addressed location, rather than as a full working copy directory.
+/// Validation should compare the symlink's target against the submodule's
+/// git commit hash instead of failing just because the expansion path is a
+/// file rather than a directory.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_as_symlink(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let repo_b_master = *repo_b_cs_map.get("B_B").unwrap();
+    let repo_b_git_hash = git_sha1_from_changeset(&ctx, &repo_b, repo_b_master).await?;
+
+    // Replace the submodule's expanded working copy with a symlink to a
+    // content-addressed location keyed by the submodule's git commit hash.
+    // The metadata file is rewritten too (even though its content doesn't
+    // change), since a real switch to symlink expansion would be committed
+    // alongside the expansion change.
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Expand submodule as a symlink instead of a directory")
+        .delete_file("small_repo/submodules/repo_b/B_A".to_string().as_str())
+        .delete_file("small_repo/submodules/repo_b/B_B".to_string().as_str())
+        .add_file_with_type(
+            "small_repo/submodules/repo_b".to_string().as_str(),
+            format!("/mnt/cas/{}", repo_b_git_hash),
+            FileType::Symlink,
+        )
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .context("symlink expansion pointing to the right commit should pass validation")?;
+
+    Ok(())
+}
+
+/// Submodule expansion validation is broken up into several steps (detect
+/// change, read metadata, derive expansion fsnode, compare), each logged to
+/// scuba individually so perf regressions can be attributed to a single step.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_logs_per_step_scuba_columns(
+    fb: FacebookInit,
+) -> Result<()> {
+    let scuba_log_file = tempfile::NamedTempFile::new()?;
+    let ctx = CoreContext::test_mock(fb.clone()).with_mutated_scuba(|scuba| {
+        scuba
+            .with_log_file(scuba_log_file.path())
+            .expect("failed to open scuba log file")
+    });
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    // Rewrite the metadata file with the same (unchanged) git hash, so
+    // validation reads it and compares fsnodes instead of returning early
+    // for a submodule the commit doesn't touch.
+    let repo_b_git_hash =
+        git_sha1_from_changeset(&ctx, &repo_b, *repo_b_cs_map.get("B_B").unwrap()).await?;
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Rewrite the submodule metadata file")
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await?;
+
+    let logged_scuba_samples = std::fs::read_to_string(scuba_log_file.path())?;
+    for expected_log_tag in [
+        "Reading submodule metadata file",
+        "Getting root fsnode id from submodule git commit",
+        "Get submodule expansion fsnode id",
+    ] {
+        assert!(
+            logged_scuba_samples.contains(expected_log_tag),
+            "expected a scuba sample logged with log_tag {}, got: {}",
+            expected_log_tag,
+            logged_scuba_samples
+        );
+    }
+    assert!(
+        logged_scuba_samples.contains("poll_time_us"),
+        "expected logged scuba samples to contain timing columns, got: {}",
+        logged_scuba_samples
+    );
+
+    Ok(())
+}
+
+/// In addition to the per-step scuba samples, `validate_all_submodule_expansions`
+/// should log a single roll-up sample per commit summarizing how many
+/// submodules were validated, how many failed and how long it all took.
+#[mononoke::fbinit_test]
+async fn test_validate_all_submodule_expansions_logs_summary_scuba_columns(
+    fb: FacebookInit,
+) -> Result<()> {
+    let scuba_log_file = tempfile::NamedTempFile::new()?;
+    let ctx = CoreContext::test_mock(fb.clone()).with_mutated_scuba(|scuba| {
+        scuba
+            .with_log_file(scuba_log_file.path())
+            .expect("failed to open scuba log file")
+    });
+    let (repo_b, _repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let bonsai = large_repo_master
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await?;
+
+    let logged_scuba_samples = std::fs::read_to_string(scuba_log_file.path())?;
+    assert!(
+        logged_scuba_samples.contains("Validated all submodule expansions"),
+        "expected a summary scuba sample logged once per commit, got: {}",
+        logged_scuba_samples
+    );
+    assert!(
+        logged_scuba_samples.contains("total_submodules"),
+        "expected the summary sample to report the total number of submodules, got: {}",
+        logged_scuba_samples
+    );
+    assert!(
+        logged_scuba_samples.contains("failed_submodules"),
+        "expected the summary sample to report the number of failed submodules, got: {}",
+        logged_scuba_samples
+    );
+    assert!(
+        logged_scuba_samples.contains("poll_time_us"),
+        "expected the summary sample to contain timing columns, got: {}",
+        logged_scuba_samples
+    );
+
+    Ok(())
+}
+
+/// `SubmoduleExpansionData::submodule_fsnode_id_cache` should be reused
+/// across calls that share it, so looking up the same submodule git commit
+/// more than once only derives its root fsnode id once.
+///
+/// This validates a large repo commit that rewrites the submodule's metadata
+/// file (with its unchanged git hash) twice, passing clones of the same
+/// `sm_exp_data` both times so they share the same cache. After both calls,
+/// the cache should hold a single entry for the submodule's git commit.
+#[mononoke::fbinit_test]
+async fn test_submodule_fsnode_id_cache_is_reused_across_repeated_git_hash(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    // Rewrite the metadata file with the same (unchanged) git hash, so
+    // validation looks up the submodule commit's fsnode id instead of
+    // returning early for a submodule the commit doesn't touch.
+    let repo_b_git_hash =
+        git_sha1_from_changeset(&ctx, &repo_b, *repo_b_cs_map.get("B_B").unwrap()).await?;
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Rewrite the submodule metadata file")
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    // Validate the same commit twice, sharing `sm_exp_data`'s cache between
+    // both calls, the same way a single validation run shares it across
+    // recursive submodule lookups.
+    for _ in 0..2 {
+        ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+            &ctx,
+            sm_exp_data.clone(),
+            bonsai.clone(),
+            mover.clone(),
+            true, // recursive
+        )
+        .await?;
+    }
+
+    assert_eq!(
+        sm_exp_data.submodule_fsnode_id_cache.lock().unwrap().len(),
+        1,
+        "expected a single cached fsnode id lookup to be reused across both validations"
+    );
+
+    Ok(())
+}
+
+/// `strict_metadata_pointer_check` should catch a fast-path match that only
+/// holds because of a stale `submodule_fsnode_id_cache` entry: the cached
+/// fsnode id for the metadata file's git hash doesn't actually match what
+/// re-deriving that hash produces, even though it happens to match the large
+/// repo's actual expansion content.
+#[mononoke::fbinit_test]
+async fn test_strict_metadata_pointer_check_detects_stale_cache(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let b_a_fsnode_id = repo_b
+        .repo_derived_data()
+        .derive::<RootFsnodeId>(&ctx, *repo_b_cs_map.get("B_A").unwrap())
+        .await
+        .context("Failed to derive B_A's fsnode id")?
+        .into_fsnode_id();
+
+    let b_b_git_hash = repo_b
+        .bonsai_git_mapping()
+        .get_git_sha1_from_bonsai(&ctx, *repo_b_cs_map.get("B_B").unwrap())
+        .await
+        .context("Failed to get B_B's git hash")?
+        .expect("B_B should have a git hash mapping");
+
+    let metadata_file_path = "small_repo/submodules/.x-repo-submodule-repo_b";
+
+    // Craft a large repo commit whose submodule expansion drops the file
+    // added by B_B, so its content (and fsnode id) matches B_A's. The
+    // metadata file is rewritten with the same (unchanged) git hash, so
+    // `validate_submodule_expansion_impl` doesn't short-circuit on "expansion
+    // changed without updating the metadata file" before reaching the fsnode
+    // comparison this test targets.
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Remove file added by B_B, so the expansion matches B_A's tree")
+        .delete_file("small_repo/submodules/repo_b/B_B")
+        .add_file(metadata_file_path, b_b_git_hash.to_string())
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    // Poison the cache: pretend B_B's git hash derives to B_A's fsnode. This
+    // happens to match the crafted expansion above, so the fast path would
+    // wrongly accept it without the strict check.
+    let submodule_fsnode_id_cache: Arc<Mutex<HashMap<(RepositoryId, GitSha1), FsnodeId>>> =
+        Default::default();
+    submodule_fsnode_id_cache
+        .lock()
+        .unwrap()
+        .insert((repo_b.repo_identity().id(), b_b_git_hash), b_a_fsnode_id);
+
+    let build_sm_exp_data = |strict_metadata_pointer_check: bool| match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo.clone(),
+            dangling_submodule_pointers: dangling_submodule_pointers.clone(),
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: submodule_fsnode_id_cache.clone(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            panic!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    // Without the strict check, the poisoned cache entry makes the fast path
+    // wrongly accept the mismatched metadata pointer.
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(false),
+        bonsai.clone(),
+        mover.clone(),
+        true, // recursive
+    )
+    .await
+    .context("fast path should accept the poisoned cache entry when strict check is disabled")?;
+
+    // With the strict check, re-deriving B_B's git hash uncached reveals it
+    // doesn't actually match the cached (and expansion-matching) fsnode id.
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(true),
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("strict check should catch the stale metadata pointer");
+    assert!(
+        format!("{err:#}").contains("re-deriving its fsnode id"),
+        "expected a stale metadata pointer error, got: {}",
+        err
+    );
+
+    Ok(())
+}
+
+/// `validate_one_submodule_expansion_with_fsnode_id` should use the fsnode id
+/// it's given instead of deriving the large repo's expansion fsnode itself.
+///
+/// To prove this, corrupt the large repo's submodule expansion (the same way
+/// `test_verify_working_copy_with_submodules_simple_error_case` does, which
+/// normally causes validation to fail), but pass in the submodule's *real*
+/// root fsnode id as the precomputed expansion fsnode id. Validation should
+/// succeed, because it never derives or walks the large repo's (corrupted)
+/// expansion to get that value.
+#[mononoke::fbinit_test]
+async fn test_validate_one_submodule_expansion_with_precomputed_fsnode_id(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    let repo_b_master_cs_id = *repo_b_cs_map.get("B_B").unwrap();
+    let submodule_fsnode_id = repo_b
+        .repo_derived_data()
+        .derive::<RootFsnodeId>(&ctx, repo_b_master_cs_id)
+        .await?
+        .into_fsnode_id();
+
+    // The metadata file is rewritten with the same (unchanged) git hash, so
+    // validation doesn't stop at the expansion changing without it.
+    let repo_b_git_hash = git_sha1_from_changeset(&ctx, &repo_b, repo_b_master_cs_id).await?;
+    const CHANGE_SUBMODULE_EXPANSION_CONTENTS: &str = "Change expansion contents";
+    let large_repo_with_changed_expansion_csid =
+        CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+            .set_message(CHANGE_SUBMODULE_EXPANSION_CONTENTS)
+            .delete_file("small_repo/submodules/repo_b/B_A".to_string().as_str())
+            .add_file(
+                "small_repo/submodules/.x-repo-submodule-repo_b",
+                repo_b_git_hash.to_string(),
+            )
+            .commit()
+            .await?;
+    let bonsai = large_repo_with_changed_expansion_csid
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let submodule_path = NonRootMPath::new(REPO_B_SUBMODULE_PATH)?;
+
+    ValidSubmoduleExpansionBonsai::validate_one_submodule_expansion_with_fsnode_id(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        &submodule_path,
+        &repo_b,
+        mover,
+        true, // recursive
+        submodule_fsnode_id,
+    )
+    .await
+    .context(
+        "Validation should succeed when the precomputed fsnode id is passed in, even though \
+         the large repo's actual expansion was corrupted",
+    )?;
+
+    Ok(())
+}
+
+/// `stream_submodule_expansion_validation` should yield one outcome per
+/// submodule dependency (here, `repo_b` and its nested `repo_c`), without
+/// requiring the caller to wait for every submodule to finish first.
+#[mononoke::fbinit_test]
+async fn test_stream_submodule_expansion_validation_multiple_submodules(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+
+    let (repo_c, repo_c_cs_map) = build_repo_c(fb).await?;
+    let c_master_git_sha1 = git_sha1_from_changeset(&ctx, &repo_c, repo_c_cs_map["C_B"]).await?;
+
+    let repo_c_submodule_path_in_repo_b = NonRootMPath::new("submodules/repo_c")?;
+    let (repo_b, _repo_b_cs_map) =
+        build_repo_b_with_c_submodule(fb, c_master_git_sha1, &repo_c_submodule_path_in_repo_b)
+            .await?;
+    let repo_c_submodule_path =
+        NonRootMPath::new(REPO_B_SUBMODULE_PATH)?.join(&repo_c_submodule_path_in_repo_b);
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![
+            (NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone()),
+            (repo_c_submodule_path, repo_c.clone()),
+        ],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+    let bonsai = large_repo_master
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 2,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let outcomes: Vec<SubmoduleValidationOutcome> = stream_submodule_expansion_validation(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )?
+    .try_collect::<Vec<_>>()
+    .await?;
+
+    let mut validated_paths: Vec<String> = outcomes
+        .iter()
+        .map(|outcome| outcome.submodule_path.to_string())
+        .collect();
+    validated_paths.sort();
+    assert_eq!(
+        validated_paths,
+        vec![
+            "submodules/repo_b".to_string(),
+            "submodules/repo_b/submodules/repo_c".to_string(),
+        ]
+    );
+
+    for outcome in outcomes {
+        outcome.result.with_context(|| {
+            format!(
+                "Expected validation of {} to succeed",
+                outcome.submodule_path
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// If a recursive submodule's repo isn't in `submodule_deps` (e.g. because
+/// it wasn't loaded for this sync), validation should fail by default, but
+/// succeed with a warning when `skip_missing_recursive_deps` is set.
+#[mononoke::fbinit_test]
+async fn test_skip_missing_recursive_deps(fb: FacebookInit) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+
+    let (repo_c, repo_c_cs_map) = build_repo_c(fb).await?;
+    let c_master_git_sha1 = git_sha1_from_changeset(&ctx, &repo_c, repo_c_cs_map["C_B"]).await?;
+
+    let repo_c_submodule_path_in_repo_b = NonRootMPath::new("submodules/repo_c")?;
+    let (repo_b, _repo_b_cs_map) =
+        build_repo_b_with_c_submodule(fb, c_master_git_sha1, &repo_c_submodule_path_in_repo_b)
+            .await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        // Only repo_b is a known submodule dependency here, so its nested
+        // repo_c submodule won't be loaded in `submodule_deps`.
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+    let bonsai = large_repo_master
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let build_sm_exp_data = |skip_missing_recursive_deps: bool| match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo.clone(),
+            dangling_submodule_pointers: dangling_submodule_pointers.clone(),
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            panic!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    // Without the flag, the missing repo_c dependency should fail validation.
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(false),
+        bonsai.clone(),
+        mover.clone(),
+        true, // recursive
+    )
+    .await
+    .expect_err("validation should fail when a recursive submodule dep isn't loaded");
+    assert!(
+        format!("{err:#}").contains("Recursive submodule not loaded"),
+        "expected a recursive submodule not loaded error, got: {}",
+        err
+    );
+
+    // With the flag, the missing repo_c dependency is skipped (with a
+    // warning) instead of failing validation.
+    ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        build_sm_exp_data(true),
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .context("validation should succeed when missing recursive deps are skipped")?;
+
+    Ok(())
+}
+
+/// If a directory diverges between the submodule and its expansion, but the
+/// submodule's own copy of that directory contains no submodule file (or
+/// subdirectory that could contain one), the divergence can't be a missing
+/// submodule expansion. Validation should fail early with a clear error
+/// naming the directory, rather than the generic "unaccounted entries"
+/// error that would otherwise surface once the maps fail to be fully
+/// consumed.
+#[mononoke::fbinit_test]
+async fn test_validate_submodule_expansion_with_plain_directory_divergence(
+    fb: FacebookInit,
+) -> Result<()> {
+    let ctx = CoreContext::test_mock(fb.clone());
+    let (repo_b, repo_b_cs_map) = build_repo_b_with_plain_subdirectory(fb).await?;
+
+    let SubmoduleSyncTestData {
+        large_repo_info: (large_repo, large_repo_master),
+        commit_syncer,
+        live_commit_sync_config,
+        ..
+    } = build_submodule_sync_test_data(
+        fb,
+        &repo_b,
+        vec![(NonRootMPath::new(REPO_B_SUBMODULE_PATH)?, repo_b.clone())],
+        vec![], // Known dangling submodule pointers
+    )
+    .await?;
+
+    // Change the content of the file inside "some_dir" in the expansion,
+    // without changing anything in repo B's manifest. "some_dir" now
+    // diverges between the two, but it doesn't contain (and can't contain)
+    // a submodule expansion. The metadata file is rewritten with the same
+    // (unchanged) git hash, so validation doesn't stop at the expansion
+    // changing without it.
+    let repo_b_git_hash =
+        git_sha1_from_changeset(&ctx, &repo_b, *repo_b_cs_map.get("B_B").unwrap()).await?;
+    let large_repo_cs_id = CreateCommitContext::new(&ctx, &large_repo, vec![large_repo_master])
+        .set_message("Diverge some_dir's content in the expansion")
+        .add_file(
+            "small_repo/submodules/repo_b/some_dir/file1",
+            "different content",
+        )
+        .add_file(
+            "small_repo/submodules/.x-repo-submodule-repo_b",
+            repo_b_git_hash.to_string(),
+        )
+        .commit()
+        .await?;
+    let bonsai = large_repo_cs_id
+        .load(&ctx, large_repo.repo_blobstore())
+        .await
+        .context("Failed to load bonsai in large repo")?;
+
+    let version = base_commit_sync_version_name();
+    let mover = commit_syncer.get_movers_by_version(&version).await?.mover;
+    let submodule_deps = commit_syncer.get_submodule_deps();
+    let small_repo_id = commit_syncer.get_small_repo().repo_identity().id();
+    let (x_repo_submodule_metadata_file_prefix, dangling_submodule_pointers) =
+        submodule_metadata_file_prefix_and_dangling_pointers(
+            small_repo_id,
+            &version,
+            live_commit_sync_config,
+        )
+        .await?;
+    let large_in_memory_repo = InMemoryRepo::from_repo(&large_repo, submodule_deps.repos())?;
+
+    let sm_exp_data = match submodule_deps {
+        SubmoduleDeps::ForSync(deps) => SubmoduleExpansionData {
+            submodule_deps: deps,
+            x_repo_submodule_metadata_file_prefix: x_repo_submodule_metadata_file_prefix.as_str(),
+            small_repo_id,
+            large_repo: large_in_memory_repo,
+            dangling_submodule_pointers,
+            validation_concurrency_limit: 1,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
+        },
+        SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => {
+            anyhow::bail!("Expected SubmoduleDeps::ForSync")
+        }
+    };
+
+    let err = ValidSubmoduleExpansionBonsai::validate_all_submodule_expansions(
+        &ctx,
+        sm_exp_data,
+        bonsai,
+        mover,
+        true, // recursive
+    )
+    .await
+    .expect_err("a plain directory divergence with no submodule should fail validation early");
+
+    assert!(
+        format!("{err:#}").contains("some_dir"),
+        "expected error to mention the diverging directory, got: {}",
+        err
+    );
+    assert!(
+        format!("{err:#}").contains("contains no submodule"),
+        "expected the dedicated early error, got: {}",
+        err
+    );
+
+    Ok(())
+}
@@ -36,6 +36,7 @@ use cross_repo_sync::InMemoryRepo;
 use cross_repo_sync::Repo;
 use cross_repo_sync::SubmoduleDeps;
 use cross_repo_sync::SubmoduleExpansionData;
+use cross_repo_sync::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 use cross_repo_sync::Syncers;
 use filenodes::Filenodes;
 use filestore::FilestoreConfig;
@@ -202,6 +203,12 @@ where
                     .as_str(),
                 small_repo_id,
                 dangling_submodule_pointers,
+                validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+                submodule_fsnode_id_cache: Default::default(),
+                check_case_insensitive_collisions: false,
+                sparse_profile_excluded_paths: HashSet::new(),
+                strict_metadata_pointer_check: false,
+                skip_missing_recursive_deps: false,
             }),
             SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
         };
@@ -38,6 +38,7 @@ use cross_repo_sync::CommitSyncer;
 use cross_repo_sync::InMemoryRepo;
 use cross_repo_sync::SubmoduleDeps;
 use cross_repo_sync::SubmoduleExpansionData;
+use cross_repo_sync::DEFAULT_VALIDATION_CONCURRENCY_LIMIT;
 use cross_repo_sync::Syncers;
 use futures::stream;
 use futures::stream::futures_unordered::FuturesUnordered;
@@ -305,6 +306,12 @@ async fn create_rewritten_merge_commit(
             small_repo_id,
             large_repo: large_in_memory_repo,
             dangling_submodule_pointers,
+            validation_concurrency_limit: DEFAULT_VALIDATION_CONCURRENCY_LIMIT,
+            submodule_fsnode_id_cache: Default::default(),
+            check_case_insensitive_collisions: false,
+            sparse_profile_excluded_paths: HashSet::new(),
+            strict_metadata_pointer_check: false,
+            skip_missing_recursive_deps: false,
         }),
         SubmoduleDeps::NotNeeded | SubmoduleDeps::NotAvailable => None,
     };
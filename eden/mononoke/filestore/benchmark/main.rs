@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Error;
+use bytes::Bytes;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use filestore::ExpectedSize;
+use filestore::collect_chunks;
+use filestore::make_chunks;
+use futures::stream;
+use rand::Rng;
+use rand::thread_rng;
+use tokio::runtime::Runtime;
+
+const KB: usize = 1024;
+const MB: usize = KB * 1024;
+
+/// Split `size` bytes of random data into `n` equally-sized `Bytes` pieces, simulating how data
+/// trickles in off the wire in small increments rather than arriving as one contiguous buffer.
+fn random_pieces(size: usize, n: usize) -> Vec<Bytes> {
+    let mut data = vec![0; size];
+    thread_rng().fill(&mut data[..]);
+    let data = Bytes::from(data);
+
+    let piece_size = (size / n).max(1);
+    let mut pieces = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = (offset + piece_size).min(data.len());
+        pieces.push(data.slice(offset..end));
+        offset = end;
+    }
+    pieces
+}
+
+fn small_inline(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let mut group = c.benchmark_group("small_inline");
+
+    for size in [128, 4 * KB] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let pieces = random_pieces(size, 1);
+            b.iter(|| {
+                let data = stream::iter(pieces.clone().into_iter().map(Ok::<_, Error>));
+                let chunks =
+                    make_chunks(data, ExpectedSize::new(size as u64), Some(64 * KB as u64));
+                runtime
+                    .block_on(collect_chunks(chunks))
+                    .expect("chunking failed")
+            });
+        });
+    }
+    group.finish();
+}
+
+fn large_chunked(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let mut group = c.benchmark_group("large_chunked");
+
+    for size in [MB, 16 * MB] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let pieces = random_pieces(size, 1);
+            b.iter(|| {
+                let data = stream::iter(pieces.clone().into_iter().map(Ok::<_, Error>));
+                let chunks =
+                    make_chunks(data, ExpectedSize::new(size as u64), Some(64 * KB as u64));
+                runtime
+                    .block_on(collect_chunks(chunks))
+                    .expect("chunking failed")
+            });
+        });
+    }
+    group.finish();
+}
+
+fn many_small_incoming_chunks(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("failed to create runtime");
+    let mut group = c.benchmark_group("many_small_incoming_chunks");
+
+    let size = 4 * MB;
+    for n_pieces in [1024, 16384] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n_pieces),
+            &n_pieces,
+            |b, &n_pieces| {
+                let pieces = random_pieces(size, n_pieces);
+                b.iter(|| {
+                    let data = stream::iter(pieces.clone().into_iter().map(Ok::<_, Error>));
+                    let chunks =
+                        make_chunks(data, ExpectedSize::new(size as u64), Some(64 * KB as u64));
+                    runtime
+                        .block_on(collect_chunks(chunks))
+                        .expect("chunking failed")
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    small_inline,
+    large_chunked,
+    many_small_incoming_chunks
+);
+criterion_main!(benches);
@@ -8,22 +8,37 @@
 use std::fmt;
 use std::fmt::Debug;
 use std::pin::Pin;
+use std::time::Duration;
 
+use anyhow::bail;
 use anyhow::Error;
 use anyhow::Result;
 use bytes::Bytes;
 use bytes::BytesMut;
+use futures::channel::oneshot;
 use futures::future::BoxFuture;
 use futures::future::FutureExt;
 use futures::future::TryFutureExt;
+use futures::stream;
 use futures::stream::BoxStream;
 use futures::stream::Stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use futures::task::Context;
 use futures::task::Poll;
+use mononoke_types::ContentId;
+use slog::trace;
+use slog::Logger;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeek;
+use tokio::io::AsyncSeekExt;
+use tokio::time::Instant;
 
 use crate::expected_size::ExpectedSize;
+use crate::incremental_hash::hash_bytes;
+use crate::incremental_hash::ContentIdIncrementalHasher;
+use crate::incremental_hash::Hasher;
 
 #[must_use = "streams do nothing unless polled"]
 #[pin_project::pin_project]
@@ -137,6 +152,11 @@ where
 pub enum Chunks<'a> {
     Inline(BoxFuture<'a, Result<Bytes, Error>>),
     Chunked(ExpectedSize, BoxStream<'a, Result<Bytes, Error>>),
+    ChunkedWithIds(ExpectedSize, BoxStream<'a, Result<(ContentId, Bytes), Error>>),
+    /// Each chunk has been encrypted with a `ChunkCipher`, paired with the
+    /// plaintext offset it started at so a decrypting reader can derive the
+    /// same per-chunk nonce the encrypting side used.
+    Encrypted(ExpectedSize, BoxStream<'a, Result<(u64, Bytes), Error>>),
 }
 
 impl Debug for Chunks<'_> {
@@ -144,10 +164,308 @@ impl Debug for Chunks<'_> {
         match self {
             Chunks::Inline(_) => write!(f, "Chunks::Inline(..)"),
             Chunks::Chunked(size, _) => write!(f, "Chunks::Chunked({:?}, ...)", size),
+            Chunks::ChunkedWithIds(size, _) => {
+                write!(f, "Chunks::ChunkedWithIds({:?}, ...)", size)
+            }
+            Chunks::Encrypted(size, _) => write!(f, "Chunks::Encrypted({:?}, ...)", size),
+        }
+    }
+}
+
+impl<'a> Chunks<'a> {
+    /// Re-chunk an already-chunked `Chunks` to a different `chunk_size`, by
+    /// running its stream through a fresh `ChunkStream` rather than buffering
+    /// it into memory first. The reassembled bytes are unchanged: only the
+    /// chunk boundaries move.
+    ///
+    /// `Chunks::Inline` is returned as-is, since its content is already
+    /// buffered in full and isn't being streamed in chunks to begin with.
+    ///
+    /// `Chunks::ChunkedWithIds` is re-chunked like `Chunks::Chunked`, and has
+    /// its content ids recomputed, since moving the chunk boundaries makes
+    /// the old ones stale.
+    ///
+    /// `Chunks::Encrypted` cannot be rechunked, since its chunk boundaries
+    /// are baked into the ciphertext: call `rechunk` before encrypting.
+    pub fn rechunk(self, chunk_size: usize) -> Result<Chunks<'a>, Error> {
+        match self {
+            Chunks::Inline(fut) => Ok(Chunks::Inline(fut)),
+            Chunks::Chunked(expected_size, stream) => Ok(Chunks::Chunked(
+                expected_size,
+                ChunkStream::new(stream, chunk_size).boxed(),
+            )),
+            Chunks::ChunkedWithIds(expected_size, stream) => {
+                let stream = ChunkStream::new(stream.map_ok(|(_id, bytes)| bytes), chunk_size)
+                    .map_ok(with_content_id)
+                    .boxed();
+                Ok(Chunks::ChunkedWithIds(expected_size, stream))
+            }
+            Chunks::Encrypted(..) => {
+                bail!("cannot rechunk an already-encrypted Chunks")
+            }
+        }
+    }
+
+    /// Wrap this `Chunks`' stream with a second, independent running total of
+    /// the bytes it emits, and error out if that total ever exceeds
+    /// `expected_size`. This is defense in depth on top of the source-side
+    /// check `make_chunks` already applies via `size_limiter`: it runs on the
+    /// stream that's actually handed to callers, so it would also catch an
+    /// overrun introduced by a combinator (e.g. `rechunk`) applied afterwards.
+    ///
+    /// `Chunks::Inline` is returned as-is, since it's produced by a single
+    /// `try_fold` over the source stream and has no streaming emission to
+    /// guard here.
+    ///
+    /// `Chunks::Encrypted` is also returned as-is: its plaintext size was
+    /// already validated by `make_chunks` before encryption, and ciphertext
+    /// length need not track plaintext length one-to-one.
+    pub fn assert_max_total_size(self) -> Chunks<'a> {
+        match self {
+            Chunks::Inline(fut) => Chunks::Inline(fut),
+            Chunks::Chunked(expected_size, stream) => {
+                let stream = stream.map(size_limiter(expected_size)).boxed();
+                Chunks::Chunked(expected_size, stream)
+            }
+            Chunks::ChunkedWithIds(expected_size, stream) => {
+                let mut observed_size: u64 = 0;
+                let stream = stream
+                    .map(move |res| {
+                        let (id, bytes) = res?;
+                        observed_size += u64::try_from(bytes.len()).unwrap();
+                        expected_size.check_less(observed_size)?;
+                        Result::<_, Error>::Ok((id, bytes))
+                    })
+                    .boxed();
+                Chunks::ChunkedWithIds(expected_size, stream)
+            }
+            encrypted @ Chunks::Encrypted(..) => encrypted,
+        }
+    }
+
+    /// Apply a fallible byte-level transform to every chunk this `Chunks`
+    /// emits, without changing how many chunks there are or reassembling
+    /// them first. This is the building block for combinators (e.g.
+    /// compression, encryption, hashing) that need to touch each chunk's
+    /// bytes but shouldn't have to duplicate the `Inline`/`Chunked` handling
+    /// themselves.
+    ///
+    /// Any size validation (e.g. `assert_max_total_size`) should run before
+    /// `map_chunks`, not after: `f` can change a chunk's length, so once it's
+    /// run, the resulting stream's `expected_size` no longer describes the
+    /// bytes it emits.
+    ///
+    /// `Chunks::ChunkedWithIds` and `Chunks::Encrypted` aren't supported:
+    /// transforming their chunks would invalidate the content ids or
+    /// plaintext offsets carried alongside the bytes.
+    pub fn map_chunks<F>(self, f: F) -> Result<Chunks<'a>, Error>
+    where
+        F: Fn(Bytes) -> Result<Bytes, Error> + Send + Sync + 'a,
+    {
+        match self {
+            Chunks::Inline(fut) => Ok(Chunks::Inline(
+                fut.and_then(move |bytes| async move { f(bytes) }).boxed(),
+            )),
+            Chunks::Chunked(expected_size, stream) => {
+                let stream = stream.and_then(move |chunk| async move { f(chunk) }).boxed();
+                Ok(Chunks::Chunked(expected_size, stream))
+            }
+            Chunks::ChunkedWithIds(..) => {
+                bail!("cannot map_chunks a Chunks::ChunkedWithIds: it would invalidate the content ids")
+            }
+            Chunks::Encrypted(..) => {
+                bail!("cannot map_chunks a Chunks::Encrypted: it would invalidate the plaintext offsets")
+            }
+        }
+    }
+
+    /// Wrap this `Chunks`' stream so that every chunk it emits is logged to
+    /// `logger` at trace level with its size, without altering the bytes (or
+    /// `ContentId`s) it emits. This is meant for debugging chunking behavior
+    /// in production, where the sizes of the chunks actually produced aren't
+    /// otherwise visible.
+    ///
+    /// `Chunks::Inline` is returned as-is, since it's produced by a single
+    /// `try_fold` over the source stream and never emits individual chunks.
+    pub fn log_chunk_sizes(self, logger: Logger) -> Chunks<'a> {
+        match self {
+            Chunks::Inline(fut) => Chunks::Inline(fut),
+            Chunks::Chunked(expected_size, stream) => {
+                let stream = stream
+                    .inspect_ok(move |chunk| {
+                        trace!(logger, "emitted chunk of {} bytes", chunk.len())
+                    })
+                    .boxed();
+                Chunks::Chunked(expected_size, stream)
+            }
+            Chunks::ChunkedWithIds(expected_size, stream) => {
+                let stream = stream
+                    .inspect_ok(move |(_id, chunk)| {
+                        trace!(logger, "emitted chunk of {} bytes", chunk.len())
+                    })
+                    .boxed();
+                Chunks::ChunkedWithIds(expected_size, stream)
+            }
+            Chunks::Encrypted(expected_size, stream) => {
+                let stream = stream
+                    .inspect_ok(move |(offset, ciphertext)| {
+                        trace!(
+                            logger,
+                            "emitted encrypted chunk of {} bytes at plaintext offset {}",
+                            ciphertext.len(),
+                            offset
+                        )
+                    })
+                    .boxed();
+                Chunks::Encrypted(expected_size, stream)
+            }
+        }
+    }
+
+    /// Wrap this `Chunks`' stream so it emits no more than `bytes_per_second`
+    /// bytes per second on average, using a token bucket that allows an
+    /// initial burst of up to one second's worth of tokens. This is for
+    /// throttling uploads/downloads: neither the emitted bytes nor the
+    /// `expected_size` checks are altered, only the pacing of emission.
+    ///
+    /// `Chunks::Inline` is returned as-is, since it's produced by a single
+    /// `try_fold` over the source stream and has no streaming emission to
+    /// pace here.
+    pub fn rate_limit(self, bytes_per_second: u64) -> Chunks<'a> {
+        match self {
+            Chunks::Inline(fut) => Chunks::Inline(fut),
+            Chunks::Chunked(expected_size, stream) => {
+                let stream = rate_limited(stream, bytes_per_second, |chunk: &Bytes| chunk.len());
+                Chunks::Chunked(expected_size, stream)
+            }
+            Chunks::ChunkedWithIds(expected_size, stream) => {
+                let stream = rate_limited(stream, bytes_per_second, |item: &(ContentId, Bytes)| {
+                    item.1.len()
+                });
+                Chunks::ChunkedWithIds(expected_size, stream)
+            }
+            Chunks::Encrypted(expected_size, stream) => {
+                let stream = rate_limited(stream, bytes_per_second, |item: &(u64, Bytes)| {
+                    item.1.len()
+                });
+                Chunks::Encrypted(expected_size, stream)
+            }
         }
     }
 }
 
+/// A token bucket with capacity for one second's worth of `bytes_per_second`
+/// tokens, refilled continuously based on elapsed wall-clock time.
+struct TokenBucket {
+    bytes_per_second: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "bytes_per_second must be positive");
+        Self {
+            bytes_per_second,
+            tokens: bytes_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Wait until `n` bytes' worth of tokens have accumulated, then spend them.
+    async fn acquire(&mut self, n: u64) {
+        loop {
+            let now = Instant::now();
+            let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+            self.last_refill = now;
+            self.tokens = (self.tokens + elapsed * self.bytes_per_second as f64)
+                .min(self.bytes_per_second as f64);
+
+            let n = n as f64;
+            if self.tokens >= n {
+                self.tokens -= n;
+                return;
+            }
+
+            let deficit = n - self.tokens;
+            tokio::time::sleep(Duration::from_secs_f64(
+                deficit / self.bytes_per_second as f64,
+            ))
+            .await;
+        }
+    }
+}
+
+/// Pace `stream`'s emission to `bytes_per_second`, spending `size_of(item)`
+/// tokens from a `TokenBucket` before letting each item through. Built on
+/// `stream::unfold` rather than a hand-rolled `Stream` impl, since the state
+/// being threaded (the bucket and the source stream) is exactly what
+/// `unfold` is for.
+fn rate_limited<'a, S, T, F>(
+    stream: S,
+    bytes_per_second: u64,
+    size_of: F,
+) -> BoxStream<'a, Result<T, Error>>
+where
+    S: Stream<Item = Result<T, Error>> + Send + 'a,
+    T: Send + 'a,
+    F: Fn(&T) -> usize + Send + 'a,
+{
+    let bucket = TokenBucket::new(bytes_per_second);
+    stream::unfold(
+        (bucket, Box::pin(stream), size_of),
+        move |(mut bucket, mut stream, size_of)| async move {
+            match stream.next().await {
+                Some(Ok(item)) => {
+                    bucket.acquire(size_of(&item) as u64).await;
+                    Some((Ok(item), (bucket, stream, size_of)))
+                }
+                Some(Err(e)) => Some((Err(e), (bucket, stream, size_of))),
+                None => None,
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Compute a chunk's `ContentId` and pair it with the chunk.
+fn with_content_id(chunk: Bytes) -> (ContentId, Bytes) {
+    let id = hash_bytes(ContentIdIncrementalHasher::new(), &chunk);
+    (id, chunk)
+}
+
+// NOTE: We stop reading if the stream we are provided exceeds the expected_size we were given.
+// While we do check later that the stream matches *exactly* the size we were given, doing this
+// check lets us bail early (and e.g. ensures that if we are told something is 1 byte but it
+// actually is 1TB, we don't try to buffer the whole 1TB).
+fn size_limiter(expected_size: ExpectedSize) -> impl FnMut(Result<Bytes, Error>) -> Result<Bytes, Error> {
+    let mut observed_size: u64 = 0; // This moves into the closure below and serves as its state.
+    move |chunk: Result<Bytes, Error>| {
+        // NOTE: unwrap() will fail if we have a Bytes whose length is too large to fit in a u64.
+        // We presumably don't have such Bytes in memory!
+        let chunk = chunk?;
+        observed_size += u64::try_from(chunk.len()).unwrap();
+        expected_size.check_less(observed_size)?;
+        Result::<_, Error>::Ok(chunk)
+    }
+}
+
+/// Chunk a stream of incoming data into a `Stream` of `Result<Bytes, Error>`, for callers that
+/// want a stream rather than the future of the `Chunks::Inline` path produced by `make_chunks`.
+/// The data goes through a `ChunkStream` whose chunk size is `expected_size`, so content of the
+/// advertised size comes out as a single item; that item is still accumulated in one buffer
+/// before it is emitted. Size limits are enforced incrementally as chunks arrive.
+pub fn make_chunks_stream<'a, S>(
+    data: S,
+    expected_size: ExpectedSize,
+) -> BoxStream<'a, Result<Bytes, Error>>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+{
+    let data = data.map(size_limiter(expected_size));
+    ChunkStream::new(data, expected_size.new_buffer().capacity().max(1)).boxed()
+}
+
 /// Chunk a stream of incoming data for storage. We use the incoming size hint to decide whether
 /// to chunk.
 pub fn make_chunks<'a, S>(
@@ -158,23 +476,7 @@ pub fn make_chunks<'a, S>(
 where
     S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
 {
-    // NOTE: We stop reading if the stream we are provided exceeds the expected_size we were given.
-    // While we do check later that the stream matches *exactly* the size we were given, doing this
-    // check lets us bail early (and e.g. ensures that if we are told something is 1 byte but it
-    // actually is 1TB, we don't try to buffer the whole 1TB).
-    let limit = {
-        let mut observed_size: u64 = 0; // This moves into the closure below and serves as its state.
-        move |chunk: Result<Bytes, Error>| {
-            // NOTE: unwrap() will fail if we have a Bytes whose length is too large to fit in a u64.
-            // We presumably don't have such Bytes in memory!
-            let chunk = chunk?;
-            observed_size += u64::try_from(chunk.len()).unwrap();
-            expected_size.check_less(observed_size)?;
-            Result::<_, Error>::Ok(chunk)
-        }
-    };
-
-    let data = data.map(limit);
+    let data = data.map(size_limiter(expected_size));
 
     match chunk_size {
         Some(chunk_size) if expected_size.should_chunk(chunk_size) => {
@@ -197,6 +499,349 @@ where
     }
 }
 
+/// Like `make_chunks`, but when the data ends up being chunked, also
+/// computes each chunk's `ContentId` as it streams, emitting `(ContentId,
+/// Bytes)` pairs instead of plain `Bytes`. This is for callers that need to
+/// address each chunk by its content id (e.g. a chunk-addressed store), so
+/// they don't need a second pass over the chunked stream just to hash it.
+pub fn make_chunks_with_ids<'a, S>(
+    data: S,
+    expected_size: ExpectedSize,
+    chunk_size: Option<u64>,
+) -> Chunks<'a>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+{
+    match make_chunks(data, expected_size, chunk_size) {
+        Chunks::Chunked(expected_size, stream) => {
+            Chunks::ChunkedWithIds(expected_size, stream.map_ok(with_content_id).boxed())
+        }
+        inline @ Chunks::Inline(..) => inline,
+        Chunks::ChunkedWithIds(..) => {
+            unreachable!("make_chunks never returns Chunks::ChunkedWithIds")
+        }
+        Chunks::Encrypted(..) => {
+            unreachable!("make_chunks never returns Chunks::Encrypted")
+        }
+    }
+}
+
+/// Round `chunk_size` up to the nearest multiple of `align_to`. Chunking
+/// always starts at offset zero, which is trivially aligned, so rounding up
+/// the chunk size this way is enough to keep every later chunk boundary
+/// (other than the very last, if the data doesn't divide evenly) aligned to
+/// `align_to` too.
+fn align_chunk_size(chunk_size: u64, align_to: u64) -> u64 {
+    assert!(align_to > 0, "align_to must be greater than zero");
+    let remainder = chunk_size % align_to;
+    if remainder == 0 {
+        chunk_size.max(align_to)
+    } else {
+        chunk_size - remainder + align_to
+    }
+}
+
+/// Like `make_chunks`, but rounds `chunk_size` up to the nearest multiple of
+/// `align_to` first, so that chunk boundaries (offsets) are multiples of
+/// `align_to` where possible, for storage backends that prefer aligned
+/// offsets. Only the last chunk may come back shorter than the aligned
+/// chunk size, if `expected_size` doesn't divide evenly; every other
+/// boundary lands exactly on a multiple of `align_to`.
+pub fn make_chunks_aligned<'a, S>(
+    data: S,
+    expected_size: ExpectedSize,
+    chunk_size: Option<u64>,
+    align_to: u64,
+) -> Chunks<'a>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+{
+    let chunk_size = chunk_size.map(|chunk_size| align_chunk_size(chunk_size, align_to));
+    make_chunks(data, expected_size, chunk_size)
+}
+
+/// Like `make_chunks`, but instead of a fixed `chunk_size`, splits the data
+/// into parts of `ceil(expected_size / n)` bytes (at least one byte), so
+/// there are at most `n` of them. Every part but the last has that size; the
+/// last holds whatever is left and may be shorter. Fewer than `n` parts come
+/// back when the data is too small, or when the size doesn't divide that way
+/// (e.g. 9 bytes into 4 parts gives three 3-byte parts). Always
+/// returns `Chunks::Chunked`, even for data small enough that `make_chunks`
+/// would have buffered it inline: this is for callers that need to fan a
+/// piece of content out across a fixed number of destinations (e.g.
+/// parallel upload to `n` backends), rather than caring about the size of
+/// each individual chunk.
+pub fn make_n_chunks<'a, S>(data: S, expected_size: ExpectedSize, n: u64) -> Chunks<'a>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+{
+    assert!(n > 0, "n must be greater than zero");
+
+    let size = expected_size.into_inner();
+    let part_size = ((size + n - 1) / n).max(1) as usize;
+
+    let data = data.map(size_limiter(expected_size));
+    let stream = ChunkStream::new(data, part_size);
+    Chunks::Chunked(expected_size, stream.boxed())
+}
+
+/// An at-rest encryption cipher for chunked content. Implementations are
+/// expected to derive a per-chunk nonce from `plaintext_offset` (e.g. via a
+/// counter or a KDF), so that encrypting the same content twice with the
+/// same key is still safe against nonce reuse across chunks.
+pub trait ChunkCipher {
+    /// Encrypt a single chunk whose first byte was at `plaintext_offset` in
+    /// the original content.
+    fn encrypt(&self, plaintext_offset: u64, plaintext: Bytes) -> Result<Bytes, Error>;
+
+    /// Reverse `encrypt` for a chunk previously encrypted at
+    /// `plaintext_offset`.
+    fn decrypt(&self, plaintext_offset: u64, ciphertext: Bytes) -> Result<Bytes, Error>;
+}
+
+/// Like `make_chunks`, but when the data ends up being chunked, encrypts
+/// each chunk with `cipher` after its plaintext has passed through
+/// `make_chunks`' size validation, pairing every ciphertext chunk with the
+/// plaintext offset it started at so a decrypting reader can derive the same
+/// nonce `cipher` used. Validating plaintext size before encrypting (rather
+/// than after) means a truncated or oversized upload is rejected without
+/// ever invoking the cipher on bad input.
+pub fn make_chunks_with_encryption<'a, S, C>(
+    data: S,
+    expected_size: ExpectedSize,
+    chunk_size: Option<u64>,
+    cipher: C,
+) -> Chunks<'a>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+    C: ChunkCipher + Send + Sync + 'a,
+{
+    match make_chunks(data, expected_size, chunk_size) {
+        Chunks::Chunked(expected_size, stream) => {
+            let mut plaintext_offset: u64 = 0;
+            let stream = stream
+                .map(move |res| {
+                    let chunk = res?;
+                    let offset = plaintext_offset;
+                    plaintext_offset += u64::try_from(chunk.len()).unwrap();
+                    let ciphertext = cipher.encrypt(offset, chunk)?;
+                    Result::<_, Error>::Ok((offset, ciphertext))
+                })
+                .boxed();
+            Chunks::Encrypted(expected_size, stream)
+        }
+        inline @ Chunks::Inline(..) => inline,
+        Chunks::ChunkedWithIds(..) => {
+            unreachable!("make_chunks never returns Chunks::ChunkedWithIds")
+        }
+        Chunks::Encrypted(..) => {
+            unreachable!("make_chunks never returns Chunks::Encrypted")
+        }
+    }
+}
+
+/// A `Stream` adapter that feeds every chunk it forwards into a `Hasher` as
+/// it goes, then sends the finished digest down `sender` once the
+/// underlying stream is exhausted. This lets `make_chunks_with_digest`
+/// compute a whole-content digest off the same single read of the data that
+/// produces the chunks, rather than hashing the reassembled content in a
+/// second pass.
+///
+/// If the underlying stream errors, the digest is never sent, and the
+/// corresponding receiver resolves to an error once this stream is dropped.
+#[pin_project::pin_project]
+struct DigestStream<S, T, H> {
+    #[pin]
+    stream: S,
+    hasher: Option<T>,
+    sender: Option<oneshot::Sender<H>>,
+}
+
+impl<S, T, H> DigestStream<S, T, H>
+where
+    S: Stream<Item = Result<Bytes, Error>>,
+    T: Hasher<H>,
+{
+    fn new<'a>(stream: S, hasher: T) -> (Self, BoxFuture<'a, Result<H, Error>>)
+    where
+        S: Send + 'a,
+        T: Send + 'a,
+        H: Send + 'a,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let stream = DigestStream {
+            stream,
+            hasher: Some(hasher),
+            sender: Some(sender),
+        };
+        (stream, receiver.map_err(Error::from).boxed())
+    }
+}
+
+impl<S, T, H> Stream for DigestStream<S, T, H>
+where
+    S: Stream<Item = Result<Bytes, Error>>,
+    T: Hasher<H>,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let proj = self.project();
+
+        match futures::ready!(proj.stream.poll_next(ctx)) {
+            Some(Ok(chunk)) => {
+                if let Some(hasher) = proj.hasher.as_mut() {
+                    hasher.update(&chunk);
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                if let (Some(hasher), Some(sender)) = (proj.hasher.take(), proj.sender.take()) {
+                    // If the receiver was dropped, there's nobody left to care about the digest.
+                    let _ = sender.send(hasher.finish());
+                }
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+/// Like `make_chunks`, but also incrementally computes a whole-content
+/// digest (e.g. a sha256, as opposed to `make_chunks_with_ids`, which hashes
+/// each chunk separately) off the same data as it streams through, and
+/// returns it via a future alongside the `Chunks`. This is for callers that
+/// need to key or verify content by its whole-content hash without paying
+/// for a second pass over the data.
+///
+/// The returned future resolves once the `Chunks` value has been fully
+/// consumed; dropping the `Chunks` before draining it makes the future
+/// resolve to an error, and if the underlying stream errors partway through,
+/// the future resolves to an error instead of a partial digest.
+pub fn make_chunks_with_digest<'a, S, T, H>(
+    data: S,
+    expected_size: ExpectedSize,
+    chunk_size: Option<u64>,
+    hasher: T,
+) -> (Chunks<'a>, BoxFuture<'a, Result<H, Error>>)
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+    T: Hasher<H> + Send + 'a,
+    H: Send + 'a,
+{
+    let data = data.map(size_limiter(expected_size));
+
+    match chunk_size {
+        Some(chunk_size) if expected_size.should_chunk(chunk_size) => {
+            let stream = ChunkStream::new(data, chunk_size as usize);
+            let (stream, digest) = DigestStream::new(stream, hasher);
+            (Chunks::Chunked(expected_size, stream.boxed()), digest)
+        }
+        _ => {
+            let (sender, receiver) = oneshot::channel();
+            let fut = data
+                .try_fold(
+                    (expected_size.new_buffer(), hasher),
+                    |(mut bytes, mut hasher), incoming| async move {
+                        hasher.update(incoming.as_ref());
+                        bytes.extend_from_slice(incoming.as_ref());
+                        Result::<_, Error>::Ok((bytes, hasher))
+                    },
+                )
+                .map(move |res| {
+                    res.map(|(bytes, hasher)| {
+                        // If the receiver was dropped, there's nobody left to care about the digest.
+                        let _ = sender.send(hasher.finish());
+                        bytes.freeze()
+                    })
+                })
+                .boxed();
+            (Chunks::Inline(fut), receiver.map_err(Error::from).boxed())
+        }
+    }
+}
+
+/// Reassemble a `Chunks` into the single `Bytes` value it represents,
+/// regardless of whether the data ended up inline or chunked. Mostly useful
+/// for tests and verification tooling that need to compare the result of
+/// chunking against the original input, so they don't have to roll their own
+/// fold over each `Chunks` variant.
+pub fn collect_chunks<'a>(chunks: Chunks<'a>) -> BoxFuture<'a, Result<Bytes, Error>> {
+    match chunks {
+        Chunks::Inline(fut) => fut,
+        Chunks::Chunked(_, stream) => concat_stream(stream),
+        Chunks::ChunkedWithIds(_, stream) => concat_stream(stream.map_ok(|(_id, bytes)| bytes)),
+        Chunks::Encrypted(_, stream) => concat_stream(stream.map_ok(|(_offset, bytes)| bytes)),
+    }
+}
+
+fn concat_stream<'a, S>(stream: S) -> BoxFuture<'a, Result<Bytes, Error>>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Send + 'a,
+{
+    stream
+        .try_fold(BytesMut::new(), |mut acc, chunk| async move {
+            acc.extend_from_slice(&chunk);
+            Result::<_, Error>::Ok(acc)
+        })
+        .map_ok(BytesMut::freeze)
+        .boxed()
+}
+
+/// Reads an arbitrary chunk of a seekable source by index, without reading
+/// through the chunks that precede it. This is for random-access callers
+/// (e.g. a chunk-addressed store serving a single chunk of a large file)
+/// that would otherwise have to drain a `ChunkStream` from the start just to
+/// reach the chunk they want.
+///
+/// Chunk boundaries match `make_chunks`: chunk `i` covers bytes
+/// `[i * chunk_size, (i + 1) * chunk_size)` of the source, with the last
+/// chunk truncated to whatever is left under `expected_size`.
+pub struct ChunkReader<S> {
+    source: S,
+    chunk_size: usize,
+    expected_size: ExpectedSize,
+}
+
+impl<S> ChunkReader<S>
+where
+    S: AsyncRead + AsyncSeek + Unpin,
+{
+    pub fn new(source: S, chunk_size: usize, expected_size: ExpectedSize) -> Self {
+        assert!(chunk_size > 0);
+
+        Self {
+            source,
+            chunk_size,
+            expected_size,
+        }
+    }
+
+    /// Seek directly to chunk `index` and read it, without touching any
+    /// earlier chunk. The last chunk of the source may come back shorter
+    /// than `chunk_size`; requesting an index past the end of the source is
+    /// an error.
+    pub async fn read_chunk(&mut self, index: u64) -> Result<Bytes, Error> {
+        let chunk_size = self.chunk_size as u64;
+        let start = index
+            .checked_mul(chunk_size)
+            .ok_or_else(|| Error::msg("chunk index overflows chunk offset"))?;
+        // The last valid start is the last byte of the source; anything past
+        // that has no data to read.
+        self.expected_size.check_less(start.saturating_add(1))?;
+
+        self.source.seek(std::io::SeekFrom::Start(start)).await?;
+
+        let mut buf = Vec::new();
+        (&mut self.source)
+            .take(chunk_size)
+            .read_to_end(&mut buf)
+            .await?;
+
+        Ok(Bytes::from(buf))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use assert_matches::assert_matches;
@@ -247,6 +892,496 @@ mod test {
         };
     }
 
+    #[tokio::test]
+    async fn test_make_chunks_stream_sub_threshold() {
+        let chunks = vec![Bytes::from(vec![1; 5]), Bytes::from(vec![2; 3])];
+        let in_stream = stream::iter(chunks.clone()).map(Ok);
+
+        let out = make_chunks_stream(in_stream, ExpectedSize::new(8))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let expected: Bytes = chunks.into_iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(&c);
+            b
+        }).freeze();
+
+        let got: Bytes = out.into_iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(&c);
+            b
+        }).freeze();
+
+        assert_eq!(expected, got);
+    }
+
+    #[tokio::test]
+    async fn test_rechunk_chunked() {
+        // Chunk some data at size 10, then rechunk it down to size 7, and
+        // check that the reassembled bytes are identical and the new chunks
+        // obey the new chunk size.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(25), Some(10));
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let rechunked = chunks.rechunk(7).unwrap();
+
+        let stream = match rechunked {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+
+        let got: Bytes = out_chunks.iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(c);
+            b
+        }).freeze();
+
+        assert_eq!(got, data);
+
+        for chunk in &out_chunks[..out_chunks.len() - 1] {
+            assert_eq!(chunk.len(), 7);
+        }
+        assert!(out_chunks.last().unwrap().len() <= 7);
+    }
+
+    #[tokio::test]
+    async fn test_make_chunks_aligned_interior_offsets() {
+        // Ask for a 7-byte chunk size aligned to 10 bytes: it should round up
+        // to chunk size 10, so every interior chunk boundary is a multiple of
+        // 10, while the reassembled bytes still match the input exactly.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks_aligned(in_stream, ExpectedSize::new(25), Some(7), 10);
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let stream = match chunks {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+
+        let got: Bytes = out_chunks.iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(c);
+            b
+        }).freeze();
+        assert_eq!(got, data);
+
+        let mut offset: u64 = 0;
+        for chunk in &out_chunks[..out_chunks.len() - 1] {
+            offset += chunk.len() as u64;
+            assert_eq!(offset % 10, 0, "interior chunk boundary {} isn't aligned", offset);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_assert_max_total_size_chunked_over_emits() {
+        // Build a Chunks::Chunked directly from a mock stream that emits more
+        // bytes than expected_size, bypassing make_chunks' own source-side
+        // size_limiter entirely. assert_max_total_size should still catch the
+        // overrun on its own.
+        let expected_size = ExpectedSize::new(5);
+        let mock_stream = stream::iter(vec![Bytes::from(vec![1; 5]), Bytes::from(vec![1; 5])])
+            .map(Ok)
+            .boxed();
+
+        let chunks = Chunks::Chunked(expected_size, mock_stream).assert_max_total_size();
+
+        let stream = match chunks {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        stream.try_collect::<Vec<_>>().await.expect_err(
+            "assert_max_total_size should catch over-emission independent of make_chunks' own check",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_map_chunks_uppercase_chunked() {
+        // Chunk some lowercase data, uppercase each chunk via map_chunks, and
+        // check the reassembled bytes are the uppercased original.
+        let data = Bytes::from(b"abcdefghijklmnopqrstuvwxy".to_vec());
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(25), Some(10));
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let mapped = chunks
+            .map_chunks(|chunk| Ok(Bytes::from(chunk.to_ascii_uppercase())))
+            .unwrap();
+
+        let got = collect_chunks(mapped).await.unwrap();
+        assert_eq!(got, Bytes::from(data.to_ascii_uppercase()));
+    }
+
+    #[tokio::test]
+    async fn test_map_chunks_uppercase_inline() {
+        let data = Bytes::from(b"abcdefg".to_vec());
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(7), Some(100));
+        assert_matches!(chunks, Chunks::Inline(..));
+
+        let mapped = chunks
+            .map_chunks(|chunk| Ok(Bytes::from(chunk.to_ascii_uppercase())))
+            .unwrap();
+
+        let got = collect_chunks(mapped).await.unwrap();
+        assert_eq!(got, Bytes::from(data.to_ascii_uppercase()));
+    }
+
+    #[tokio::test]
+    async fn test_map_chunks_chunked_with_ids_rejected() {
+        // map_chunks would invalidate the content ids carried alongside a
+        // Chunks::ChunkedWithIds, so it must return an error rather than
+        // panicking.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data]).map(Ok);
+
+        let chunks = make_chunks_with_ids(in_stream, ExpectedSize::new(25), Some(10));
+        assert_matches!(chunks, Chunks::ChunkedWithIds(..));
+
+        chunks
+            .map_chunks(|chunk| Ok(chunk))
+            .expect_err("map_chunks on a ChunkedWithIds should error, not panic");
+    }
+
+    #[derive(Default)]
+    struct TestLogCapture(std::sync::Mutex<Vec<String>>);
+
+    impl slog::Drain for TestLogCapture {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record<'_>,
+            _values: &slog::OwnedKVList,
+        ) -> Result<Self::Ok, Self::Err> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_chunk_sizes() {
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(25), Some(10));
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let capture = std::sync::Arc::new(TestLogCapture::default());
+        let logger = slog::Logger::root(capture.clone(), slog::o!());
+
+        let stream = match chunks.log_chunk_sizes(logger) {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(out_chunks.len(), 3);
+
+        let logged = capture.0.lock().unwrap();
+        assert_eq!(logged.len(), out_chunks.len());
+        for chunk in &out_chunks {
+            let expected_msg = format!("emitted chunk of {} bytes", chunk.len());
+            assert!(
+                logged.contains(&expected_msg),
+                "expected {:?} to contain {:?}",
+                logged,
+                expected_msg
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_paces_emission() {
+        // Chunk 25 bytes into five 5-byte chunks, then rate-limit to 5 bytes
+        // per second: emitting the whole 25 bytes should take at least 4
+        // seconds (the first chunk is a free burst, the other four each wait
+        // for a fresh second's worth of tokens).
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(25), Some(5)).rate_limit(5);
+
+        let stream = match chunks {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let start = tokio::time::Instant::now();
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        let elapsed = start.elapsed();
+
+        let got: Bytes = out_chunks.iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(c);
+            b
+        }).freeze();
+        assert_eq!(got, data);
+        assert!(
+            elapsed >= Duration::from_secs(4),
+            "rate-limited emission finished too quickly: {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_make_chunks_with_ids() {
+        // Chunk some data, and check that each chunk's id matches an
+        // independently computed hash of that chunk's bytes.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data]).map(Ok);
+
+        let chunks = make_chunks_with_ids(in_stream, ExpectedSize::new(25), Some(10));
+
+        let stream = match chunks {
+            Chunks::ChunkedWithIds(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(out_chunks.len(), 3);
+
+        for (id, bytes) in out_chunks {
+            let expected_id = hash_bytes(ContentIdIncrementalHasher::new(), &bytes);
+            assert_eq!(id, expected_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_make_n_chunks_splits_into_n_parts() {
+        // 25 bytes split into 3 parts of ceil(25 / 3) = 9 bytes: two full
+        // 9-byte parts, and a last part holding the remaining 7.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_n_chunks(in_stream, ExpectedSize::new(25), 3);
+
+        let stream = match chunks {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(out_chunks.len(), 3);
+
+        let got: Bytes = out_chunks.iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(c);
+            b
+        }).freeze();
+        assert_eq!(got, data);
+    }
+
+    #[tokio::test]
+    async fn test_make_n_chunks_fewer_parts_for_tiny_input() {
+        // Asking for more parts than there are bytes should just yield one
+        // part per byte, not empty padding parts.
+        let data = Bytes::from(vec![1; 2]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_n_chunks(in_stream, ExpectedSize::new(2), 5);
+
+        let stream = match chunks {
+            Chunks::Chunked(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(out_chunks.len(), 2);
+
+        let got: Bytes = out_chunks.iter().fold(BytesMut::new(), |mut b, c| {
+            b.extend_from_slice(c);
+            b
+        }).freeze();
+        assert_eq!(got, data);
+    }
+
+    /// A trivial reversible `ChunkCipher` for tests: XOR every byte with a
+    /// key byte selected by `plaintext_offset`. This is not a real cipher,
+    /// but it round-trips and, like a real stream cipher, produces different
+    /// ciphertext for the same plaintext at different offsets.
+    struct XorCipher {
+        key: Vec<u8>,
+    }
+
+    impl XorCipher {
+        fn apply(&self, plaintext_offset: u64, bytes: Bytes) -> Bytes {
+            let key = &self.key;
+            let out: Vec<u8> = bytes
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    let key_byte = key[(plaintext_offset as usize + i) % key.len()];
+                    b ^ key_byte
+                })
+                .collect();
+            Bytes::from(out)
+        }
+    }
+
+    impl ChunkCipher for XorCipher {
+        fn encrypt(&self, plaintext_offset: u64, plaintext: Bytes) -> Result<Bytes, Error> {
+            Ok(self.apply(plaintext_offset, plaintext))
+        }
+
+        fn decrypt(&self, plaintext_offset: u64, ciphertext: Bytes) -> Result<Bytes, Error> {
+            Ok(self.apply(plaintext_offset, ciphertext))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_make_chunks_with_encryption_round_trips() {
+        // Chunk and encrypt some data, then decrypt each chunk with its
+        // recorded plaintext offset and check the reassembled plaintext
+        // matches the original.
+        let data = Bytes::from((0u8..25).collect::<Vec<u8>>());
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+        let cipher = XorCipher {
+            key: vec![0xaa, 0x55, 0x0f],
+        };
+
+        let chunks = make_chunks_with_encryption(in_stream, ExpectedSize::new(25), Some(10), cipher);
+
+        let stream = match chunks {
+            Chunks::Encrypted(_, stream) => stream,
+            c => panic!("Did not expect {:?}", c),
+        };
+
+        let out_chunks = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(out_chunks.len(), 3);
+        assert_ne!(
+            out_chunks[0].1, data[0..10],
+            "ciphertext should differ from plaintext"
+        );
+
+        let decrypt_cipher = XorCipher {
+            key: vec![0xaa, 0x55, 0x0f],
+        };
+        let got: Bytes = out_chunks
+            .into_iter()
+            .fold(BytesMut::new(), |mut b, (offset, ciphertext)| {
+                let plaintext = decrypt_cipher.decrypt(offset, ciphertext).unwrap();
+                b.extend_from_slice(&plaintext);
+                b
+            })
+            .freeze();
+
+        assert_eq!(got, data);
+    }
+
+    #[tokio::test]
+    async fn test_map_chunks_encrypted_rejected() {
+        // map_chunks would bypass the cipher and corrupt already-encrypted
+        // chunks, so it must return an error rather than panicking.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data]).map(Ok);
+        let cipher = XorCipher {
+            key: vec![0xaa, 0x55, 0x0f],
+        };
+
+        let chunks = make_chunks_with_encryption(in_stream, ExpectedSize::new(25), Some(10), cipher);
+        assert_matches!(chunks, Chunks::Encrypted(..));
+
+        chunks
+            .map_chunks(|chunk| Ok(chunk))
+            .expect_err("map_chunks on an Encrypted should error, not panic");
+    }
+
+    #[tokio::test]
+    async fn test_rechunk_encrypted_rejected() {
+        // rechunk operates on plaintext chunk boundaries, which no longer
+        // apply once chunks are encrypted, so it must return an error
+        // rather than panicking.
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data]).map(Ok);
+        let cipher = XorCipher {
+            key: vec![0xaa, 0x55, 0x0f],
+        };
+
+        let chunks = make_chunks_with_encryption(in_stream, ExpectedSize::new(25), Some(10), cipher);
+        assert_matches!(chunks, Chunks::Encrypted(..));
+
+        chunks
+            .rechunk(7)
+            .expect_err("rechunk on an Encrypted should error, not panic");
+    }
+
+    #[tokio::test]
+    async fn test_make_chunks_with_digest_inline() {
+        use crate::incremental_hash::Sha256IncrementalHasher;
+
+        let data = Bytes::from(vec![1; 10]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let (chunks, digest) = make_chunks_with_digest(
+            in_stream,
+            ExpectedSize::new(10),
+            Some(100),
+            Sha256IncrementalHasher::new(),
+        );
+        assert_matches!(chunks, Chunks::Inline(..));
+
+        let got = collect_chunks(chunks).await.unwrap();
+        assert_eq!(got, data);
+
+        let expected_digest = hash_bytes(Sha256IncrementalHasher::new(), &data);
+        assert_eq!(digest.await.unwrap(), expected_digest);
+    }
+
+    #[tokio::test]
+    async fn test_make_chunks_with_digest_chunked() {
+        use crate::incremental_hash::Sha256IncrementalHasher;
+
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let (chunks, digest) = make_chunks_with_digest(
+            in_stream,
+            ExpectedSize::new(25),
+            Some(10),
+            Sha256IncrementalHasher::new(),
+        );
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let got = collect_chunks(chunks).await.unwrap();
+        assert_eq!(got, data);
+
+        let expected_digest = hash_bytes(Sha256IncrementalHasher::new(), &data);
+        assert_eq!(digest.await.unwrap(), expected_digest);
+    }
+
+    #[tokio::test]
+    async fn test_collect_chunks_inline() {
+        let data = Bytes::from(vec![1; 10]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(10), Some(100));
+        assert_matches!(chunks, Chunks::Inline(..));
+
+        let got = collect_chunks(chunks).await.unwrap();
+        assert_eq!(got, data);
+    }
+
+    #[tokio::test]
+    async fn test_collect_chunks_chunked() {
+        let data = Bytes::from(vec![1; 25]);
+        let in_stream = stream::iter(vec![data.clone()]).map(Ok);
+
+        let chunks = make_chunks(in_stream, ExpectedSize::new(25), Some(10));
+        assert_matches!(chunks, Chunks::Chunked(..));
+
+        let got = collect_chunks(chunks).await.unwrap();
+        assert_eq!(got, data);
+    }
+
     #[tokio::test]
     async fn test_make_chunks_overflow_inline() {
         // Make chunks buffers if we expect content that is small enough to fit the chunk size.
@@ -438,6 +1573,60 @@ mod test {
         true
     }
 
+    #[pin_project::pin_project]
+    struct CountingReader<R> {
+        #[pin]
+        inner: R,
+        bytes_read: usize,
+    }
+
+    impl<R: AsyncRead> AsyncRead for CountingReader<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            ctx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let proj = self.project();
+            let before = buf.filled().len();
+            let res = proj.inner.poll_read(ctx, buf);
+            if res.is_ready() {
+                *proj.bytes_read += buf.filled().len() - before;
+            }
+            res
+        }
+    }
+
+    impl<R: AsyncSeek> AsyncSeek for CountingReader<R> {
+        fn start_seek(self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+            self.project().inner.start_seek(position)
+        }
+
+        fn poll_complete(
+            self: Pin<&mut Self>,
+            ctx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<u64>> {
+            self.project().inner.poll_complete(ctx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chunk_reader_reads_single_chunk_by_index() {
+        // 25 bytes, distinct per-byte values so a wrong offset is obvious.
+        let data: Vec<u8> = (0..25).collect();
+        let source = CountingReader {
+            inner: std::io::Cursor::new(data.clone()),
+            bytes_read: 0,
+        };
+
+        let mut reader = ChunkReader::new(source, 5, ExpectedSize::new(25));
+        let chunk = reader.read_chunk(2).await.unwrap();
+
+        assert_eq!(chunk.as_ref(), &data[10..15]);
+        // Only chunk 2's bytes were ever read: chunks 0 and 1 were skipped
+        // over via seek, not read and discarded.
+        assert_eq!(reader.source.bytes_read, 5);
+    }
+
     quickcheck! {
         fn check_chunk_stream(in_chunks: Vec<Vec<u8>>, size: u8) -> bool {
             let size = (size as usize) + 1; // Don't allow 0 as the size.
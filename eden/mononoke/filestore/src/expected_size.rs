@@ -45,6 +45,13 @@ impl ExpectedSize {
         // NOTE: This will panic if we can't fit an u64 into usize. That's expected.
         BytesMut::with_capacity(self.0.try_into().unwrap())
     }
+
+    // Unlike the methods above, this hands back the raw size. It's meant for
+    // arithmetic (e.g. deriving a chunk size to split evenly into a fixed
+    // number of parts), not for treating the hint as a trusted observed size.
+    pub(crate) fn into_inner(self) -> u64 {
+        self.0
+    }
 }
 
 /// The incremental_hash crate does need access to the internal u64 to create its hashes, so we
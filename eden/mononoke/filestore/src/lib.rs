@@ -46,7 +46,14 @@ mod rechunk;
 mod streamhash;
 
 pub use alias::add_aliases_to_multiplexer;
+pub use chunk::collect_chunks;
 pub use chunk::make_chunks;
+pub use chunk::make_chunks_stream;
+pub use chunk::make_chunks_with_digest;
+pub use chunk::make_chunks_with_encryption;
+pub use chunk::make_chunks_with_ids;
+pub use chunk::ChunkCipher;
+pub use chunk::ChunkStream;
 pub use chunk::Chunks;
 pub use copy::copy;
 pub use expected_size::ExpectedSize;
@@ -472,6 +479,12 @@ pub async fn store<B: Blobstore + Clone + 'static>(
             )
             .await?
         }
+        Chunks::ChunkedWithIds(..) => {
+            unreachable!("make_chunks never returns Chunks::ChunkedWithIds")
+        }
+        Chunks::Encrypted(..) => {
+            unreachable!("make_chunks never returns Chunks::Encrypted")
+        }
     };
 
     finalize::finalize(blobstore, ctx, Some(req), prepared).await
@@ -339,6 +339,12 @@ impl GDMV2Instructions {
                         .await?,
                 )
             }
+            filestore::Chunks::ChunkedWithIds(..) => {
+                unreachable!("make_chunks never returns Chunks::ChunkedWithIds")
+            }
+            filestore::Chunks::Encrypted(..) => {
+                unreachable!("make_chunks never returns Chunks::Encrypted")
+            }
         };
 
         Ok(Self {
@@ -42,6 +42,7 @@ use mercurial_bundles::bundle2::StreamEvent;
 use mercurial_bundles::Bundle2Item;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgFileNodeId;
+use mercurial_types::HgNodeHash;
 use mercurial_types::NonRootMPath;
 use qps::Qps;
 use slog::Logger;
@@ -146,15 +147,22 @@ impl<H: HgCommands + Send + Sync + 'static> HgCommandHandler<H> {
                     .boxed(),
                 ok(instream).boxed(),
             ),
-            SingleRequest::Heads => (
+            SingleRequest::DebugGetbundle(args, diagnostics) => (
                 hgcmds
-                    .heads()
+                    .debuggetbundle(args, diagnostics)
+                    .map_ok(SingleResponse::DebugGetbundle)
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
+            SingleRequest::Heads { bookmarks } => (
+                hgcmds
+                    .heads(bookmarks)
                     .map_ok(SingleResponse::Heads)
                     .into_stream()
                     .boxed(),
                 ok(instream).boxed(),
             ),
-            SingleRequest::Hello => (
+            SingleRequest::Hello { .. } => (
                 hgcmds
                     .hello()
                     .map_ok(SingleResponse::Hello)
@@ -170,6 +178,31 @@ impl<H: HgCommands + Send + Sync + 'static> HgCommandHandler<H> {
                     .boxed(),
                 ok(instream).boxed(),
             ),
+            SingleRequest::Pushkey {
+                namespace,
+                key,
+                old,
+                new,
+            } => (
+                hgcmds
+                    .pushkey(namespace, key, old, new)
+                    .map_ok(SingleResponse::Pushkey)
+                    .into_stream()
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
+            SingleRequest::ListkeysPaged {
+                namespace,
+                offset,
+                limit,
+            } => (
+                hgcmds
+                    .listkeyspaged(namespace, offset, limit)
+                    .map_ok(SingleResponse::Listkeys)
+                    .into_stream()
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
             SingleRequest::ListKeysPatterns {
                 namespace,
                 patterns,
@@ -181,7 +214,7 @@ impl<H: HgCommands + Send + Sync + 'static> HgCommandHandler<H> {
                     .boxed(),
                 ok(instream).boxed(),
             ),
-            SingleRequest::Lookup { key } => (
+            SingleRequest::Lookup { key, kind: _ } => (
                 hgcmds
                     .lookup(key)
                     .map_ok(SingleResponse::Lookup)
@@ -218,7 +251,14 @@ impl<H: HgCommands + Send + Sync + 'static> HgCommandHandler<H> {
                     .boxed(),
                 ok(instream).boxed(),
             ),
-            SingleRequest::StreamOutShallow { tag } => (
+            SingleRequest::StreamOut { tag } => (
+                hgcmds
+                    .stream_out(tag)
+                    .map_ok(SingleResponse::StreamOut)
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
+            SingleRequest::StreamOutShallow { tag, .. } => (
                 hgcmds
                     .stream_out_shallow(tag)
                     .map_ok(SingleResponse::StreamOutShallow)
@@ -252,6 +292,27 @@ impl<H: HgCommands + Send + Sync + 'static> HgCommandHandler<H> {
                     .boxed(),
                 ok(instream).boxed(),
             ),
+            SingleRequest::Getfiles { files } => (
+                hgcmds
+                    .getfiles(files)
+                    .map_ok(SingleResponse::Getfiles)
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
+            SingleRequest::Protocaps { caps } => (
+                hgcmds
+                    .protocaps(caps)
+                    .map_ok(SingleResponse::Protocaps)
+                    .into_stream()
+                    .boxed(),
+                ok(instream).boxed(),
+            ),
+            // Only reachable if the parser was explicitly run in capture mode,
+            // which the normal request-handling path never does.
+            SingleRequest::Unknown { name, .. } => (
+                once(future::err(ErrorKind::Unimplemented(name).into())).boxed(),
+                ok(instream).boxed(),
+            ),
         }
     }
 
@@ -423,7 +484,9 @@ impl Decoder for Getpackv1ArgDecoder {
                     }
 
                     let filename_bytes = src.split_to(filelen);
-                    ParsedFilename(NonRootMPath::new(&filename_bytes)?)
+                    let filename = NonRootMPath::new(&filename_bytes)
+                        .with_context(|| "Malformed getpack/getfiles request: invalid filename")?;
+                    ParsedFilename(filename)
                 }
                 ParsedFilename(file) => {
                     let prefix_len = 4;
@@ -449,7 +512,8 @@ impl Decoder for Getpackv1ArgDecoder {
                     }
 
                     let node = src.split_to(node_size);
-                    let node = HgFileNodeId::from_bytes(&node)?;
+                    let node = HgFileNodeId::from_bytes(&node)
+                        .with_context(|| "Malformed getpack/getfiles request: invalid filenode")?;
                     file_nodes.push(node);
                     ParsingFileNodes(file, file_nodes_count, file_nodes)
                 }
@@ -562,8 +626,19 @@ pub trait HgCommands {
         once(async { Err(ErrorKind::Unimplemented("getbundle".into()).into()) }).boxed()
     }
 
-    // @wireprotocommand('heads')
-    fn heads(&self) -> HgCommandRes<HashSet<HgChangesetId>> {
+    // @wireprotocommand('debuggetbundle', '*')
+    // A diagnostic variant of `getbundle` that requests extra debug info
+    // in the response alongside (or instead of) the bundle itself.
+    fn debuggetbundle(
+        &self,
+        _args: GetbundleArgs,
+        _diagnostics: bool,
+    ) -> BoxStream<'static, Result<Bytes, Error>> {
+        once(async { Err(ErrorKind::Unimplemented("debuggetbundle".into()).into()) }).boxed()
+    }
+
+    // @wireprotocommand('heads', 'bookmarks')
+    fn heads(&self, _bookmarks: bool) -> HgCommandRes<HashSet<HgChangesetId>> {
         unimplemented("heads")
     }
 
@@ -577,6 +652,29 @@ pub trait HgCommands {
         unimplemented("listkeys")
     }
 
+    // @wireprotocommand('pushkey', 'namespace', 'key', 'old', 'new')
+    fn pushkey(
+        &self,
+        _namespace: String,
+        _key: String,
+        _old: String,
+        _new: String,
+    ) -> HgCommandRes<bool> {
+        unimplemented("pushkey")
+    }
+
+    // @wireprotocommand('listkeyspaged', 'namespace', 'offset', 'limit')
+    // A paginated variant of `listkeys`, for namespaces with key sets too
+    // large to return in a single response.
+    fn listkeyspaged(
+        &self,
+        _namespace: String,
+        _offset: usize,
+        _limit: usize,
+    ) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        unimplemented("listkeyspaged")
+    }
+
     // @wireprotocommand('listkeyspatterns', 'namespace', 'patterns *')
     fn listkeyspatterns(
         &self,
@@ -617,6 +715,11 @@ pub trait HgCommands {
         once(async { Err(ErrorKind::Unimplemented("gettreepack".into()).into()) }).boxed()
     }
 
+    // @wireprotocommand('stream_out', '*')
+    fn stream_out(&self, _tag: Option<String>) -> BoxStream<'static, Result<Bytes, Error>> {
+        once(async { Err(ErrorKind::Unimplemented("stream_out".into()).into()) }).boxed()
+    }
+
     // @wireprotocommand('stream_out_shallow', '*')
     fn stream_out_shallow(&self, _tag: Option<String>) -> BoxStream<'static, Result<Bytes, Error>> {
         once(async { Err(ErrorKind::Unimplemented("stream_out_shallow".into()).into()) }).boxed()
@@ -644,6 +747,19 @@ pub trait HgCommands {
     ) -> BoxStream<'static, Result<Bytes, Error>> {
         once(async { Err(ErrorKind::Unimplemented("getcommitdata".into()).into()) }).boxed()
     }
+
+    // @wireprotocommand('getfiles', 'files *')
+    fn getfiles(
+        &self,
+        _files: Vec<(HgNodeHash, Bytes)>,
+    ) -> BoxStream<'static, Result<Bytes, Error>> {
+        once(async { Err(ErrorKind::Unimplemented("getfiles".into()).into()) }).boxed()
+    }
+
+    // @wireprotocommand('protocaps', 'caps')
+    fn protocaps(&self, _caps: Vec<String>) -> HgCommandRes<Vec<String>> {
+        unimplemented("protocaps")
+    }
 }
 
 #[cfg(test)]
@@ -680,7 +796,10 @@ mod test {
         let logger = Logger::root(Discard, o!());
         let handler = HgCommandHandler::new(logger, Dummy, None, None);
 
-        let (r, _) = handler.handle(SingleRequest::Hello, StreamReader::new(stream::empty()));
+        let (r, _) = handler.handle(
+            SingleRequest::Hello { payload: None },
+            StreamReader::new(stream::empty()),
+        );
         let r = assert_one(r.collect::<Vec<_>>().await);
         println!("hello r = {:?}", r);
 
@@ -700,7 +819,10 @@ mod test {
         let logger = Logger::root(Discard, o!());
         let handler = HgCommandHandler::new(logger, Dummy, None, None);
 
-        let (r, _) = handler.handle(SingleRequest::Heads, StreamReader::new(stream::empty()));
+        let (r, _) = handler.handle(
+            SingleRequest::Heads { bookmarks: false },
+            StreamReader::new(stream::empty()),
+        );
         let r = assert_one(r.collect::<Vec<_>>().await);
         println!("heads r = {:?}", r);
 
@@ -738,6 +860,45 @@ mod test {
         );
     }
 
+    #[mononoke::test]
+    fn getpackv1decoder_truncated_entry_waits_for_more_data() {
+        // A filename length prefix announcing 4 bytes, but only 2 of them
+        // have arrived so far. This is not malformed -- it's merely
+        // incomplete -- so the decoder must report "need more data" (`Ok(None)`)
+        // rather than erroring out.
+        let mut decoder = Getpackv1ArgDecoder::new();
+        let mut buf = vec![];
+        buf.put_u16(4);
+        buf.put_slice(b"fi");
+        assert_eq!(
+            decoder
+                .decode(&mut BytesMut::from(buf.as_slice()))
+                .expect("truncated entry should not be treated as malformed"),
+            None
+        );
+    }
+
+    #[mononoke::test]
+    fn getpackv1decoder_malformed_filename_errors() {
+        // A filename with no path components (here just `/`) is never a
+        // valid `NonRootMPath`, so the entry is malformed and must be a clear
+        // error. (A zero-length filename can't be sent at all: a zero-length
+        // *prefix* is the stream terminator, handled separately in
+        // `GetPackv1ParsingState::Start`.)
+        let mut decoder = Getpackv1ArgDecoder::new();
+        let mut buf = vec![];
+        buf.put_u16(1);
+        buf.put_slice(b"/");
+        let err = decoder
+            .decode(&mut BytesMut::from(buf.as_slice()))
+            .expect_err("malformed filename should be a clear error");
+        assert!(
+            err.to_string().contains("Malformed getpack/getfiles request"),
+            "error did not clearly identify the malformed entry: {}",
+            err
+        );
+    }
+
     #[tokio::test]
     async fn getpackv1() {
         let input = "\u{0}\u{4}path\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}";
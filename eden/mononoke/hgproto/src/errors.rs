@@ -11,8 +11,13 @@ use thiserror::Error;
 pub enum ErrorKind {
     #[error("Unimplemented operation '{0}'")]
     Unimplemented(String),
-    #[error("command parse failed for '{0}'")]
-    CommandParse(String),
+    #[error("command parse failed for '{command}'{}", offset.map(|o| format!(" at byte offset {o}")).unwrap_or_default())]
+    CommandParse {
+        command: String,
+        /// Byte offset into `command` where parsing failed, if the
+        /// underlying nom error carried position information.
+        offset: Option<usize>,
+    },
     #[error("unconsumed data left after parsing '{0}'")]
     UnconsumedData(String),
     #[error("malformed batch with command '{0}'")]
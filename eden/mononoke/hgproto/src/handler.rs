@@ -142,12 +142,12 @@ where
 {
     req.record_request(&handler.wireproto_calls);
     match req {
-        Request::Batch(reqs) => {
+        Request::Batch { cmds, .. } => {
             let (sender, receiver) = oneshot::channel();
             (
                 try_stream! {
                     let mut all_resps = Vec::new();
-                    for req in reqs {
+                    for req in cmds {
                         let (mut resps, remainder) = handler.commands_handler.handle(req, input);
                         while let Some(resp) = resps.try_next().await? {
                             all_resps.push(resp)
@@ -22,6 +22,7 @@ use std::sync::Mutex;
 use bytes::Bytes;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgManifestId;
+use mercurial_types::HgNodeHash;
 
 pub mod batch;
 mod commands;
@@ -34,7 +35,13 @@ const MAX_NODES_TO_LOG: usize = 5;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Request {
-    Batch(Vec<SingleRequest>),
+    Batch {
+        cmds: Vec<SingleRequest>,
+        /// Set if the batch contained the reserved `abort` control command.
+        /// Any commands after it in the batch are dropped instead of being
+        /// parsed and executed, so `cmds` only contains the ones before it.
+        aborted: bool,
+    },
     Single(SingleRequest),
 }
 
@@ -42,7 +49,9 @@ impl Request {
     pub fn record_request(&self, record: &Mutex<Vec<String>>) {
         let mut record = record.lock().expect("lock poisoned");
         match *self {
-            Request::Batch(ref batch) => record.extend(batch.iter().map(|s| s.name().into())),
+            Request::Batch { ref cmds, .. } => {
+                record.extend(cmds.iter().map(|s| s.name().into()))
+            }
             Request::Single(ref req) => record.push(req.name().into()),
         }
     }
@@ -64,17 +73,41 @@ pub enum SingleRequest {
         all_args: HashMap<Vec<u8>, Vec<u8>>,
     },
     Getbundle(GetbundleArgs),
-    Heads,
-    Hello,
+    /// A diagnostic variant of `getbundle` that reuses the same argument
+    /// parsing, plus a `diagnostics` flag requesting extra debug info in the
+    /// response. Used by clients/tools that want to inspect what a
+    /// `getbundle` would produce without changing its actual arguments.
+    DebugGetbundle(GetbundleArgs, bool),
+    Heads {
+        // Whether to also include bookmark heads in the response.
+        bookmarks: bool,
+    },
+    Hello {
+        // The raw protocol line the client advertised alongside `hello`, if
+        // any, so servers can record what the client claims to support.
+        payload: Option<String>,
+    },
     Listkeys {
         namespace: String,
     },
+    Pushkey {
+        namespace: String,
+        key: String,
+        old: String,
+        new: String,
+    },
+    ListkeysPaged {
+        namespace: String,
+        offset: usize,
+        limit: usize,
+    },
     ListKeysPatterns {
         namespace: String,
         patterns: Vec<String>,
     },
     Lookup {
         key: String,
+        kind: LookupKind,
     },
     Known {
         nodes: Vec<HgChangesetId>,
@@ -91,14 +124,37 @@ pub enum SingleRequest {
         respondlightly: bool,
     },
     Gettreepack(GettreepackArgs),
+    StreamOut {
+        tag: Option<String>,
+    },
     StreamOutShallow {
         tag: Option<String>,
+        // Whether the client asked for the "flat" manifest format to be
+        // skipped in favor of the tree manifest format only.
+        noflatmanifest: bool,
     },
     GetpackV1,
     GetpackV2,
     GetCommitData {
         nodes: Vec<HgChangesetId>,
     },
+    Getfiles {
+        files: Vec<(HgNodeHash, Bytes)>,
+    },
+    /// The SSH2 protocol's capability-negotiation command: the client sends
+    /// the capabilities it supports up front, rather than waiting for the
+    /// server to advertise its own via `hello`/`capabilities`.
+    Protocaps {
+        caps: Vec<String>,
+    },
+    /// An unrecognized command, captured generically instead of being rejected
+    /// outright. Only produced when the parser is explicitly run in capture
+    /// mode (see `sshproto::request::parse_with_params`); the normal parsing
+    /// path never returns this variant.
+    Unknown {
+        name: String,
+        args: HashMap<Vec<u8>, Vec<u8>>,
+    },
 }
 
 impl SingleRequest {
@@ -110,38 +166,134 @@ impl SingleRequest {
             SingleRequest::ClientTelemetry { .. } => "clienttelemetry",
             SingleRequest::Debugwireargs { .. } => "debugwireargs",
             SingleRequest::Getbundle(_) => "getbundle",
-            SingleRequest::Heads => "heads",
-            SingleRequest::Hello => "hello",
+            SingleRequest::DebugGetbundle(..) => "debuggetbundle",
+            SingleRequest::Heads { .. } => "heads",
+            SingleRequest::Hello { .. } => "hello",
             SingleRequest::Listkeys { .. } => "listkeys",
+            SingleRequest::Pushkey { .. } => "pushkey",
+            SingleRequest::ListkeysPaged { .. } => "listkeyspaged",
             SingleRequest::Lookup { .. } => "lookup",
             SingleRequest::Known { .. } => "known",
             SingleRequest::Knownnodes { .. } => "knownnodes",
             SingleRequest::Unbundle { .. } => "unbundle",
             SingleRequest::UnbundleReplay { .. } => "unbundlereplay",
             SingleRequest::Gettreepack(_) => "gettreepack",
+            SingleRequest::StreamOut { .. } => "stream_out",
             SingleRequest::StreamOutShallow { .. } => "stream_out_shallow",
             SingleRequest::GetpackV1 => "getpackv1",
             SingleRequest::GetpackV2 => "getpackv2",
             SingleRequest::ListKeysPatterns { .. } => "listkeyspatterns",
             SingleRequest::GetCommitData { .. } => "getcommitdata",
+            SingleRequest::Getfiles { .. } => "getfiles",
+            SingleRequest::Protocaps { .. } => "protocaps",
+            SingleRequest::Unknown { .. } => "unknown",
+        }
+    }
+
+    /// A hint for whether this request's response is expected to be
+    /// streamed (e.g. `getbundle`, `gettreepack`, `stream_out`) or small
+    /// enough to buffer (e.g. `hello`, `heads`), for pipelining and
+    /// buffering decisions that need to be made before the response itself
+    /// is available. Matched exhaustively, with no wildcard arm, so adding
+    /// a new `SingleRequest` variant forces an explicit choice here.
+    pub fn expected_response_streaming(&self) -> ResponseSizeHint {
+        use ResponseSizeHint::*;
+
+        match *self {
+            SingleRequest::Between { .. } => Small,
+            SingleRequest::Branchmap => Small,
+            SingleRequest::Capabilities => Small,
+            SingleRequest::ClientTelemetry { .. } => Small,
+            SingleRequest::Debugwireargs { .. } => Small,
+            SingleRequest::Getbundle(_) => Streaming,
+            SingleRequest::DebugGetbundle(..) => Streaming,
+            SingleRequest::Heads { .. } => Small,
+            SingleRequest::Hello { .. } => Small,
+            SingleRequest::Listkeys { .. } => Small,
+            SingleRequest::Pushkey { .. } => Small,
+            SingleRequest::ListkeysPaged { .. } => Small,
+            SingleRequest::ListKeysPatterns { .. } => Small,
+            SingleRequest::Lookup { .. } => Small,
+            SingleRequest::Known { .. } => Small,
+            SingleRequest::Knownnodes { .. } => Small,
+            SingleRequest::Unbundle { .. } => Streaming,
+            SingleRequest::UnbundleReplay { .. } => Streaming,
+            SingleRequest::Gettreepack(_) => Streaming,
+            SingleRequest::StreamOut { .. } => Streaming,
+            SingleRequest::StreamOutShallow { .. } => Streaming,
+            SingleRequest::GetpackV1 => Streaming,
+            SingleRequest::GetpackV2 => Streaming,
+            SingleRequest::GetCommitData { .. } => Small,
+            SingleRequest::Getfiles { .. } => Small,
+            SingleRequest::Protocaps { .. } => Small,
+            SingleRequest::Unknown { .. } => Small,
         }
     }
 }
 
+/// The classification returned by `SingleRequest::expected_response_streaming`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseSizeHint {
+    /// The response is expected to be large, or its size isn't known ahead
+    /// of time, so it should be streamed back to the client rather than
+    /// buffered in full.
+    Streaming,
+    /// The response is expected to be small, and can be buffered without
+    /// much concern for memory or latency impact.
+    Small,
+}
+
+/// The classification a `lookup` command's key is parsed into, based on
+/// whether it looks like a plain key or a revset expression.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LookupKind {
+    /// A literal key: a bookmark name, tag, or hex node id, to be looked up
+    /// directly.
+    Key,
+    /// A revset expression (e.g. containing `::`, `%`, or a function call)
+    /// that should be routed to a revset evaluator instead of a direct
+    /// lookup.
+    RevsetExpression,
+}
+
 /// The arguments that `getbundle` accepts, in a separate struct for
 /// the convenience of callers.
-#[derive(Eq, PartialEq)]
+#[derive(Default, Eq, PartialEq)]
 pub struct GetbundleArgs {
-    /// List of space-delimited hex nodes of heads to retrieve
-    pub heads: Vec<HgChangesetId>,
-    /// List of space-delimited hex nodes that the client has in common with the server
-    pub common: Vec<HgChangesetId>,
+    /// List of space-delimited hex nodes of heads to retrieve. `None` if
+    /// the client omitted the `heads` param entirely, as distinct from an
+    /// explicitly-sent empty list.
+    pub heads: Option<Vec<HgChangesetId>>,
+    /// List of space-delimited hex nodes that the client has in common with
+    /// the server. `None` if the client omitted the `common` param
+    /// entirely, as distinct from an explicitly-sent empty list.
+    pub common: Option<Vec<HgChangesetId>>,
     /// Comma-delimited set of strings defining client bundle capabilities.
     pub bundlecaps: HashSet<Vec<u8>>,
+    /// Bundle format version requested by the client, derived from
+    /// `bundlecaps` so callers don't have to re-parse the caps map
+    /// themselves. e.g. `"02"` for a client advertising `HG20` (bundle2),
+    /// or `"01"` for one advertising a classic `HG10*` changegroup
+    /// capability. `None` if `bundlecaps` didn't contain such a capability.
+    pub bundle_version: Option<String>,
     /// Comma-delimited list of strings of ``pushkey`` namespaces. For each namespace listed, a bundle2 part will be included with the content of that namespace.
     pub listkeys: Vec<Vec<u8>>,
     /// phases: Boolean indicating whether phases data is requested
     pub phases: bool,
+    /// Structured phase-heads data, for protocol versions where `phases`
+    /// carries a list of changeset heads rather than a plain boolean. `None`
+    /// if `phases` was absent or sent in its usual boolean form.
+    pub phase_heads: Option<Vec<HgChangesetId>>,
+    /// cbattempted: Boolean indicating whether the client already attempted
+    /// (and failed) to apply a changegroup for this pull, and is retrying.
+    pub cbattempted: bool,
+    /// obsmarkers: Boolean indicating whether obsolescence markers are
+    /// requested. Defaults to `false` when absent.
+    pub obsmarkers: bool,
+    /// cg: Boolean indicating whether a changegroup part is requested.
+    /// Defaults to `true` when absent, matching the historical behavior of
+    /// always including a changegroup before this param existed.
+    pub cg: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -156,16 +308,34 @@ impl Debug for GetbundleArgs {
             .iter()
             .map(|s| String::from_utf8_lossy(s))
             .collect();
-        let heads: Vec<_> = self.heads.iter().take(MAX_NODES_TO_LOG).collect();
-        let common: Vec<_> = self.common.iter().take(MAX_NODES_TO_LOG).collect();
+        let heads: Vec<_> = self
+            .heads
+            .iter()
+            .flatten()
+            .take(MAX_NODES_TO_LOG)
+            .collect();
+        let common: Vec<_> = self
+            .common
+            .iter()
+            .flatten()
+            .take(MAX_NODES_TO_LOG)
+            .collect();
         fmt.debug_struct("GetbundleArgs")
-            .field("heads_len", &self.heads.len())
+            .field("heads_len", &self.heads.as_ref().map(Vec::len))
             .field("heads", &heads)
-            .field("common_len", &self.common.len())
+            .field("common_len", &self.common.as_ref().map(Vec::len))
             .field("common", &common)
             .field("bundlecaps", &bcaps)
+            .field("bundle_version", &self.bundle_version)
             .field("listkeys", &listkeys)
             .field("phases", &self.phases)
+            .field(
+                "phase_heads_len",
+                &self.phase_heads.as_ref().map(Vec::len),
+            )
+            .field("cbattempted", &self.cbattempted)
+            .field("obsmarkers", &self.obsmarkers)
+            .field("cg", &self.cg)
             .finish()
     }
 }
@@ -187,6 +357,73 @@ pub struct GettreepackArgs {
     pub depth: Option<usize>,
 }
 
+impl Default for GettreepackArgs {
+    /// `MPath` has no `Default` impl of its own, so this can't be derived;
+    /// `rootdir` defaults to `MPath::ROOT`, i.e. the repo root.
+    fn default() -> Self {
+        Self {
+            rootdir: MPath::ROOT,
+            mfnodes: Vec::new(),
+            basemfnodes: BTreeSet::new(),
+            directories: Vec::new(),
+            depth: None,
+        }
+    }
+}
+
+/// Builds the server's advertised capability set for the `hello`/
+/// `capabilities` commands: the bare command names the server supports,
+/// plus the `bundlecaps` and `unbundle` (bundle format) capability entries,
+/// which each carry a comma-separated list of values on the wire.
+///
+/// `to_wire_string` produces the same space-separated format that
+/// `sshproto::request::parse_client_caps` parses, so a capability set built
+/// here round-trips through the wire format.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Capabilities {
+    commands: Vec<String>,
+    bundlecaps: Vec<String>,
+    formats: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertise a bare command capability (e.g. `"lookup"`, `"known"`).
+    pub fn with_command(mut self, command: impl Into<String>) -> Self {
+        self.commands.push(command.into());
+        self
+    }
+
+    /// Advertise a `bundlecaps` sub-capability.
+    pub fn with_bundlecap(mut self, bundlecap: impl Into<String>) -> Self {
+        self.bundlecaps.push(bundlecap.into());
+        self
+    }
+
+    /// Advertise a supported bundle format (e.g. `"HG10GZ"`), sent as part
+    /// of the `unbundle` capability.
+    pub fn with_format(mut self, format: impl Into<String>) -> Self {
+        self.formats.push(format.into());
+        self
+    }
+
+    /// Serialize this capability set to the wire format used by the
+    /// `hello`/`capabilities` commands.
+    pub fn to_wire_string(&self) -> String {
+        let mut entries: Vec<String> = self.commands.clone();
+        if !self.bundlecaps.is_empty() {
+            entries.push(format!("bundlecaps={}", self.bundlecaps.join(",")));
+        }
+        if !self.formats.is_empty() {
+            entries.push(format!("unbundle={}", self.formats.join(",")));
+        }
+        entries.join(" ")
+    }
+}
+
 #[derive(Debug)]
 pub enum Response {
     Batch(Vec<SingleResponse>),
@@ -201,9 +438,11 @@ pub enum SingleResponse {
     ClientTelemetry(String),
     Debugwireargs(Bytes),
     Getbundle(Bytes),
+    DebugGetbundle(Bytes),
     Heads(HashSet<HgChangesetId>),
     Hello(HashMap<String, Vec<String>>),
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
+    Pushkey(bool),
     ListKeysPatterns(BTreeMap<String, HgChangesetId>),
     Lookup(Bytes),
     Known(Vec<bool>),
@@ -211,10 +450,13 @@ pub enum SingleResponse {
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
+    StreamOut(Bytes),
     StreamOutShallow(Bytes),
     Getpackv1(Bytes),
     Getpackv2(Bytes),
     GetCommitData(Bytes),
+    Getfiles(Bytes),
+    Protocaps(Vec<String>),
 }
 
 impl SingleResponse {
@@ -223,8 +465,8 @@ impl SingleResponse {
         use SingleResponse::*;
 
         match self {
-            &Getbundle(_) | &ReadyForStream | &Unbundle(_) | &Gettreepack(_)
-            | &StreamOutShallow(_) | &Getpackv1(_) | &Getpackv2(_) => true,
+            &Getbundle(_) | &DebugGetbundle(_) | &ReadyForStream | &Unbundle(_) | &Gettreepack(_)
+            | &StreamOut(_) | &StreamOutShallow(_) | &Getpackv1(_) | &Getpackv2(_) => true,
             _ => false,
         }
     }
@@ -235,3 +477,249 @@ pub use commands::HgCommands;
 pub use errors::ErrorKind;
 pub use handler::HgProtoHandler;
 use mononoke_types::path::MPath;
+
+#[cfg(test)]
+mod test {
+    use maplit::hashmap;
+    use mononoke_macros::mononoke;
+
+    use super::*;
+
+    fn hash_ones() -> HgChangesetId {
+        HgChangesetId::new("1111111111111111111111111111111111111111".parse().unwrap())
+    }
+
+    fn manifest_ones() -> HgManifestId {
+        HgManifestId::new("1111111111111111111111111111111111111111".parse().unwrap())
+    }
+
+    /// Every `SingleRequest` variant, paired with its expected
+    /// `expected_response_streaming()` classification. The compiler does not
+    /// check that this list is complete (only the match in
+    /// `expected_response_streaming` is exhaustive), so a new variant has to
+    /// be added here by hand.
+    fn all_variants_with_expected_hint() -> Vec<(SingleRequest, ResponseSizeHint)> {
+        use ResponseSizeHint::*;
+
+        vec![
+            (SingleRequest::Between { pairs: vec![] }, Small),
+            (SingleRequest::Branchmap, Small),
+            (SingleRequest::Capabilities, Small),
+            (
+                SingleRequest::ClientTelemetry {
+                    args: HashMap::new(),
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Debugwireargs {
+                    one: vec![],
+                    two: vec![],
+                    all_args: HashMap::new(),
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Getbundle(GetbundleArgs {
+                    heads: None,
+                    common: None,
+                    bundlecaps: HashSet::new(),
+                    bundle_version: None,
+                    listkeys: vec![],
+                    phases: false,
+                    phase_heads: None,
+                    cbattempted: false,
+                    obsmarkers: false,
+                    cg: true,
+                }),
+                Streaming,
+            ),
+            (
+                SingleRequest::DebugGetbundle(GetbundleArgs::default(), false),
+                Streaming,
+            ),
+            (SingleRequest::Heads { bookmarks: false }, Small),
+            (SingleRequest::Hello { payload: None }, Small),
+            (
+                SingleRequest::Listkeys {
+                    namespace: "bookmarks".to_string(),
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Pushkey {
+                    namespace: "bookmarks".to_string(),
+                    key: "master".to_string(),
+                    old: "".to_string(),
+                    new: "".to_string(),
+                },
+                Small,
+            ),
+            (
+                SingleRequest::ListkeysPaged {
+                    namespace: "bookmarks".to_string(),
+                    offset: 0,
+                    limit: 100,
+                },
+                Small,
+            ),
+            (
+                SingleRequest::ListKeysPatterns {
+                    namespace: "bookmarks".to_string(),
+                    patterns: vec![],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Lookup {
+                    key: "master".to_string(),
+                    kind: LookupKind::Key,
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Known {
+                    nodes: vec![hash_ones()],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Knownnodes {
+                    nodes: vec![hash_ones()],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Unbundle { heads: vec![] },
+                Streaming,
+            ),
+            (
+                SingleRequest::UnbundleReplay {
+                    heads: vec![],
+                    replaydata: "".to_string(),
+                    respondlightly: false,
+                },
+                Streaming,
+            ),
+            (
+                SingleRequest::Gettreepack(GettreepackArgs {
+                    rootdir: MPath::ROOT,
+                    mfnodes: vec![manifest_ones()],
+                    basemfnodes: BTreeSet::new(),
+                    directories: vec![],
+                    depth: None,
+                }),
+                Streaming,
+            ),
+            (SingleRequest::StreamOut { tag: None }, Streaming),
+            (
+                SingleRequest::StreamOutShallow {
+                    tag: None,
+                    noflatmanifest: false,
+                },
+                Streaming,
+            ),
+            (SingleRequest::GetpackV1, Streaming),
+            (SingleRequest::GetpackV2, Streaming),
+            (
+                SingleRequest::GetCommitData {
+                    nodes: vec![hash_ones()],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Getfiles {
+                    files: vec![(hash_ones().into_nodehash(), Bytes::from_static(b"path"))],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Protocaps {
+                    caps: vec!["partre".to_string(), "commondata".to_string()],
+                },
+                Small,
+            ),
+            (
+                SingleRequest::Unknown {
+                    name: "madeup".to_string(),
+                    args: HashMap::new(),
+                },
+                Small,
+            ),
+        ]
+    }
+
+    #[mononoke::test]
+    fn test_expected_response_streaming_covers_every_variant() {
+        for (req, expected) in all_variants_with_expected_hint() {
+            assert_eq!(
+                req.expected_response_streaming(),
+                expected,
+                "wrong streaming hint for {}",
+                req.name(),
+            );
+        }
+    }
+
+    #[mononoke::test]
+    fn test_getbundle_args_default() {
+        let args = GetbundleArgs {
+            heads: Some(vec![hash_ones()]),
+            ..Default::default()
+        };
+        assert_eq!(args.heads, Some(vec![hash_ones()]));
+        assert_eq!(args.common, None);
+        assert_eq!(args.bundlecaps, HashSet::new());
+        assert_eq!(args.bundle_version, None);
+        assert_eq!(args.listkeys, Vec::<Vec<u8>>::new());
+        assert!(!args.phases);
+        assert!(!args.cbattempted);
+    }
+
+    #[mononoke::test]
+    fn test_gettreepack_args_default() {
+        let args = GettreepackArgs {
+            mfnodes: vec![manifest_ones()],
+            ..Default::default()
+        };
+        assert_eq!(args.rootdir, MPath::ROOT);
+        assert_eq!(args.mfnodes, vec![manifest_ones()]);
+        assert_eq!(args.basemfnodes, BTreeSet::new());
+        assert_eq!(args.directories, Vec::<Bytes>::new());
+        assert_eq!(args.depth, None);
+    }
+
+    #[mononoke::test]
+    fn test_capabilities_wire_round_trip() {
+        let caps = Capabilities::new()
+            .with_command("lookup")
+            .with_command("known")
+            .with_command("getbundle")
+            .with_bundlecap("HG20")
+            .with_format("HG10GZ")
+            .with_format("HG10BZ")
+            .with_format("HG10UN");
+
+        let wire = caps.to_wire_string();
+        assert_eq!(
+            wire,
+            "lookup known getbundle bundlecaps=HG20 unbundle=HG10GZ,HG10BZ,HG10UN"
+        );
+
+        let parsed = sshproto::request::parse_client_caps(wire.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            hashmap! {
+                "lookup".to_string() => vec![],
+                "known".to_string() => vec![],
+                "getbundle".to_string() => vec![],
+                "bundlecaps".to_string() => vec!["HG20".to_string()],
+                "unbundle".to_string() => vec![
+                    "HG10GZ".to_string(),
+                    "HG10BZ".to_string(),
+                    "HG10UN".to_string(),
+                ],
+            }
+        );
+    }
+}
@@ -5,11 +5,15 @@
  * GNU General Public License version 2.
  */
 
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::iter;
 use std::str;
 use std::str::FromStr;
 
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Error;
 use anyhow::Result;
@@ -18,6 +22,7 @@ use bytes::BytesMut;
 use hex::FromHex;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgManifestId;
+use mercurial_types::HgNodeHash;
 use mononoke_types::path::MPath;
 use nom::alt;
 use nom::apply;
@@ -29,11 +34,11 @@ use nom::eof;
 use nom::error_position;
 use nom::is_alphanumeric;
 use nom::is_digit;
-use nom::many0;
 use nom::map;
 use nom::map_res;
 use nom::named;
 use nom::named_args;
+use nom::opt;
 use nom::separated_list;
 use nom::separated_list_complete;
 use nom::tag;
@@ -48,16 +53,49 @@ use nom::FindSubstring;
 use nom::IResult;
 use nom::Needed;
 use nom::Slice;
+use percent_encoding::percent_decode;
+use tokio_util::codec::Decoder;
 
 use crate::batch;
 use crate::errors;
 use crate::GetbundleArgs;
 use crate::GettreepackArgs;
+use crate::LookupKind;
 use crate::Request;
 use crate::SingleRequest;
 
 const BAD_UTF8_ERR_CODE: u32 = 111;
 const BAD_PATH_ERR_CODE: u32 = 222;
+const DUPLICATE_PARAM_KEY_ERR_CODE: u32 = 333;
+// A decimal integer (e.g. a param's declared length) parsed too large to fit
+// in a `usize`.
+const INTEGER_OVERFLOW_ERR_CODE: u32 = 888;
+// A `nodehash`-shaped field contained a byte outside `[0-9a-fA-F]`.
+const BAD_HEX_DIGIT_ERR_CODE: u32 = 999;
+// A `"* N\n"` star-parameter group nested deeper than `MAX_PARAM_STAR_DEPTH`.
+const PARAM_STAR_TOO_DEEP_ERR_CODE: u32 = 1110;
+
+// The maximum nesting depth of recursive `"* N\n"` star-parameter groups.
+// `param_star` calls back into `params_ref`, which can invoke `param_star`
+// again for a nested group, so a crafted request built entirely of nested
+// star groups could otherwise recurse deeply enough to overflow the stack.
+// No legitimate command nests star groups more than one level deep, so this
+// is chosen generously above that.
+const MAX_PARAM_STAR_DEPTH: usize = 10;
+
+thread_local! {
+    /// `parse_command`'s nom combinators can only report failure as an
+    /// `ErrorKind::Custom(u32)` code, which loses whatever message `func`
+    /// actually failed with (e.g. "missing param two"). Stash it here so
+    /// `parse_request` can fold it into the final error it returns, instead
+    /// of just the raw, unparsed input buffer.
+    static LAST_COMMAND_PARSE_ERROR: RefCell<Option<String>> = RefCell::new(None);
+
+    /// Current recursion depth of nested `param_star` calls, incremented on
+    /// entry and decremented on exit so it tracks the live call stack rather
+    /// than a running total. Bounded by `MAX_PARAM_STAR_DEPTH`.
+    static PARAM_STAR_DEPTH: RefCell<usize> = RefCell::new(0);
+}
 
 /// Parse an unsigned decimal integer. If it reaches the end of input, it returns Incomplete,
 /// as there may be more digits following
@@ -74,14 +112,201 @@ fn digit<F: Fn(u8) -> bool>(input: &[u8], isdigit: F) -> IResult<&[u8], &[u8]> {
     IResult::Incomplete(Needed::Unknown)
 }
 
+/// Parse an unsigned decimal integer, like `map_res!(digit, FromStr::from_str)`
+/// would, but distinguishing a value too large for `usize` (`ErrorKind::Custom(
+/// INTEGER_OVERFLOW_ERR_CODE)`) from a plain parse failure, so callers (and
+/// error messages) can tell "not a number" apart from "too big a number".
+fn integer(input: &[u8]) -> IResult<&[u8], usize> {
+    let (rest, digits) = match digit(input, is_digit) {
+        IResult::Done(rest, digits) => (rest, digits),
+        IResult::Error(e) => return IResult::Error(e),
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+    };
+
+    match str::from_utf8(digits).ok().and_then(|s| usize::from_str(s).ok()) {
+        Some(val) => IResult::Done(rest, val),
+        None => IResult::Error(Err::Code(ErrorKind::Custom(INTEGER_OVERFLOW_ERR_CODE))),
+    }
+}
+
+// Like `integer`, but assumes input is complete, so reaching the end of
+// input means the number is the entire input (rather than returning
+// Incomplete). Suitable for parsing a param whose whole value is a decimal
+// number, e.g. via `parseval`.
 named!(
-    integer<usize>,
+    integer_complete<usize>,
     map_res!(
-        map_res!(apply!(digit, is_digit), str::from_utf8),
+        map_res!(take_while1!(is_digit), str::from_utf8),
         FromStr::from_str
     )
 );
 
+// The maximum number of keys a single `listkeyspaged` response may return.
+const MAX_LISTKEYS_PAGE_LIMIT: usize = 10_000;
+
+// The maximum number of pairs a single `between` request's `pairs` param may
+// carry, to bound how much work a single request can ask the server to do.
+const MAX_BETWEEN_PAIRS: usize = 10_000;
+
+// Shared defaults for `bounded_separated_list`/`bounded_many0` below, used by
+// list parsers that don't already have a narrower, purpose-specific cap
+// (e.g. `MAX_BETWEEN_PAIRS`). Chosen to be generous enough that no
+// legitimate client request comes close, while still bounding the amount of
+// memory and time a single param can force the server to spend parsing a
+// pathologically large or numerous list.
+const MAX_LIST_ELEMENT_LEN: usize = 4096;
+const MAX_LIST_COUNT: usize = 100_000;
+
+const LIST_ELEMENT_TOO_LONG_ERR_CODE: u32 = 444;
+const LIST_TOO_LONG_ERR_CODE: u32 = 555;
+
+// The maximum number of params a single `params_ref` call (including the
+// params a nested `* <count>` group expands to) will accept, so that a
+// malicious `* <huge count>` can't force an equally huge `HashMap`
+// allocation before parsing has even had a chance to fail on the params
+// themselves.
+const MAX_PARAM_COUNT: usize = 100_000;
+const TOO_MANY_PARAMS_ERR_CODE: u32 = 666;
+
+// The maximum number of bytes of the original request to include verbatim in
+// a `CommandParse` error message, so that a huge malformed request doesn't
+// force an allocation as large as the request itself just to report failure.
+const MAX_COMMAND_PARSE_ERROR_BYTES: usize = 1024;
+
+/// Outcome of trying to parse one capped element out of `bounded_separated_list`
+/// or `bounded_many0`'s input.
+enum BoundedElement<'a, T> {
+    /// The element parsed cleanly, within the cap.
+    Parsed { rest: &'a [u8], val: T },
+    /// The element's parser didn't find its own end strictly before the
+    /// cap, so it can't be told apart from one that's actually longer than
+    /// `max_element_len`.
+    TooLong,
+    /// The element's parser failed (or returned incomplete) on input that
+    /// wasn't truncated by the cap, i.e. a genuine parse failure.
+    NoMatch,
+}
+
+/// Apply `elem` to `rest`, but feed it no more than one byte past
+/// `max_element_len`: just enough to tell an element that ends exactly at
+/// the cap apart from one that keeps going past it, without letting a
+/// pathologically long single element make `elem` scan unbounded input.
+fn bounded_element<'a, F, T>(
+    rest: &'a [u8],
+    max_element_len: usize,
+    elem: F,
+) -> BoundedElement<'a, T>
+where
+    F: Fn(&'a [u8]) -> IResult<&'a [u8], T>,
+{
+    let peek_len = cmp::min(rest.len(), max_element_len.saturating_add(1));
+    let truncated = rest.len() > peek_len;
+    match elem(&rest[..peek_len]) {
+        IResult::Done(tail, val) => {
+            let consumed = peek_len - tail.len();
+            if consumed > max_element_len {
+                BoundedElement::TooLong
+            } else {
+                BoundedElement::Parsed {
+                    rest: &rest[consumed..],
+                    val,
+                }
+            }
+        }
+        IResult::Incomplete(_) if truncated => BoundedElement::TooLong,
+        _ => BoundedElement::NoMatch,
+    }
+}
+
+/// Like `separated_list_complete!`, but bounded to resist abuse: each
+/// element's raw (pre-parse) input is capped at `max_element_len` bytes, and
+/// the list is capped at `max_count` elements. Exceeding either cap fails
+/// the parse outright, rather than silently truncating the list, so callers
+/// never see a shorter list than what the client actually sent.
+fn bounded_separated_list<'a, F, T>(
+    inp: &'a [u8],
+    sep: &'static [u8],
+    max_element_len: usize,
+    max_count: usize,
+    elem: F,
+) -> IResult<&'a [u8], Vec<T>>
+where
+    F: Fn(&'a [u8]) -> IResult<&'a [u8], T>,
+{
+    let mut out = Vec::new();
+    let mut rest = inp;
+
+    loop {
+        if out.len() >= max_count {
+            if let BoundedElement::Parsed { .. } | BoundedElement::TooLong =
+                bounded_element(rest, max_element_len, &elem)
+            {
+                return IResult::Error(Err::Code(ErrorKind::Custom(LIST_TOO_LONG_ERR_CODE)));
+            }
+            break;
+        }
+
+        match bounded_element(rest, max_element_len, &elem) {
+            BoundedElement::Parsed { rest: tail, val } => {
+                out.push(val);
+                rest = tail;
+            }
+            BoundedElement::TooLong => {
+                return IResult::Error(Err::Code(ErrorKind::Custom(
+                    LIST_ELEMENT_TOO_LONG_ERR_CODE,
+                )));
+            }
+            BoundedElement::NoMatch => break,
+        }
+
+        match tag!(rest, sep) {
+            IResult::Done(tail, _) => rest = tail,
+            _ => break,
+        }
+    }
+
+    IResult::Done(rest, out)
+}
+
+/// Like `many0!`, but bounded the same way as `bounded_separated_list`: for
+/// elements (like `batch_param_path`) that consume their own delimiter
+/// rather than relying on a separate separator parser.
+fn bounded_many0<'a, F, T>(
+    inp: &'a [u8],
+    max_element_len: usize,
+    max_count: usize,
+    elem: F,
+) -> IResult<&'a [u8], Vec<T>>
+where
+    F: Fn(&'a [u8]) -> IResult<&'a [u8], T>,
+{
+    let mut out = Vec::new();
+    let mut rest = inp;
+
+    while out.len() < max_count {
+        match bounded_element(rest, max_element_len, &elem) {
+            BoundedElement::Parsed { rest: tail, val } => {
+                out.push(val);
+                rest = tail;
+            }
+            BoundedElement::TooLong => {
+                return IResult::Error(Err::Code(ErrorKind::Custom(
+                    LIST_ELEMENT_TOO_LONG_ERR_CODE,
+                )));
+            }
+            BoundedElement::NoMatch => return IResult::Done(rest, out),
+        }
+    }
+
+    if let BoundedElement::Parsed { .. } | BoundedElement::TooLong =
+        bounded_element(rest, max_element_len, &elem)
+    {
+        return IResult::Error(Err::Code(ErrorKind::Custom(LIST_TOO_LONG_ERR_CODE)));
+    }
+
+    IResult::Done(rest, out)
+}
+
 /// Return an identifier of the form [a-zA-Z_][a-zA-Z0-9_]*. Returns Incomplete
 /// if it manages to reach the end of input, as there may be more identifier coming.
 fn ident(input: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -120,6 +345,17 @@ named!(
     })
 );
 
+/// Parse a Python-style `"True"`/`"False"` boolean, as sent for
+/// `stream_out_shallow`'s `noflatmanifest` param. Assumption: input is
+/// complete.
+named!(
+    python_bool<bool>,
+    alt!(
+        tag!("True") => { |_| true }
+      | tag!("False") => { |_| false }
+    )
+);
+
 named!(
     batch_param_comma_separated<Bytes>,
     map_res!(
@@ -128,58 +364,185 @@ named!(
     )
 );
 
-// List of comma-separated values, each of which is encoded using batch param encoding.
+/// Validate and normalize a path-bearing param value: reject traversal
+/// sequences (`..`), collapse repeated separators (`//`), and require valid
+/// UTF-8. Used for path-like params such as `gettreepack` directories and
+/// narrow specs, where a malicious or buggy client could otherwise smuggle a
+/// path that escapes the intended root.
+fn path_value(input: &[u8]) -> Result<Bytes> {
+    let s = str::from_utf8(input)?;
+
+    let mut normalized = String::with_capacity(s.len());
+    for component in s.split('/') {
+        if component.is_empty() {
+            // Collapse "//" (and leading/trailing "/") rather than erroring.
+            continue;
+        }
+        if component == ".." {
+            bail!("path param contains a traversal sequence: {:?}", s);
+        }
+        if !normalized.is_empty() {
+            normalized.push('/');
+        }
+        normalized.push_str(component);
+    }
+
+    Ok(Bytes::from(normalized))
+}
+
 named!(
-    gettreepack_directories<Vec<Bytes>>,
-    complete!(many0!(batch_param_comma_separated))
+    batch_param_path<Bytes>,
+    map_res!(
+        do_parse!(key: take_while!(notcomma) >> take!(1) >> (key)),
+        |k: &[u8]| -> Result<Bytes> { path_value(&batch::unescape(k)?) }
+    )
 );
 
+// List of comma-separated values, each of which is encoded using batch param encoding.
+// Each directory is additionally validated/normalized as a path.
+fn gettreepack_directories(inp: &[u8]) -> IResult<&[u8], Vec<Bytes>> {
+    bounded_many0(inp, MAX_LIST_ELEMENT_LEN, MAX_LIST_COUNT, batch_param_path)
+}
+
 // A "*" parameter is a meta-parameter - its argument is a count of
 // a number of other parameters. (We accept nested/recursive star parameters,
-// but I don't know if that ever happens in practice.)
+// bounded by `MAX_PARAM_STAR_DEPTH` below.)
 named!(
-    param_star<HashMap<&[u8], &[u8]>>,
+    param_star_body<HashMap<&[u8], &[u8]>>,
     do_parse!(
-        tag!(b"* ") >> count: integer >> tag!(b"\n") >> res: apply!(params_ref, count) >> (res)
+        // Nested "* N" groups are always parsed leniently: duplicate-key
+        // strictness is only enforced by the `params_ref` call that directly
+        // unpacks this group's keys into its own result (see below).
+        tag!(b"* ") >> count: integer >> tag!(b"\n") >> res: apply!(params_ref, count, false) >> (res)
     )
 );
 
+// Wraps `param_star_body` with a depth counter, since it recurses back into
+// `params_ref` (and so potentially back into itself) for nested groups.
+fn param_star(inp: &[u8]) -> IResult<&[u8], HashMap<&[u8], &[u8]>> {
+    let depth = PARAM_STAR_DEPTH.with(|cell| {
+        let mut depth = cell.borrow_mut();
+        *depth += 1;
+        *depth
+    });
+
+    let result = if depth > MAX_PARAM_STAR_DEPTH {
+        LAST_COMMAND_PARSE_ERROR.with(|cell| {
+            *cell.borrow_mut() = Some(format!(
+                "star parameter nesting depth {} exceeds maximum of {}",
+                depth, MAX_PARAM_STAR_DEPTH
+            ))
+        });
+        IResult::Error(Err::Code(ErrorKind::Custom(PARAM_STAR_TOO_DEEP_ERR_CODE)))
+    } else {
+        param_star_body(inp)
+    };
+
+    PARAM_STAR_DEPTH.with(|cell| *cell.borrow_mut() -= 1);
+
+    result
+}
+
+// The maximum length, in bytes, of a single named parameter's declared
+// value that `param_kv` will accept. A client that advertises a value
+// length past this is more likely probing for a way to make the server
+// buffer an unbounded amount of memory than sending a genuine parameter, so
+// it's rejected outright with a parse error rather than `take!` returning
+// `Incomplete` and waiting on bytes that may never arrive.
+const MAX_PARAM_VALUE_LEN: usize = 100 * 1024 * 1024;
+const PARAM_VALUE_TOO_LONG_ERR_CODE: u32 = 777;
+
+named!(
+    param_kv_header<(&[u8], usize)>,
+    do_parse!(key: ident >> tag!(b" ") >> len: integer >> tag!(b"\n") >> (key, len))
+);
+
 // A named parameter is a name followed by a decimal integer of the number of
 // bytes in the parameter, followed by newline. The parameter value has no terminator.
 // ident <bytelen>\n
 // <bytelen bytes>
-named!(
-    param_kv<HashMap<&[u8], &[u8]>>,
-    do_parse!(
-        key: ident
-            >> tag!(b" ")
-            >> len: integer
-            >> tag!(b"\n")
-            >> val: take!(len)
-            >> (iter::once((key, val)).collect())
-    )
-);
+fn param_kv(inp: &[u8]) -> IResult<&[u8], HashMap<&[u8], &[u8]>> {
+    let (rest, (key, len)) = match param_kv_header(inp) {
+        IResult::Done(rest, header) => (rest, header),
+        IResult::Error(err) => return IResult::Error(err),
+        IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+    };
+
+    if len > MAX_PARAM_VALUE_LEN {
+        LAST_COMMAND_PARSE_ERROR.with(|cell| {
+            *cell.borrow_mut() = Some(format!(
+                "param {:?} declared length {} exceeds maximum of {}",
+                String::from_utf8_lossy(key),
+                len,
+                MAX_PARAM_VALUE_LEN
+            ))
+        });
+        return IResult::Error(Err::Code(ErrorKind::Custom(PARAM_VALUE_TOO_LONG_ERR_CODE)));
+    }
+
+    match take!(rest, len) {
+        IResult::Done(rest, val) => IResult::Done(rest, iter::once((key, val)).collect()),
+        IResult::Error(err) => IResult::Error(err),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
 
 /// Normal ssh protocol params:
 /// either a "*", which indicates a number of following parameters,
 /// or a named parameter whose value bytes follow.
 /// "count" is the number of required parameters, including the "*" parameter - but *not*
 /// the parameters that the "*" parameter expands to.
-fn params_ref(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<&[u8], &[u8]>> {
+///
+/// If `strict` is set, a key that's already present in the result (i.e. a
+/// duplicate parameter) is a parse error rather than letting the later
+/// occurrence silently overwrite the earlier one.
+fn params_ref(inp: &[u8], count: usize, strict: bool) -> IResult<&[u8], HashMap<&[u8], &[u8]>> {
+    if count > MAX_PARAM_COUNT {
+        LAST_COMMAND_PARSE_ERROR.with(|cell| {
+            *cell.borrow_mut() = Some(format!(
+                "param count {} exceeds maximum of {}",
+                count, MAX_PARAM_COUNT
+            ))
+        });
+        return IResult::Error(Err::Code(ErrorKind::Custom(TOO_MANY_PARAMS_ERR_CODE)));
+    }
+
     let mut inp = inp;
     let mut have = 0;
 
     let mut ret = HashMap::with_capacity(count);
 
     while have < count {
-        let res = alt!(inp,
-              param_star => { |kv: HashMap<_, _>| { have += 1; kv } }
-            | param_kv => { |kv: HashMap<_, _>| { have += kv.len(); kv } }
-        );
+        let res = if inp.starts_with(b"* ") {
+            // A star group is parsed outside `alt!`, which would replace a
+            // `Custom` error from it (e.g. nesting too deep, or too many
+            // params) with a generic `ErrorKind::Alt`.
+            match param_star(inp) {
+                IResult::Done(rest, kv) => {
+                    have += 1;
+                    IResult::Done(rest, kv)
+                }
+                failed => failed,
+            }
+        } else {
+            alt!(inp,
+                  param_star => { |kv: HashMap<_, _>| { have += 1; kv } }
+                | param_kv => { |kv: HashMap<_, _>| { have += kv.len(); kv } }
+            )
+        };
 
         match res {
             IResult::Done(rest, val) => {
                 for (k, v) in val.into_iter() {
+                    if strict && ret.contains_key(k) {
+                        LAST_COMMAND_PARSE_ERROR.with(|cell| {
+                            *cell.borrow_mut() =
+                                Some(format!("duplicate parameter key {:?}", String::from_utf8_lossy(k)))
+                        });
+                        return IResult::Error(Err::Code(ErrorKind::Custom(
+                            DUPLICATE_PARAM_KEY_ERR_CODE,
+                        )));
+                    }
                     ret.insert(k, v);
                 }
                 inp = rest;
@@ -191,14 +554,18 @@ fn params_ref(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<&[u8], &[u8]>>
     IResult::Done(inp, ret)
 }
 
-fn params(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>> {
+fn params_with_strictness(
+    inp: &[u8],
+    count: usize,
+    strict: bool,
+) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>> {
     // Parsing of params is down first by extracting references, then converting them to owned
     // Vecs, if sucessful. This ensures that validating inputs (i.e. making sure we have all the
     // data we need) is not dependent on the length of the arguments, and instead is only dependent
     // on the complexity of what is being parsed (i.e. the count of arguments). This is important
     // because this is hooked into a Tokio decoder, so it'll get called in a loop every time new
     // data is received (e.g. ~8KiB intervals, since that is the buffer size).
-    match params_ref(inp, count) {
+    match params_ref(inp, count, strict) {
         // Convert to owned if successful.
         IResult::Done(rest, ret) => {
             let ret = ret
@@ -213,6 +580,18 @@ fn params(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>>
     }
 }
 
+fn params(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>> {
+    params_with_strictness(inp, count, false)
+}
+
+/// Like `params`, but rejects a request that repeats a parameter key, rather
+/// than letting the later occurrence silently overwrite the earlier one.
+/// Not currently wired into any command's parsing; callers that want strict
+/// clients can pass this instead of `params` to `parse_with_params`.
+pub fn params_strict(inp: &[u8], count: usize) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>> {
+    params_with_strictness(inp, count, true)
+}
+
 fn notcomma(b: u8) -> bool {
     b != b','
 }
@@ -231,7 +610,10 @@ named!(
 
 // Extract parameters from batch - same signature as params
 // Batch parameters are a comma-delimited list of parameters; count is unused
-// and there's no notion of star params.
+// and there's no notion of star params: unlike the unbatched "* N\n..." syntax,
+// batch params are always sent flat (e.g. "heads=...,common=..."), so commands
+// like `getbundle` that rely on `*` when unbatched parse the same way here
+// without any special-casing.
 named_args!(batch_params(_count: usize)<HashMap<Vec<u8>, Vec<u8>>>,
     map!(
         separated_list_complete!(tag!(","), batch_param_escaped),
@@ -239,11 +621,46 @@ named_args!(batch_params(_count: usize)<HashMap<Vec<u8>, Vec<u8>>>,
     )
 );
 
-// A nodehash is simply 40 hex digits.
-named!(
-    nodehash<HgChangesetId>,
-    map_res!(take!(40), |v: &[u8]| str::parse(str::from_utf8(v)?))
-);
+/// Find the position and value of the first byte in `v` that isn't an ASCII
+/// hex digit, if any. Uppercase digits are allowed here (the caller
+/// normalizes them to lowercase before parsing), only non-hex bytes count.
+fn find_bad_hex_digit(v: &[u8]) -> Option<(usize, u8)> {
+    v.iter()
+        .position(|b| !b.is_ascii_hexdigit())
+        .map(|pos| (pos, v[pos]))
+}
+
+// A nodehash is simply 40 hex digits. The hex digits are normalized to
+// lowercase before parsing, since some clients send uppercase hashes and
+// the underlying hex decoder only accepts lowercase. Unlike `manifestid`,
+// this validates each byte up front instead of relying on `map_res!`, so a
+// stray non-hex byte gets a dedicated error naming its position rather than
+// an opaque `ErrorKind::MapRes`.
+fn nodehash(inp: &[u8]) -> IResult<&[u8], HgChangesetId> {
+    match take!(inp, 40) {
+        IResult::Done(rest, v) => {
+            if let Some((pos, byte)) = find_bad_hex_digit(v) {
+                LAST_COMMAND_PARSE_ERROR.with(|cell| {
+                    *cell.borrow_mut() = Some(format!(
+                        "invalid hex digit {:#04x} at position {} in nodehash",
+                        byte, pos
+                    ))
+                });
+                return IResult::Error(Err::Code(ErrorKind::Custom(BAD_HEX_DIGIT_ERR_CODE)));
+            }
+
+            match str::from_utf8(&v.to_ascii_lowercase())
+                .ok()
+                .and_then(|s| s.parse().ok())
+            {
+                Some(hash) => IResult::Done(rest, hash),
+                None => IResult::Error(Err::Code(ErrorKind::Custom(BAD_HEX_DIGIT_ERR_CODE))),
+            }
+        }
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+    }
+}
 
 // A manifestid is simply 40 hex digits.
 named!(
@@ -258,16 +675,94 @@ named!(
 );
 
 // A space-separated list of pairs.
-named!(
-    pairlist<Vec<(HgChangesetId, HgChangesetId)>>,
-    separated_list_complete!(tag!(" "), pair)
-);
+fn pairlist(inp: &[u8]) -> IResult<&[u8], Vec<(HgChangesetId, HgChangesetId)>> {
+    bounded_separated_list(inp, b" ", MAX_LIST_ELEMENT_LEN, MAX_LIST_COUNT, pair)
+}
 
 // A space-separated list of changeset IDs
-named!(
-    hashlist<Vec<HgChangesetId>>,
-    separated_list_complete!(tag!(" "), nodehash)
-);
+fn hashlist(inp: &[u8]) -> IResult<&[u8], Vec<HgChangesetId>> {
+    bounded_separated_list(inp, b" ", MAX_LIST_ELEMENT_LEN, MAX_LIST_COUNT, nodehash)
+}
+
+/// Like `hashlist`, but specialized for `known`'s `nodes` param, whose lists
+/// can get very large. Sizes the output `Vec` up front from the input's byte
+/// length (each node is a 40-hex-digit hash plus a separating space, so
+/// `len / 41` is a good capacity estimate) and parses the whole list in a
+/// single pass, rather than growing it incrementally the way
+/// `separated_list_complete!` does.
+fn known_nodes(inp: &[u8]) -> IResult<&[u8], Vec<HgChangesetId>> {
+    let mut out = Vec::with_capacity(inp.len() / 41 + 1);
+    let mut rest = inp;
+
+    loop {
+        let node = match nodehash(rest) {
+            IResult::Done(r, node) => {
+                rest = r;
+                node
+            }
+            _ => break,
+        };
+        out.push(node);
+
+        match tag!(rest, " ") {
+            IResult::Done(r, _) => rest = r,
+            _ => break,
+        }
+    }
+
+    IResult::Done(rest, out)
+}
+
+// A list of changeset IDs, separated by either commas or spaces, but never a
+// mix of both within the same list. Unlike `hashlist`, which is always
+// space-separated and is kept as-is for existing callers, this is meant for
+// commands that want to accept either convention (e.g. some client versions
+// send `getbundle`'s `heads`/`common` comma-separated instead of the usual
+// space-separated format).
+fn node_list(inp: &[u8]) -> IResult<&[u8], Vec<HgChangesetId>> {
+    if let IResult::Done(rest, nodes) = separated_list_complete!(inp, tag!(","), nodehash) {
+        if rest.is_empty() {
+            return IResult::Done(rest, nodes);
+        }
+    }
+    hashlist(inp)
+}
+
+// A getfiles entry is a 40-hex-digit node immediately followed by the rest
+// of its line, which is the file's path. Like `known_nodes`, this hand-rolls
+// the loop instead of using `separated_list_complete!`, since the last entry
+// has no trailing newline to split on: the list is framed by the
+// surrounding param's declared byte length, not a delimiter.
+fn getfiles_list(inp: &[u8]) -> IResult<&[u8], Vec<(HgNodeHash, Bytes)>> {
+    let mut out = Vec::new();
+    let mut rest = inp;
+
+    while !rest.is_empty() {
+        let node = match nodehash(rest) {
+            IResult::Done(r, node) => {
+                rest = r;
+                node.into_nodehash()
+            }
+            _ => break,
+        };
+
+        let path = match take_until_and_consume1!(rest, "\n") {
+            IResult::Done(r, path) => {
+                rest = r;
+                path
+            }
+            _ => {
+                let path = rest;
+                rest = &rest[rest.len()..];
+                path
+            }
+        };
+
+        out.push((node, Bytes::copy_from_slice(path)));
+    }
+
+    IResult::Done(rest, out)
+}
 
 // A changeset is simply 40 hex digits.
 named!(
@@ -281,23 +776,49 @@ named!(
     separated_list_complete!(tag!(" "), hg_changeset_id)
 );
 
+// A leading `auth <token>\n` prelude, as sent by deployments that wrap the
+// SSH protocol with a per-request auth token. The token is opaque to this
+// parser: it's whatever bytes precede the newline.
+named!(
+    auth_prelude<Bytes>,
+    do_parse!(
+        tag!(b"auth ") >> token: take_until_and_consume1!("\n") >> (Bytes::from(token.to_vec()))
+    )
+);
+
 // A space-separated list of manifest IDs
 named!(
     manifestlist<Vec<HgManifestId>>,
     separated_list_complete!(tag!(" "), manifestid)
 );
 
+// A single word in a `stringlist`, percent-decoded. Reimplemented on top of
+// `percent_decoded_string` (rather than restricting to alphanumeric
+// characters) so that clients can send punctuation or percent-encoded
+// bytes; plain alphanumeric tokens like `force` still round-trip unchanged
+// since decoding them is a no-op.
+fn stringlist_word(inp: &[u8]) -> IResult<&[u8], String> {
+    match take_while!(inp, notspace) {
+        IResult::Done(rest, word) => match percent_decoded_string(word) {
+            IResult::Done(_, s) => IResult::Done(rest, s),
+            IResult::Error(e) => IResult::Error(e),
+            IResult::Incomplete(n) => IResult::Incomplete(n),
+        },
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+    }
+}
+
 // A space-separated list of strings
-named!(
-    stringlist<Vec<String>>,
-    separated_list!(
-        complete!(tag!(" ")),
-        map_res!(
-            map_res!(take_while!(is_alphanumeric), str::from_utf8),
-            FromStr::from_str
-        )
+fn stringlist(inp: &[u8]) -> IResult<&[u8], Vec<String>> {
+    bounded_separated_list(
+        inp,
+        b" ",
+        MAX_LIST_ELEMENT_LEN,
+        MAX_LIST_COUNT,
+        stringlist_word,
     )
-);
+}
 
 named!(
     hex_stringlist<Vec<String>>,
@@ -312,6 +833,70 @@ named!(
     })
 );
 
+/// Given a client's decoded `bundlecaps`, work out the bundle format version
+/// it requested, so callers don't have to re-parse the caps themselves.
+/// `HG20` (bundle2) maps to version `"02"`; a classic `HG10GZ`/`HG10BZ`/
+/// `HG10UN` changegroup capability (version 1, with a compression suffix)
+/// maps to version `"01"`. Returns `None` if `bundlecaps` advertised neither.
+fn bundle_version_from_bundlecaps(bundlecaps: &HashSet<Vec<u8>>) -> Option<String> {
+    bundlecaps.iter().find_map(|cap| match cap.as_slice() {
+        [b'H', b'G', major, minor, ..] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            Some(format!("0{}", *major as char))
+        }
+        _ => None,
+    })
+}
+
+/// Parse the `phases` param, tolerating both the usual boolean form and the
+/// structured form some protocol versions send instead: a `node_list` of
+/// phase heads. Returns `(phases, phase_heads)`, mirroring the two
+/// `GetbundleArgs` fields it feeds.
+fn phases_arg_from_kv(
+    kv: &HashMap<Vec<u8>, Vec<u8>>,
+) -> Result<(bool, Option<Vec<HgChangesetId>>)> {
+    let v = match kv.get(b"phases".as_ref()) {
+        None => return Ok((false, None)),
+        Some(v) => v,
+    };
+
+    if let IResult::Done(rest, phases) = boolean(v.as_ref()) {
+        if rest.is_empty() {
+            return Ok((phases, None));
+        }
+    }
+
+    match node_list(v.as_ref()) {
+        IResult::Done(rest, heads) if rest.is_empty() => Ok((false, Some(heads))),
+        IResult::Done(..) => bail!("unconsumed characters remain after parsing param: phases"),
+        IResult::Incomplete(err) => bail!("param parse incomplete: {:?}", err),
+        IResult::Error(err) => Err(param_parse_error("phases", &err)),
+    }
+}
+
+/// Build a `GetbundleArgs` from a `getbundle`-shaped param map, shared by
+/// both the `getbundle` and `debuggetbundle` parse arms.
+fn getbundle_args_from_kv(kv: &HashMap<Vec<u8>, Vec<u8>>) -> Result<GetbundleArgs> {
+    let bundlecaps: HashSet<Vec<u8>> = parseval_default(kv, "bundlecaps", commavalues)?
+        .into_iter()
+        .collect();
+    let bundle_version = bundle_version_from_bundlecaps(&bundlecaps);
+    let (phases, phase_heads) = phases_arg_from_kv(kv)?;
+    Ok(GetbundleArgs {
+        heads: parseval_option(kv, "heads", node_list)?,
+        common: parseval_option(kv, "common", node_list)?,
+        bundlecaps,
+        bundle_version,
+        listkeys: parseval_default(kv, "listkeys", commavalues)?,
+        phases,
+        phase_heads,
+        cbattempted: parseval_default(kv, "cbattempted", boolean)?,
+        obsmarkers: parseval_default(kv, "obsmarkers", boolean)?,
+        // Historically, a changegroup part was always included; `cg` lets a
+        // client opt out, so default to `true` when the param is absent.
+        cg: parseval_option(kv, "cg", boolean)?.unwrap_or(true),
+    })
+}
+
 /// A comma-separated list of arbitrary values. The input is assumed to be
 /// complete and exact.
 fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
@@ -330,6 +915,67 @@ fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
     }
 }
 
+fn notspace(b: u8) -> bool {
+    b != b' '
+}
+
+fn capname_char(b: u8) -> bool {
+    b != b'=' && b != b' '
+}
+
+// A single entry in a capabilities string: either a bare capability name, or
+// a name followed by a comma-separated list of values
+// (e.g. "unbundle=HG10GZ,HG10BZ,HG10UN").
+named!(
+    client_cap<(&[u8], &[u8])>,
+    do_parse!(
+        name: take_while1!(capname_char)
+            >> value: map!(
+                opt!(complete!(do_parse!(tag!("=") >> v: take_while!(notspace) >> (v)))),
+                |v: Option<&[u8]>| v.unwrap_or(b"")
+            )
+            >> ((name, value))
+    )
+);
+
+// A space-separated list of capability entries, as advertised by a client
+// during `hello`/`capabilities` negotiation.
+named!(
+    client_caps_list<Vec<(&[u8], &[u8])>>,
+    separated_list_complete!(tag!(" "), client_cap)
+);
+
+/// Parse a client's advertised capabilities string (as sent during `hello`/
+/// `capabilities` negotiation) into a map from capability name to its
+/// comma-separated sub-values, reusing the same comma-decoding as `bundlecaps`.
+pub fn parse_client_caps(caps: &[u8]) -> Result<HashMap<String, Vec<String>>> {
+    if caps.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    match client_caps_list(caps) {
+        IResult::Done(rest, entries) => match match_eof(rest) {
+            IResult::Done(..) => entries
+                .into_iter()
+                .map(|(name, value)| {
+                    let name = str::from_utf8(name)?.to_string();
+                    let values = match commavalues(value) {
+                        IResult::Done(_, values) => values
+                            .into_iter()
+                            .map(|v| Ok(str::from_utf8(&v)?.to_string()))
+                            .collect::<Result<Vec<String>>>()?,
+                        _ => bail!("failed to parse capability values for {}", name),
+                    };
+                    Ok((name, values))
+                })
+                .collect(),
+            _ => bail!("unconsumed characters remain after parsing capabilities"),
+        },
+        IResult::Incomplete(err) => bail!("capabilities parse incomplete: {:?}", err),
+        IResult::Error(err) => bail!("capabilities parse failed: {:?}", err),
+    }
+}
+
 fn notsemi(b: u8) -> bool {
     b != b';'
 }
@@ -352,6 +998,18 @@ named!(
 );
 
 named!(match_eof<&'a [u8]>, eof!());
+
+/// Build an error for a failed param parse, preferring the descriptive
+/// detail a parser may have stashed in `LAST_COMMAND_PARSE_ERROR` (since
+/// nom's `ErrorKind::Custom` can only carry a numeric code) over the raw nom
+/// error's `Debug` output.
+fn param_parse_error(key: &str, err: &Err<&[u8]>) -> Error {
+    match LAST_COMMAND_PARSE_ERROR.with(|cell| cell.borrow_mut().take()) {
+        Some(detail) => anyhow!("param {} parse failed: {}", key, detail),
+        None => anyhow!("param {} parse failed: {:?}", key, err),
+    }
+}
+
 /// Given a hash of parameters, look up a parameter by name, and if it exists,
 /// apply a parser to its value. If it doesn't, error out.
 fn parseval<'a, F, T>(params: &'a HashMap<Vec<u8>, Vec<u8>>, key: &str, parser: F) -> Result<T>
@@ -366,7 +1024,7 @@ where
                 _ => bail!("Unconsumed characters remain after parsing param"),
             },
             IResult::Incomplete(err) => bail!("param parse incomplete: {:?}", err),
-            IResult::Error(err) => bail!("param parse failed: {:?}", err),
+            IResult::Error(err) => return Err(param_parse_error(key, &err)),
         },
     }
 }
@@ -393,7 +1051,7 @@ where
                 ),
             },
             IResult::Incomplete(err) => bail!("param parse incomplete: {:?}", err),
-            IResult::Error(err) => bail!("param parse failed: {:?}", err),
+            IResult::Error(err) => return Err(param_parse_error(key, &err)),
         },
     }
 }
@@ -419,7 +1077,7 @@ where
                 ),
             },
             IResult::Incomplete(err) => bail!("param parse incomplete: {:?}", err),
-            IResult::Error(err) => bail!("param parse failed: {:?}", err),
+            IResult::Error(err) => return Err(param_parse_error(key, &err)),
         },
     }
 }
@@ -449,7 +1107,10 @@ where
         IResult::Done(rest, v) => {
             match func(v) {
                 Ok(t) => IResult::Done(rest, t),
-                Err(_e) => IResult::Error(Err::Code(ErrorKind::Custom(999999))), // ugh
+                Err(e) => {
+                    LAST_COMMAND_PARSE_ERROR.with(|cell| *cell.borrow_mut() = Some(e.to_string()));
+                    IResult::Error(Err::Code(ErrorKind::Custom(999999))) // ugh
+                }
             }
         }
         IResult::Error(e) => IResult::Error(e),
@@ -466,14 +1127,60 @@ fn ident_string(inp: &[u8]) -> IResult<&[u8], String> {
     }
 }
 
+/// Parse an alphanumeric identifier (e.g. `bookmarks`, `phases`), and map it
+/// to `String`. Assumes input is complete, like `utf8_string_complete`.
+fn ident_string_alphanum(inp: &[u8]) -> IResult<&[u8], String> {
+    match take_while1!(inp, is_alphanumeric) {
+        IResult::Done(rest, s) => IResult::Done(rest, String::from_utf8_lossy(s).into_owned()),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+        IResult::Error(e) => IResult::Error(e),
+    }
+}
+
 /// Parse utf8 string, assumes that input is complete
 fn utf8_string_complete(inp: &[u8]) -> IResult<&[u8], String> {
     match String::from_utf8(Vec::from(inp)) {
         Ok(s) => IResult::Done(b"", s),
+        Err(e) => {
+            LAST_COMMAND_PARSE_ERROR
+                .with(|cell| *cell.borrow_mut() = Some(format!("invalid UTF-8: {}", e)));
+            IResult::Error(Err::Code(ErrorKind::Custom(BAD_UTF8_ERR_CODE)))
+        }
+    }
+}
+
+/// Percent-decode a parameter value and interpret the result as UTF-8.
+/// Unlike `ident_string`, this accepts any character the client percent-
+/// encodes (e.g. `%2d` for `-`), at the cost of requiring clients that want
+/// such characters in a namespace to actually encode them; plain
+/// alphanumeric/underscore input round-trips unchanged since percent-decoding
+/// it is a no-op. Assumes that input is complete, like `utf8_string_complete`.
+fn percent_decoded_string(inp: &[u8]) -> IResult<&[u8], String> {
+    match percent_decode(inp).decode_utf8() {
+        Ok(s) => IResult::Done(b"", s.into_owned()),
         Err(_) => IResult::Error(Err::Code(ErrorKind::Custom(BAD_UTF8_ERR_CODE))),
     }
 }
 
+/// Characters that only show up in Mercurial revset expression syntax (DAG
+/// range `::`, `%` ancestors-only, function calls, set operators, and
+/// quoting) and never in a literal `lookup` key (a bookmark, tag, or hex
+/// node id). Used to flag a `lookup` key that's actually a revset expression
+/// so the server can route it to a revset evaluator instead of a direct
+/// lookup.
+const REVSET_OPERATOR_CHARS: &[char] = &['(', ')', ':', '%', '+', '~', '^', ',', '\''];
+
+/// Classify a `lookup` command's key as either a literal key or a
+/// revset-looking expression, based on whether it contains any character
+/// that's only meaningful in revset syntax.
+fn classify_lookup_key(key: &str) -> LookupKind {
+    if key.contains(REVSET_OPERATOR_CHARS) {
+        LookupKind::RevsetExpression
+    } else {
+        LookupKind::Key
+    }
+}
+
 /// Parse an MPath; assumes that input is complete.
 fn path_complete(inp: &[u8]) -> IResult<&[u8], MPath> {
     match MPath::new(inp) {
@@ -528,16 +1235,22 @@ macro_rules! command_star {
 
 /// Parse a non-batched command
 fn parse_singlerequest(inp: &[u8]) -> IResult<&[u8], SingleRequest> {
-    parse_with_params(inp, params)
+    parse_with_params(inp, params, false)
 }
 
 struct Batch {
     cmds: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
-fn parse_batchrequest(inp: &[u8]) -> IResult<&[u8], Vec<SingleRequest>> {
+/// Reserved batch command name that aborts the rest of the batch instead of
+/// being parsed and executed as a command. Lets a client bail out of a batch
+/// partway through, e.g. after it decides the remaining commands are no
+/// longer needed.
+const BATCH_ABORT_CMD: &[u8] = b"abort";
+
+fn parse_batchrequest(inp: &[u8]) -> IResult<&[u8], (Vec<SingleRequest>, bool)> {
     fn parse_cmd(inp: &[u8]) -> IResult<&[u8], SingleRequest> {
-        parse_with_params(inp, batch_params)
+        parse_with_params(inp, batch_params, false)
     }
 
     let (rest, batch) = try_parse!(
@@ -548,7 +1261,12 @@ fn parse_batchrequest(inp: &[u8]) -> IResult<&[u8], Vec<SingleRequest>> {
     );
 
     let mut parsed_cmds = Vec::with_capacity(batch.cmds.len());
+    let mut aborted = false;
     for cmd in batch.cmds {
+        if cmd.0 == BATCH_ABORT_CMD {
+            aborted = true;
+            break;
+        }
         let full_cmd = Bytes::from([cmd.0, cmd.1].join(&b'\n'));
         // Jump through hoops to prevent the lifetime of `full_cmd` from leaking into the IResult
         // via errors.
@@ -564,7 +1282,7 @@ fn parse_batchrequest(inp: &[u8]) -> IResult<&[u8], Vec<SingleRequest>> {
         };
         parsed_cmds.push(cmd);
     }
-    IResult::Done(rest, parsed_cmds)
+    IResult::Done(rest, (parsed_cmds, aborted))
 }
 
 pub fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
@@ -572,17 +1290,32 @@ pub fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
         let origlen = buf.len();
         let parse_res = alt!(
             &buf[..],
-            map!(parse_batchrequest, Request::Batch) | map!(parse_singlerequest, Request::Single)
+            map!(parse_batchrequest, |(cmds, aborted)| Request::Batch { cmds, aborted })
+                | map!(parse_singlerequest, Request::Single)
         );
 
         match parse_res {
             IResult::Done(rest, val) => Some((origlen - rest.len(), val)),
             IResult::Incomplete(_) => None,
             IResult::Error(err) => {
-                println!("parse_request parsing error: {:?}", err);
-                Err(errors::ErrorKind::CommandParse(
-                    String::from_utf8_lossy(buf.as_ref()).into_owned(),
-                ))?
+                let offset = match &err {
+                    Err::Position(_, rest) | Err::NodePosition(_, rest, _) => {
+                        Some(origlen - rest.len())
+                    }
+                    Err::Code(_) | Err::Node(_, _) => None,
+                };
+                let detail = LAST_COMMAND_PARSE_ERROR.with(|cell| cell.borrow_mut().take());
+                let truncated = buf.len() > MAX_COMMAND_PARSE_ERROR_BYTES;
+                let shown = &buf.as_ref()[..buf.len().min(MAX_COMMAND_PARSE_ERROR_BYTES)];
+                let mut buf = String::from_utf8_lossy(shown).into_owned();
+                if truncated {
+                    buf.push_str("...(truncated)");
+                }
+                let command = match detail {
+                    Some(detail) => format!("{}: {}", buf, detail),
+                    None => buf,
+                };
+                Err(errors::ErrorKind::CommandParse { command, offset })?
             }
         }
     };
@@ -593,20 +1326,109 @@ pub fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
     }))
 }
 
+/// Like `parse_request`, but also returns whatever bytes are left in `buf`
+/// once the request has been parsed out of it. Some requests (e.g.
+/// `unbundle`) are followed by a streaming payload that can arrive in the
+/// same read as the request itself; returning the tail directly means
+/// callers pipelining such requests don't have to re-inspect `buf`
+/// themselves to find where the payload starts.
+pub fn parse_request_with_tail(buf: &mut BytesMut) -> Result<Option<(Request, Bytes)>> {
+    Ok(parse_request(buf)?.map(|req| {
+        let tail = buf.split_to(buf.len()).freeze();
+        (req, tail)
+    }))
+}
+
+/// Like `parse_request`, but when `expect_auth_prelude` is set, first strips
+/// an optional leading `auth <token>\n` prelude and returns the token
+/// separately from the `Request` that follows it. This lets a deployment
+/// that wraps the SSH protocol with a per-request auth token authenticate
+/// the connection without teaching every command parser about the token.
+///
+/// `expect_auth_prelude` must stay off for deployments that don't send the
+/// prelude, since otherwise a command that happens to start with `auth `
+/// (there are none today, but this parses generically) could be
+/// misinterpreted as a token.
+pub fn parse_request_with_auth_prelude(
+    buf: &mut BytesMut,
+    expect_auth_prelude: bool,
+) -> Result<Option<(Option<Bytes>, Request)>> {
+    if !expect_auth_prelude {
+        return Ok(parse_request(buf)?.map(|req| (None, req)));
+    }
+
+    match auth_prelude(&buf[..]) {
+        IResult::Done(rest, token) => {
+            let consumed = buf.len() - rest.len();
+            let _ = buf.split_to(consumed);
+            Ok(parse_request(buf)?.map(|req| (Some(token), req)))
+        }
+        IResult::Incomplete(_) => Ok(None),
+        IResult::Error(_) => Ok(parse_request(buf)?.map(|req| (None, req))),
+    }
+}
+
+/// A `tokio_util::codec::Decoder` wrapper around [`parse_request`], for
+/// callers that want to drive parsing off a `Framed` stream instead of
+/// managing the buffer and calling `parse_request` themselves.
+#[derive(Debug, Default)]
+pub struct HgSshDecoder;
+
+impl Decoder for HgSshDecoder {
+    type Item = Request;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>> {
+        parse_request(buf)
+    }
+}
+
+// A command we don't otherwise recognize, captured generically as its name
+// plus whatever params follow it in the "*"-expanded star syntax. Used only
+// when `parse_with_params` is asked to run in capture mode, instead of
+// rejecting unrecognized commands outright.
+fn parse_unknown_command(
+    inp: &[u8],
+    parse_params: fn(&[u8], usize) -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>>,
+) -> IResult<&[u8], SingleRequest> {
+    do_parse!(
+        inp,
+        name: map_res!(ident, str::from_utf8)
+            >> tag!("\n")
+            >> args: call!(parse_params, 1)
+            >> (SingleRequest::Unknown {
+                name: name.to_string(),
+                args,
+            })
+    )
+}
+
 /// Common parser, generalized over how to parse parameters (either unbatched or
-/// batched syntax.)
+/// batched syntax.) When `capture_unknown` is set, a command that doesn't match
+/// any of the known commands below is captured generically as
+/// `SingleRequest::Unknown` instead of causing a parse error.
 #[rustfmt::skip]
 fn parse_with_params(
     inp: &[u8],
     parse_params: fn(&[u8], usize)
         -> IResult<&[u8], HashMap<Vec<u8>, Vec<u8>>>,
+    capture_unknown: bool,
 ) -> IResult<&[u8], SingleRequest> {
     use SingleRequest::*;
 
-    alt!(inp,
-          command!("between", Between, parse_params, {
-              pairs => pairlist,
-          })
+    let res = alt!(inp,
+          call!(parse_command, "between", parse_params, 1,
+              |kv| {
+                  let pairs = parseval(&kv, "pairs", pairlist)?;
+                  if pairs.len() > MAX_BETWEEN_PAIRS {
+                      bail!(
+                          "between pairs count {} exceeds maximum of {}",
+                          pairs.len(),
+                          MAX_BETWEEN_PAIRS
+                      );
+                  }
+                  Ok(Between { pairs })
+              })
         | command!("branchmap", Branchmap, parse_params, {})
         | command!("capabilities", Capabilities, parse_params, {})
         | call!(parse_command, "debugwireargs", parse_params, 2+1,
@@ -620,32 +1442,72 @@ fn parse_with_params(
                 args: kv,
             }))
         | call!(parse_command, "getbundle", parse_params, 1,
-            |kv| Ok(Getbundle(GetbundleArgs {
-                // Some params are currently ignored, like:
-                // - obsmarkers
-                // - cg
-                // - cbattempted
-                // If those params are needed, they should be parsed here.
-                heads: parseval_default(&kv, "heads", hashlist)?,
-                common: parseval_default(&kv, "common", hashlist)?,
-                bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?.into_iter().collect(),
-                listkeys: parseval_default(&kv, "listkeys", commavalues)?,
-                phases: parseval_default(&kv, "phases", boolean)?,
-            })))
-        | command!("heads", Heads, parse_params, {})
-        | command!("hello", Hello, parse_params, {})
+            |kv| Ok(Getbundle(getbundle_args_from_kv(&kv)?)))
+        | call!(parse_command, "debuggetbundle", parse_params, 1,
+            |kv| {
+                let diagnostics = parseval_default(&kv, "diagnostics", boolean)?;
+                Ok(DebugGetbundle(getbundle_args_from_kv(&kv)?, diagnostics))
+            })
+        // `heads` has historically taken no parameters, so it may be sent
+        // either with or without the optional `bookmarks` flag.
+        | alt!(
+              call!(parse_command, "heads", parse_params, 1, |kv| Ok(Heads {
+                  bookmarks: parseval_default(&kv, "bookmarks", boolean)?,
+              }))
+            | call!(parse_command, "heads", parse_params, 0, |_| Ok(Heads {
+                  bookmarks: false,
+              }))
+          )
+        // `hello` may carry the client's advertised protocol line as a
+        // `payload` param, but bare `hello\n` (with no star group at all)
+        // must keep parsing as before.
+        | alt!(
+              call!(parse_command, "hello", parse_params, 1, |kv| Ok(Hello {
+                  payload: parseval_option(&kv, "payload", utf8_string_complete)?,
+              }))
+            | call!(parse_command, "hello", parse_params, 0, |_| Ok(Hello {
+                  payload: None,
+              }))
+          )
         | command!("listkeys", Listkeys, parse_params, {
-              namespace => ident_string,
+              namespace => percent_decoded_string,
         })
+        | command!("pushkey", Pushkey, parse_params, {
+              namespace => ident_string_alphanum,
+              key => utf8_string_complete,
+              old => utf8_string_complete,
+              new => utf8_string_complete,
+        })
+        | call!(parse_command, "listkeyspaged", parse_params, 3,
+            |kv| {
+                let limit = parseval(&kv, "limit", integer_complete)?;
+                if limit > MAX_LISTKEYS_PAGE_LIMIT {
+                    bail!(
+                        "listkeyspaged limit {} exceeds maximum of {}",
+                        limit,
+                        MAX_LISTKEYS_PAGE_LIMIT
+                    );
+                }
+                Ok(ListkeysPaged {
+                    namespace: parseval(&kv, "namespace", ident_string)?,
+                    offset: parseval(&kv, "offset", integer_complete)?,
+                    limit,
+                })
+            })
+        // `patterns` is hex-encoded per element (see `hex_stringlist`), which
+        // already lets a pattern carry arbitrary bytes such as `/` or `*`
+        // without needing a separate percent-decoded list combinator.
         | command!("listkeyspatterns", ListKeysPatterns, parse_params, {
              namespace => ident_string,
              patterns => hex_stringlist,
         })
-        | command!("lookup", Lookup, parse_params, {
-              key => utf8_string_complete,
-          })
+        | call!(parse_command, "lookup", parse_params, 1, |kv| {
+            let key = parseval(&kv, "key", utf8_string_complete)?;
+            let kind = classify_lookup_key(&key);
+            Ok(Lookup { key, kind })
+        })
         | command_star!("known", Known, parse_params, {
-              nodes => hashlist,
+              nodes => known_nodes,
           })
         | command_star!("knownnodes", Knownnodes, parse_params, {
               nodes => hg_changeset_list,
@@ -671,9 +1533,15 @@ fn parse_with_params(
                     )
                 ))?,
             })))
+        | call!(parse_command, "stream_out", parse_params, 1, |kv| {
+            Ok(StreamOut {
+                tag: parseval_option(&kv, "tag", utf8_string_complete)?
+            })
+        })
         | call!(parse_command, "stream_out_shallow", parse_params, 1, |kv| {
             Ok(StreamOutShallow {
-                tag: parseval_option(&kv, "tag", utf8_string_complete)?
+                tag: parseval_option(&kv, "tag", utf8_string_complete)?,
+                noflatmanifest: parseval_default(&kv, "noflatmanifest", python_bool)?,
             })
         })
         | command_star!("getpackv1", GetpackV1, parse_params, {})
@@ -681,7 +1549,339 @@ fn parse_with_params(
         | command!("getcommitdata", GetCommitData, parse_params, {
             nodes => hg_changeset_list,
         })
-    )
+        | command!("getfiles", Getfiles, parse_params, {
+            files => getfiles_list,
+        })
+        | command!("protocaps", Protocaps, parse_params, {
+            caps => stringlist,
+        })
+    );
+
+    match res {
+        IResult::Error(_) if capture_unknown => parse_unknown_command(inp, parse_params),
+        other => other,
+    }
+}
+
+/// Write a single named `key len\nvalue` parameter, the inverse of
+/// `param_kv`.
+fn write_named_param(out: &mut BytesMut, key: &str, value: &[u8]) {
+    out.extend_from_slice(key.as_bytes());
+    out.extend_from_slice(b" ");
+    out.extend_from_slice(value.len().to_string().as_bytes());
+    out.extend_from_slice(b"\n");
+    out.extend_from_slice(value);
+}
+
+/// Write a `* N\n` star group followed by each of `params` as a named
+/// parameter, the inverse of `param_star`.
+fn write_star_params(out: &mut BytesMut, params: &[(String, Vec<u8>)]) {
+    out.extend_from_slice(b"* ");
+    out.extend_from_slice(params.len().to_string().as_bytes());
+    out.extend_from_slice(b"\n");
+    for (key, value) in params {
+        write_named_param(out, key, value);
+    }
+}
+
+fn node_list_to_bytes(nodes: &[HgChangesetId]) -> Vec<u8> {
+    nodes
+        .iter()
+        .map(HgChangesetId::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into_bytes()
+}
+
+// The inverse of `getfiles_list`: each entry is the node's hex form
+// immediately followed by its path, entries separated by (but not trailed
+// by) a newline.
+fn getfiles_list_to_bytes(files: &[(HgNodeHash, Bytes)]) -> Vec<u8> {
+    files
+        .iter()
+        .map(|(node, path)| {
+            let mut entry = node.to_string().into_bytes();
+            entry.extend_from_slice(path);
+            entry
+        })
+        .collect::<Vec<_>>()
+        .join(&b'\n')
+}
+
+fn manifest_list_to_bytes(nodes: &[HgManifestId]) -> Vec<u8> {
+    nodes
+        .iter()
+        .map(HgManifestId::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into_bytes()
+}
+
+fn commavalues_to_bytes(values: &[Vec<u8>]) -> Vec<u8> {
+    values
+        .iter()
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+        .collect::<Vec<_>>()
+        .join(",")
+        .into_bytes()
+}
+
+/// `gettreepack`'s `directories` param batch-escapes each directory and
+/// terminates it with a comma, the inverse of `gettreepack_directories`.
+fn directories_to_bytes(dirs: &[Bytes]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for dir in dirs {
+        out.extend_from_slice(&batch::escape(dir));
+        out.push(b',');
+    }
+    out
+}
+
+/// Build the `* N\n`-wrapped param list shared by `getbundle` and
+/// `debuggetbundle`, the inverse of `getbundle_args_from_kv`. Fields that
+/// were absent (or at their documented default) are omitted, so re-parsing
+/// the result reproduces the same defaults rather than an explicit param.
+fn getbundle_kv_params(args: &GetbundleArgs) -> Vec<(String, Vec<u8>)> {
+    let mut params = Vec::new();
+    if let Some(heads) = &args.heads {
+        params.push(("heads".to_string(), node_list_to_bytes(heads)));
+    }
+    if let Some(common) = &args.common {
+        params.push(("common".to_string(), node_list_to_bytes(common)));
+    }
+    if !args.bundlecaps.is_empty() {
+        let caps: Vec<_> = args.bundlecaps.iter().cloned().collect();
+        params.push(("bundlecaps".to_string(), commavalues_to_bytes(&caps)));
+    }
+    if !args.listkeys.is_empty() {
+        params.push(("listkeys".to_string(), commavalues_to_bytes(&args.listkeys)));
+    }
+    if let Some(phase_heads) = &args.phase_heads {
+        params.push(("phases".to_string(), node_list_to_bytes(phase_heads)));
+    } else if args.phases {
+        params.push(("phases".to_string(), b"1".to_vec()));
+    }
+    if args.cbattempted {
+        params.push(("cbattempted".to_string(), b"1".to_vec()));
+    }
+    if args.obsmarkers {
+        params.push(("obsmarkers".to_string(), b"1".to_vec()));
+    }
+    if !args.cg {
+        params.push(("cg".to_string(), b"0".to_vec()));
+    }
+    params
+}
+
+fn gettreepack_kv_params(args: &GettreepackArgs) -> Vec<(String, Vec<u8>)> {
+    let basemfnodes: Vec<_> = args.basemfnodes.iter().copied().collect();
+    let mut params = vec![
+        ("rootdir".to_string(), args.rootdir.to_vec()),
+        ("mfnodes".to_string(), manifest_list_to_bytes(&args.mfnodes)),
+        (
+            "basemfnodes".to_string(),
+            manifest_list_to_bytes(&basemfnodes),
+        ),
+        (
+            "directories".to_string(),
+            directories_to_bytes(&args.directories),
+        ),
+    ];
+    if let Some(depth) = args.depth {
+        params.push(("depth".to_string(), depth.to_string().into_bytes()));
+    }
+    params
+}
+
+/// Encode a `SingleRequest` back into the wire form accepted by
+/// `parse_with_params`, the inverse of parsing. Used by proxy/replay tooling
+/// that needs to re-serialize a request it parsed earlier.
+pub fn encode_request(req: &SingleRequest, out: &mut BytesMut) {
+    use SingleRequest::*;
+
+    match req {
+        Between { pairs } => {
+            out.extend_from_slice(b"between\n");
+            let value = pairs
+                .iter()
+                .map(|(a, b)| format!("{}-{}", a, b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write_star_params(out, &[("pairs".to_string(), value.into_bytes())]);
+        }
+        Branchmap => out.extend_from_slice(b"branchmap\n"),
+        Capabilities => out.extend_from_slice(b"capabilities\n"),
+        ClientTelemetry { args } => {
+            out.extend_from_slice(b"clienttelemetry\n");
+            let params: Vec<_> = args
+                .iter()
+                .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.clone()))
+                .collect();
+            write_star_params(out, &params);
+        }
+        Debugwireargs {
+            one,
+            two,
+            all_args,
+        } => {
+            out.extend_from_slice(b"debugwireargs\n");
+            let extra: Vec<_> = all_args
+                .iter()
+                .filter(|(k, _)| k.as_slice() != b"one" && k.as_slice() != b"two")
+                .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.clone()))
+                .collect();
+            write_star_params(out, &extra);
+            write_named_param(out, "one", one);
+            write_named_param(out, "two", two);
+        }
+        Getbundle(args) => {
+            out.extend_from_slice(b"getbundle\n");
+            write_star_params(out, &getbundle_kv_params(args));
+        }
+        DebugGetbundle(args, diagnostics) => {
+            out.extend_from_slice(b"debuggetbundle\n");
+            let mut params = getbundle_kv_params(args);
+            if *diagnostics {
+                params.push(("diagnostics".to_string(), b"1".to_vec()));
+            }
+            write_star_params(out, &params);
+        }
+        Heads { bookmarks } => {
+            out.extend_from_slice(b"heads\n");
+            if *bookmarks {
+                write_named_param(out, "bookmarks", b"1");
+            }
+        }
+        Hello { payload } => {
+            out.extend_from_slice(b"hello\n");
+            if let Some(payload) = payload {
+                write_star_params(out, &[("payload".to_string(), payload.clone().into_bytes())]);
+            }
+        }
+        Listkeys { namespace } => {
+            out.extend_from_slice(b"listkeys\n");
+            write_named_param(out, "namespace", namespace.as_bytes());
+        }
+        Pushkey {
+            namespace,
+            key,
+            old,
+            new,
+        } => {
+            out.extend_from_slice(b"pushkey\n");
+            write_named_param(out, "namespace", namespace.as_bytes());
+            write_named_param(out, "key", key.as_bytes());
+            write_named_param(out, "old", old.as_bytes());
+            write_named_param(out, "new", new.as_bytes());
+        }
+        ListkeysPaged {
+            namespace,
+            offset,
+            limit,
+        } => {
+            out.extend_from_slice(b"listkeyspaged\n");
+            write_named_param(out, "namespace", namespace.as_bytes());
+            write_named_param(out, "offset", offset.to_string().as_bytes());
+            write_named_param(out, "limit", limit.to_string().as_bytes());
+        }
+        ListKeysPatterns {
+            namespace,
+            patterns,
+        } => {
+            out.extend_from_slice(b"listkeyspatterns\n");
+            write_named_param(out, "namespace", namespace.as_bytes());
+            let value = patterns
+                .iter()
+                .map(|p| hex::encode(p.as_bytes()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write_named_param(out, "patterns", value.as_bytes());
+        }
+        Lookup { key, kind: _ } => {
+            out.extend_from_slice(b"lookup\n");
+            write_named_param(out, "key", key.as_bytes());
+        }
+        Known { nodes } => {
+            out.extend_from_slice(b"known\n");
+            write_star_params(out, &[]);
+            write_named_param(out, "nodes", &node_list_to_bytes(nodes));
+        }
+        Knownnodes { nodes } => {
+            out.extend_from_slice(b"knownnodes\n");
+            write_star_params(out, &[]);
+            write_named_param(out, "nodes", &node_list_to_bytes(nodes));
+        }
+        Unbundle { heads } => {
+            out.extend_from_slice(b"unbundle\n");
+            write_named_param(out, "heads", heads.join(" ").as_bytes());
+        }
+        UnbundleReplay {
+            heads,
+            replaydata,
+            respondlightly,
+        } => {
+            out.extend_from_slice(b"unbundlereplay\n");
+            write_named_param(out, "heads", heads.join(" ").as_bytes());
+            write_named_param(out, "replaydata", replaydata.as_bytes());
+            write_named_param(
+                out,
+                "respondlightly",
+                if *respondlightly { b"1" } else { b"0" },
+            );
+        }
+        Gettreepack(args) => {
+            out.extend_from_slice(b"gettreepack\n");
+            write_star_params(out, &gettreepack_kv_params(args));
+        }
+        StreamOut { tag } => {
+            out.extend_from_slice(b"stream_out\n");
+            match tag {
+                Some(tag) => write_star_params(out, &[("tag".to_string(), tag.clone().into_bytes())]),
+                None => write_star_params(out, &[]),
+            }
+        }
+        StreamOutShallow {
+            tag,
+            noflatmanifest,
+        } => {
+            out.extend_from_slice(b"stream_out_shallow\n");
+            let mut params = Vec::new();
+            if let Some(tag) = tag {
+                params.push(("tag".to_string(), tag.clone().into_bytes()));
+            }
+            if *noflatmanifest {
+                params.push(("noflatmanifest".to_string(), b"True".to_vec()));
+            }
+            write_star_params(out, &params);
+        }
+        GetpackV1 => {
+            out.extend_from_slice(b"getpackv1\n");
+            write_star_params(out, &[]);
+        }
+        GetpackV2 => {
+            out.extend_from_slice(b"getpackv2\n");
+            write_star_params(out, &[]);
+        }
+        GetCommitData { nodes } => {
+            out.extend_from_slice(b"getcommitdata\n");
+            write_named_param(out, "nodes", &node_list_to_bytes(nodes));
+        }
+        Getfiles { files } => {
+            out.extend_from_slice(b"getfiles\n");
+            write_named_param(out, "files", &getfiles_list_to_bytes(files));
+        }
+        Protocaps { caps } => {
+            out.extend_from_slice(b"protocaps\n");
+            write_named_param(out, "caps", caps.join(" ").as_bytes());
+        }
+        Unknown { name, args } => {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b"\n");
+            for (key, value) in args {
+                write_named_param(out, &String::from_utf8_lossy(key), value);
+            }
+        }
+    }
 }
 
 /// Test individual combinators
@@ -693,12 +1893,54 @@ mod test {
 
     use super::*;
 
+    #[mononoke::test]
+    fn test_hg_ssh_decoder_split_across_fills() {
+        let mut decoder = HgSshDecoder;
+        let mut buf = BytesMut::new();
+
+        // First fill: less than one full request, so the decoder must ask
+        // for more bytes rather than erroring out.
+        buf.extend_from_slice(b"hea");
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+
+        // Second fill: completes the first request and starts the second.
+        buf.extend_from_slice(b"ds\nhea");
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(Request::Single(SingleRequest::Heads { bookmarks: false }))
+        );
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+
+        // Third fill: completes the second request.
+        buf.extend_from_slice(b"ds\n");
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(Request::Single(SingleRequest::Heads { bookmarks: false }))
+        );
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+    }
+
     #[mononoke::test]
     fn test_integer() {
         assert_eq!(integer(b"1234 "), IResult::Done(&b" "[..], 1234));
         assert_eq!(integer(b"1234"), IResult::Incomplete(Needed::Unknown));
     }
 
+    #[mononoke::test]
+    fn test_integer_overflow() {
+        let too_big = format!("{} ", u128::from(usize::MAX) + 1);
+        assert_eq!(
+            integer(too_big.as_bytes()),
+            IResult::Error(Err::Code(ErrorKind::Custom(INTEGER_OVERFLOW_ERR_CODE)))
+        );
+
+        // A non-numeric value is a distinct error from an overflowing one.
+        assert_eq!(
+            integer(b"notanumber "),
+            IResult::Error(Err::Code(ErrorKind::Digit))
+        );
+    }
+
     #[mononoke::test]
     fn test_ident() {
         assert_eq!(
@@ -792,6 +2034,19 @@ mod test {
         );
     }
 
+    #[mononoke::test]
+    fn test_param_kv_declared_length_too_large_rejected() {
+        // The declared length is absurd, but the actual payload is tiny: a
+        // real allocation-exhaustion attempt wouldn't include the gigabytes
+        // it claims, so this must fail deterministically rather than wait
+        // (as Incomplete) for bytes that will never come.
+        let p = b"foo 999999999\nbar";
+        match param_kv(p) {
+            IResult::Error(Err::Code(ErrorKind::Custom(PARAM_VALUE_TOO_LONG_ERR_CODE))) => {}
+            bad => panic!("expected a param-value-too-long parse error, got {:?}", bad),
+        }
+    }
+
     #[mononoke::test]
     fn test_params() {
         let p = b"bar 12\n\
@@ -863,6 +2118,77 @@ mod test {
         }
     }
 
+    #[mononoke::test]
+    fn test_params_duplicate_key_lenient_last_wins() {
+        let p = b"foo 1\n\
+                  afoo 1\n\
+                  b";
+
+        match params(p, 2) {
+            IResult::Done(rest, v) => {
+                assert_eq!(rest, b"");
+                assert_eq!(v, hashmap! { b"foo".to_vec() => b"b".to_vec() });
+            }
+            bad => panic!("bad result {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_params_duplicate_key_strict_errors() {
+        let p = b"foo 1\n\
+                  afoo 1\n\
+                  b";
+
+        match params_strict(p, 2) {
+            IResult::Error(Err::Code(ErrorKind::Custom(DUPLICATE_PARAM_KEY_ERR_CODE))) => {}
+            bad => panic!("expected a duplicate key parse error, got {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_params_star_count_too_large_rejected() {
+        // A `* <count>` group whose count exceeds MAX_PARAM_COUNT must be
+        // rejected outright, rather than attempting a `HashMap` allocation
+        // sized to the (attacker-controlled) count.
+        let star = b"* 4294967295\n";
+        match params(star, 1) {
+            IResult::Error(Err::Code(ErrorKind::Custom(TOO_MANY_PARAMS_ERR_CODE))) => {}
+            bad => panic!("expected a too-many-params parse error, got {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_params_star_nesting_depth_exceeded() {
+        // 12 nested `"* 1\n"` groups, each requiring exactly one further
+        // param, terminated by a plain param. This exceeds
+        // MAX_PARAM_STAR_DEPTH (10) and must be rejected with a clean parse
+        // error rather than recursing further.
+        let mut nested = b"* 1\n".repeat(12);
+        nested.extend_from_slice(b"foo 0\n");
+
+        match params(&nested, 1) {
+            IResult::Error(Err::Code(ErrorKind::Custom(PARAM_STAR_TOO_DEEP_ERR_CODE))) => {}
+            bad => panic!("expected a too-deep parse error, got {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_params_star_nesting_depth_at_default_max_accepted() {
+        // Exactly MAX_PARAM_STAR_DEPTH (10) nested `"* 1\n"` groups is still
+        // within the default limit and must parse successfully, confirming
+        // the limit itself is allowed and only a deeper group is rejected.
+        let mut nested = b"* 1\n".repeat(MAX_PARAM_STAR_DEPTH);
+        nested.extend_from_slice(b"foo 0\n");
+
+        match params(&nested, 1) {
+            IResult::Done(remain, kv) => {
+                assert_eq!(remain, b"");
+                assert_eq!(kv, hashmap! { b"foo".to_vec() => vec![] });
+            }
+            bad => panic!("expected successful parse, got {:?}", bad),
+        }
+    }
+
     #[mononoke::test]
     fn test_params_star() {
         let star = b"* 1\n\
@@ -986,7 +2312,7 @@ mod test {
         assert_eq!(
             nodehash(b"000000000000000000000000000000x000000000")
                 .map_err(|err| Err::Code(err.into_error_kind())),
-            IResult::Error(Err::Code(ErrorKind::MapRes,))
+            IResult::Error(Err::Code(ErrorKind::Custom(BAD_HEX_DIGIT_ERR_CODE)))
         );
 
         assert_eq!(
@@ -995,6 +2321,33 @@ mod test {
         );
     }
 
+    #[mononoke::test]
+    fn test_nodehash_uppercase() {
+        assert_eq!(
+            nodehash(b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"),
+            nodehash(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_nodehash_bad_hex_digit_position() {
+        // 30 zeros, then the offending byte at position 30, then 9 more zeros.
+        let err = nodehash(b"000000000000000000000000000000x000000000")
+            .map_err(|err| Err::Code(err.into_error_kind()));
+        assert_eq!(
+            err,
+            IResult::Error(Err::Code(ErrorKind::Custom(BAD_HEX_DIGIT_ERR_CODE)))
+        );
+
+        LAST_COMMAND_PARSE_ERROR.with(|cell| *cell.borrow_mut() = None);
+        nodehash(b"000000000000000000000000000000x000000000");
+        let detail = LAST_COMMAND_PARSE_ERROR.with(|cell| cell.borrow_mut().take());
+        assert_eq!(
+            detail,
+            Some("invalid hex digit 0x78 at position 30 in nodehash".to_string())
+        );
+    }
+
     #[mononoke::test]
     fn test_parseval_extra_characters() {
         let kv = hashmap! {
@@ -1042,6 +2395,60 @@ mod test {
         assert_eq!(pair(&p[..40]), IResult::Incomplete(Needed::Size(41)));
     }
 
+    #[mononoke::test]
+    fn test_bounded_separated_list_element_cap() {
+        // Each element's raw span is capped at 3 bytes: "abc" fits, but a
+        // 4-byte element like "abcd" cannot be told apart from a
+        // pathologically long one, so it's rejected rather than silently
+        // truncated to "abc".
+        assert_eq!(
+            bounded_separated_list(b"abc def", b" ", 3, 100, stringlist_word),
+            IResult::Done(&b""[..], vec!["abc".to_string(), "def".to_string()])
+        );
+
+        match bounded_separated_list(b"abcd", b" ", 3, 100, stringlist_word) {
+            IResult::Error(Err::Code(ErrorKind::Custom(444))) => {}
+            bad => panic!("expected element cap to fire, got {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_bounded_separated_list_count_cap() {
+        assert_eq!(
+            bounded_separated_list(b"a b c", b" ", 100, 3, stringlist_word),
+            IResult::Done(
+                &b""[..],
+                vec!["a".to_string(), "b".to_string(), "c".to_string()]
+            )
+        );
+
+        match bounded_separated_list(b"a b c d", b" ", 100, 3, stringlist_word) {
+            IResult::Error(Err::Code(ErrorKind::Custom(555))) => {}
+            bad => panic!("expected count cap to fire, got {:?}", bad),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_bounded_many0_caps() {
+        assert_eq!(
+            bounded_many0(b"a,b,", 100, 100, batch_param_path),
+            IResult::Done(
+                &b""[..],
+                vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+            )
+        );
+
+        match bounded_many0(b"ab,", 1, 100, batch_param_path) {
+            IResult::Error(Err::Code(ErrorKind::Custom(444))) => {}
+            bad => panic!("expected element cap to fire, got {:?}", bad),
+        }
+
+        match bounded_many0(b"a,b,c,", 100, 2, batch_param_path) {
+            IResult::Error(Err::Code(ErrorKind::Custom(555))) => {}
+            bad => panic!("expected count cap to fire, got {:?}", bad),
+        }
+    }
+
     #[mononoke::test]
     fn test_pairlist() {
         let p =
@@ -1116,6 +2523,77 @@ mod test {
         );
     }
 
+    #[mononoke::test]
+    fn test_stringlist() {
+        assert_eq!(
+            stringlist(b"force"),
+            IResult::Done(&b""[..], vec!["force".to_string()])
+        );
+
+        assert_eq!(
+            stringlist(b"666f726365"),
+            IResult::Done(&b""[..], vec!["666f726365".to_string()])
+        );
+
+        assert_eq!(
+            stringlist(b"foo%20bar baz"),
+            IResult::Done(&b""[..], vec!["foo bar".to_string(), "baz".to_string()])
+        );
+    }
+
+    #[mononoke::test]
+    fn test_node_list() {
+        // Space-separated
+        let p =
+            b"0000000000000000000000000000000000000000 0000000000000000000000000000000000000000";
+        assert_eq!(
+            node_list(p),
+            IResult::Done(
+                &b""[..],
+                vec![HgChangesetId::new(NULL_HASH), HgChangesetId::new(NULL_HASH)]
+            )
+        );
+
+        // Comma-separated
+        let p =
+            b"0000000000000000000000000000000000000000,0000000000000000000000000000000000000000";
+        assert_eq!(
+            node_list(p),
+            IResult::Done(
+                &b""[..],
+                vec![HgChangesetId::new(NULL_HASH), HgChangesetId::new(NULL_HASH)]
+            )
+        );
+
+        // Single node (no separator needed either way)
+        let p = b"0000000000000000000000000000000000000000";
+        assert_eq!(
+            node_list(p),
+            IResult::Done(&b""[..], vec![HgChangesetId::new(NULL_HASH)])
+        );
+
+        // Mixed separators within one list should not be accepted: the
+        // leftover, unconsumed bytes are left on the wire rather than being
+        // silently dropped.
+        let p = b"0000000000000000000000000000000000000000,0000000000000000000000000000000000000000 0000000000000000000000000000000000000000";
+        let (rest, nodes) = match node_list(p) {
+            IResult::Done(rest, nodes) => (rest, nodes),
+            other => panic!("expected a partial parse, got {:?}", other),
+        };
+        assert!(
+            !rest.is_empty(),
+            "mixed-separator list should not be fully consumed"
+        );
+        assert_eq!(nodes, vec![HgChangesetId::new(NULL_HASH)]);
+    }
+
+    #[mononoke::test]
+    fn test_path_value() {
+        assert_eq!(path_value(b"a//b").unwrap(), Bytes::from("a/b"));
+        assert!(path_value(b"../x").is_err());
+        assert_eq!(path_value(b"a/b/c").unwrap(), Bytes::from("a/b/c"));
+    }
+
     #[mononoke::test]
     fn test_commavalues() {
         // Empty list
@@ -1145,6 +2623,32 @@ mod test {
         );
     }
 
+    #[mononoke::test]
+    fn test_parse_client_caps() {
+        assert_eq!(parse_client_caps(b"").unwrap(), hashmap! {});
+
+        assert_eq!(
+            parse_client_caps(b"lookup known getbundle").unwrap(),
+            hashmap! {
+                "lookup".to_string() => vec![],
+                "known".to_string() => vec![],
+                "getbundle".to_string() => vec![],
+            }
+        );
+
+        assert_eq!(
+            parse_client_caps(
+                b"lookup streamreqs=generaldelta,lz4revlog unbundle=HG10GZ,HG10BZ,HG10UN"
+            )
+            .unwrap(),
+            hashmap! {
+                "lookup".to_string() => vec![],
+                "streamreqs".to_string() => vec!["generaldelta".to_string(), "lz4revlog".to_string()],
+                "unbundle".to_string() => vec!["HG10GZ".to_string(), "HG10BZ".to_string(), "HG10UN".to_string()],
+            }
+        );
+    }
+
     #[mononoke::test]
     fn test_cmd() {
         let p = b"foo bar";
@@ -1292,7 +2796,13 @@ mod test_parse {
                    cmds 6\n\
                    hello ";
 
-        test_parse(inp, Request::Batch(vec![SingleRequest::Hello]))
+        test_parse(
+            inp,
+            Request::Batch {
+                cmds: vec![SingleRequest::Hello { payload: None }],
+                aborted: false,
+            },
+        )
     }
 
     #[mononoke::test]
@@ -1304,7 +2814,10 @@ mod test_parse {
 
         test_parse(
             inp,
-            Request::Batch(vec![SingleRequest::Known { nodes: vec![] }]),
+            Request::Batch {
+                cmds: vec![SingleRequest::Known { nodes: vec![] }],
+                aborted: false,
+            },
         )
     }
 
@@ -1317,10 +2830,41 @@ mod test_parse {
 
         test_parse(
             inp,
-            Request::Batch(vec![
-                SingleRequest::Hello,
-                SingleRequest::Known { nodes: vec![] },
-            ]),
+            Request::Batch {
+                cmds: vec![
+                    SingleRequest::Hello { payload: None },
+                    SingleRequest::Known { nodes: vec![] },
+                ],
+                aborted: false,
+            },
+        )
+    }
+
+    #[mononoke::test]
+    fn test_parse_batch_getbundle() {
+        let inp = "batch\n\
+                   * 0\n\
+                   cmds 104\n\
+                   getbundle heads=1111111111111111111111111111111111111111,\
+                   common=2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Batch {
+                cmds: vec![SingleRequest::Getbundle(GetbundleArgs {
+                    heads: Some(vec![hash_ones()]),
+                    common: Some(vec![hash_twos()]),
+                    bundlecaps: hashset![],
+                    bundle_version: None,
+                    listkeys: vec![],
+                    phases: false,
+                    phase_heads: None,
+                    cbattempted: false,
+                    obsmarkers: false,
+                    cg: true,
+                })],
+                aborted: false,
+            },
         )
     }
 
@@ -1338,6 +2882,33 @@ mod test_parse {
         );
     }
 
+    #[mononoke::test]
+    fn test_parse_between_exceeds_pair_cap() {
+        let pair = "1111111111111111111111111111111111111111-2222222222222222222222222222222222222222";
+        let pairs = vec![pair; MAX_BETWEEN_PAIRS + 1].join(" ");
+        let inp = format!("between\npairs {}\n{}", pairs.len(), pairs);
+
+        // The command-level `alt!` reports the failure as `ErrorKind::Alt`;
+        // the reason is in the detail that `parse_command` stashes.
+        LAST_COMMAND_PARSE_ERROR.with(|cell| *cell.borrow_mut() = None);
+        match parse_singlerequest(inp.as_bytes()) {
+            IResult::Error(_) => {}
+            bad => panic!(
+                "expected between to reject a pairs list over the cap, got {:?}",
+                bad
+            ),
+        }
+        let detail = LAST_COMMAND_PARSE_ERROR.with(|cell| cell.borrow_mut().take());
+        assert_eq!(
+            detail,
+            Some(format!(
+                "between pairs count {} exceeds maximum of {}",
+                MAX_BETWEEN_PAIRS + 1,
+                MAX_BETWEEN_PAIRS
+            ))
+        );
+    }
+
     #[mononoke::test]
     fn test_parse_branchmap() {
         let inp = "branchmap\n";
@@ -1346,17 +2917,82 @@ mod test_parse {
     }
 
     #[mononoke::test]
-    fn test_parse_capabilities() {
-        let inp = "capabilities\n";
+    fn test_parse_request_bad_command_name_reports_offset() {
+        let mut buf = BytesMut::from(&b"notacommand\n"[..]);
+
+        let err = parse_request(&mut buf).expect_err("should fail: unknown command");
+        match err.downcast_ref::<errors::ErrorKind>() {
+            Some(errors::ErrorKind::CommandParse { command, offset }) => {
+                assert_eq!(command, "notacommand\n");
+                assert_eq!(*offset, Some(0));
+            }
+            other => panic!("expected CommandParse, got {:?}", other),
+        }
+    }
 
-        test_parse(inp, Request::Single(SingleRequest::Capabilities {}));
+    #[mononoke::test]
+    fn test_parse_request_bad_command_name_error_is_bounded() {
+        // A huge malformed request shouldn't force the error path to
+        // allocate a string as large as the whole request.
+        let huge = "a".repeat(MAX_COMMAND_PARSE_ERROR_BYTES * 4);
+        let mut buf = BytesMut::from(huge.as_bytes());
+
+        let err = parse_request(&mut buf).expect_err("should fail: unknown command");
+        assert!(
+            err.to_string().len() < MAX_COMMAND_PARSE_ERROR_BYTES * 2,
+            "error message wasn't bounded: {} bytes",
+            err.to_string().len()
+        );
     }
 
     #[mononoke::test]
-    fn test_parse_debugwireargs() {
-        let inp = "debugwireargs\n\
-                   * 2\n\
-                   three 5\nTHREE\
+    fn test_parse_request_with_auth_prelude_present() {
+        let mut buf = BytesMut::from(&b"auth sometoken\nbranchmap\n"[..]);
+
+        let (token, req) = parse_request_with_auth_prelude(&mut buf, true)
+            .unwrap()
+            .expect("expected a fully parsed request");
+
+        assert_eq!(token, Some(Bytes::from(&b"sometoken"[..])));
+        assert_eq!(req, Request::Single(SingleRequest::Branchmap {}));
+    }
+
+    #[mononoke::test]
+    fn test_parse_request_without_auth_prelude() {
+        // When the caller doesn't opt into the prelude, a plain request
+        // parses exactly like `parse_request`.
+        let mut buf = BytesMut::from(&b"branchmap\n"[..]);
+
+        let (token, req) = parse_request_with_auth_prelude(&mut buf, false)
+            .unwrap()
+            .expect("expected a fully parsed request");
+
+        assert_eq!(token, None);
+        assert_eq!(req, Request::Single(SingleRequest::Branchmap {}));
+
+        // Also true when the flag is on but the client never sends a prelude.
+        let mut buf = BytesMut::from(&b"branchmap\n"[..]);
+
+        let (token, req) = parse_request_with_auth_prelude(&mut buf, true)
+            .unwrap()
+            .expect("expected a fully parsed request");
+
+        assert_eq!(token, None);
+        assert_eq!(req, Request::Single(SingleRequest::Branchmap {}));
+    }
+
+    #[mononoke::test]
+    fn test_parse_capabilities() {
+        let inp = "capabilities\n";
+
+        test_parse(inp, Request::Single(SingleRequest::Capabilities {}));
+    }
+
+    #[mononoke::test]
+    fn test_parse_debugwireargs() {
+        let inp = "debugwireargs\n\
+                   * 2\n\
+                   three 5\nTHREE\
                    empty 0\n\
                    one 3\nONE\
                    two 3\nTWO";
@@ -1375,6 +3011,25 @@ mod test_parse {
         );
     }
 
+    #[mononoke::test]
+    fn test_parse_debugwireargs_missing_required_param() {
+        // `two` is required but absent here; `empty` and `three` are just
+        // padding to satisfy the command's required param count.
+        let inp = "debugwireargs\n\
+                   * 1\n\
+                   empty 0\n\
+                   one 3\nONE\
+                   three 0\n";
+
+        let mut buf = BytesMut::from(inp);
+        let err = parse_request(&mut buf).expect_err("should fail: `two` is missing");
+        assert!(
+            err.to_string().contains("missing param two"),
+            "error did not name the missing param: {}",
+            err
+        );
+    }
+
     #[mononoke::test]
     fn test_parse_getbundle() {
         // with no arguments
@@ -1384,17 +3039,22 @@ mod test_parse {
         test_parse(
             inp,
             Request::Single(SingleRequest::Getbundle(GetbundleArgs {
-                heads: vec![],
-                common: vec![],
+                heads: None,
+                common: None,
                 bundlecaps: hashset![],
+                bundle_version: None,
                 listkeys: vec![],
                 phases: false,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
             })),
         );
 
         // with arguments
         let inp = "getbundle\n\
-             * 6\n\
+             * 7\n\
              heads 40\n\
              1111111111111111111111111111111111111111\
              common 81\n\
@@ -1405,16 +3065,236 @@ mod test_parse {
              key1,key2\
              phases 1\n\
              1\
+             cbattempted 1\n\
+             1\
              extra 5\n\
              extra";
         test_parse(
             inp,
             Request::Single(SingleRequest::Getbundle(GetbundleArgs {
-                heads: vec![hash_ones()],
-                common: vec![hash_twos(), hash_threes()],
+                heads: Some(vec![hash_ones()]),
+                common: Some(vec![hash_twos(), hash_threes()]),
                 bundlecaps: hashset![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
+                bundle_version: None,
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
                 phases: true,
+                phase_heads: None,
+                cbattempted: true,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_obsmarkers_and_cg() {
+        // `obsmarkers`, `cg`, and `cbattempted` all sent explicitly, each
+        // with a non-default value.
+        let inp = "getbundle\n\
+             * 3\n\
+             obsmarkers 1\n\
+             1\
+             cg 1\n\
+             0\
+             cbattempted 1\n\
+             1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: None,
+                common: None,
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: false,
+                phase_heads: None,
+                cbattempted: true,
+                obsmarkers: true,
+                cg: false,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_phases_boolean() {
+        let inp = "getbundle\n\
+             * 1\n\
+             phases 1\n\
+             1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: None,
+                common: None,
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: true,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_phases_heads_list() {
+        // Some protocol versions send `phases` as a list of phase heads
+        // rather than a plain boolean; that shouldn't fail to parse.
+        let inp = "getbundle\n\
+             * 1\n\
+             phases 81\n\
+             1111111111111111111111111111111111111111,2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: None,
+                common: None,
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: false,
+                phase_heads: Some(vec![hash_ones(), hash_twos()]),
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_debuggetbundle() {
+        let inp = "debuggetbundle\n\
+             * 3\n\
+             heads 40\n\
+             1111111111111111111111111111111111111111\
+             phases 1\n\
+             1\
+             diagnostics 1\n\
+             1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::DebugGetbundle(
+                GetbundleArgs {
+                    heads: Some(vec![hash_ones()]),
+                    common: None,
+                    bundlecaps: hashset![],
+                    bundle_version: None,
+                    listkeys: vec![],
+                    phases: true,
+                    phase_heads: None,
+                    cbattempted: false,
+                    obsmarkers: false,
+                    cg: true,
+                },
+                true,
+            )),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_heads_common_absent_vs_empty() {
+        // Absent `heads`/`common` params must parse to `None`, distinct
+        // from an explicitly-sent but empty list.
+        let inp = "getbundle\n\
+             * 0\n";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: None,
+                common: None,
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: false,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+
+        let inp = "getbundle\n\
+             * 2\n\
+             heads 0\n\
+             \
+             common 0\n\
+             ";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: Some(vec![]),
+                common: Some(vec![]),
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: false,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_bundle_version_from_bundlecaps() {
+        // `HG20` in bundlecaps means the client wants bundle format 02.
+        let inp = "getbundle\n\
+             * 1\n\
+             bundlecaps 19\n\
+             HG20,cap1,CAP2,cap3";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: None,
+                common: None,
+                bundlecaps: hashset![
+                    b"HG20".to_vec(),
+                    b"cap1".to_vec(),
+                    b"CAP2".to_vec(),
+                    b"cap3".to_vec()
+                ],
+                bundle_version: Some("02".to_string()),
+                listkeys: vec![],
+                phases: false,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
+            })),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_getbundle_comma_separated_heads() {
+        // Some clients send `heads`/`common` as comma- rather than
+        // space-separated hashes.
+        let inp = "getbundle\n\
+             * 2\n\
+             heads 81\n\
+             1111111111111111111111111111111111111111,2222222222222222222222222222222222222222\
+             common 40\n\
+             3333333333333333333333333333333333333333";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: Some(vec![hash_ones(), hash_twos()]),
+                common: Some(vec![hash_threes()]),
+                bundlecaps: hashset![],
+                bundle_version: None,
+                listkeys: vec![],
+                phases: false,
+                phase_heads: None,
+                cbattempted: false,
+                obsmarkers: false,
+                cg: true,
             })),
         );
     }
@@ -1423,14 +3303,42 @@ mod test_parse {
     fn test_parse_heads() {
         let inp = "heads\n";
 
-        test_parse(inp, Request::Single(SingleRequest::Heads {}));
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Heads { bookmarks: false }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_heads_bookmarks() {
+        let inp = "heads\nbookmarks 1\n1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Heads { bookmarks: true }),
+        );
     }
 
     #[mononoke::test]
     fn test_parse_hello() {
         let inp = "hello\n";
 
-        test_parse(inp, Request::Single(SingleRequest::Hello {}));
+        test_parse(inp, Request::Single(SingleRequest::Hello { payload: None }));
+    }
+
+    #[mononoke::test]
+    fn test_parse_hello_with_payload() {
+        let inp = "hello\n\
+                   * 1\n\
+                   payload 13\n\
+                   mercurial-ssh";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Hello {
+                payload: Some("mercurial-ssh".to_string()),
+            }),
+        );
     }
 
     #[mononoke::test]
@@ -1447,6 +3355,92 @@ mod test_parse {
         );
     }
 
+    #[mononoke::test]
+    fn test_parse_listkeys_percent_encoded_namespace() {
+        let inp = "listkeys\n\
+                   namespace 15\n\
+                   book%2dmarks%21";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Listkeys {
+                namespace: "book-marks!".to_string(),
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_pushkey_bookmark_move() {
+        let inp = "pushkey\n\
+                   namespace 9\n\
+                   bookmarkskey 6\n\
+                   masterold 12\n\
+                   aaaaaaaaaaaanew 12\n\
+                   bbbbbbbbbbbb";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: "master".to_string(),
+                old: "aaaaaaaaaaaa".to_string(),
+                new: "bbbbbbbbbbbb".to_string(),
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_pushkey_empty_old_creation() {
+        let inp = "pushkey\n\
+                   namespace 6\n\
+                   phasesold 0\n\
+                   key 6\n\
+                   abcdefnew 1\n\
+                   1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "phases".to_string(),
+                key: "abcdef".to_string(),
+                old: "".to_string(),
+                new: "1".to_string(),
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_listkeyspaged() {
+        let inp = "listkeyspaged\n\
+                   namespace 9\n\
+                   bookmarksoffset 1\n\
+                   0limit 3\n\
+                   100";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::ListkeysPaged {
+                namespace: "bookmarks".to_string(),
+                offset: 0,
+                limit: 100,
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_listkeyspaged_limit_too_large() {
+        let inp = b"listkeyspaged\n\
+                   namespace 9\n\
+                   bookmarksoffset 1\n\
+                   0limit 6\n\
+                   999999";
+
+        match parse_singlerequest(inp) {
+            IResult::Error(_) => {}
+            other => panic!("expected limit above the maximum to be rejected, got {:?}", other),
+        }
+    }
+
     #[mononoke::test]
     fn test_parse_lookup() {
         let inp = "lookup\n\
@@ -1457,6 +3451,7 @@ mod test_parse {
             inp,
             Request::Single(SingleRequest::Lookup {
                 key: "bookmarks".to_string(),
+                kind: LookupKind::Key,
             }),
         );
     }
@@ -1471,10 +3466,39 @@ mod test_parse {
             inp,
             Request::Single(SingleRequest::Lookup {
                 key: "5c79".to_string(),
+                kind: LookupKind::Key,
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_lookup_revset_expression() {
+        let inp = "lookup\n\
+                   key 10\n\
+                   ::master-1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Lookup {
+                key: "::master-1".to_string(),
+                kind: LookupKind::RevsetExpression,
             }),
         );
     }
 
+    #[mononoke::test]
+    fn test_parse_lookup_bad_utf8_key_reports_detail() {
+        // The value for `key` is a single byte that isn't valid UTF-8.
+        let mut buf = BytesMut::from(&b"lookup\nkey 1\n\xff"[..]);
+
+        let err = parse_request(&mut buf).expect_err("should fail: key is not valid UTF-8");
+        assert!(
+            err.to_string().contains("UTF-8"),
+            "error did not mention UTF-8: {}",
+            err
+        );
+    }
+
     #[mononoke::test]
     fn test_parse_gettreepack() {
         let inp = "gettreepack\n\
@@ -1591,6 +3615,27 @@ mod test_parse {
         test_parse(inp, Request::Single(SingleRequest::Known { nodes: vec![] }));
     }
 
+    #[mononoke::test]
+    fn test_parse_known_large_list() {
+        let nodes: Vec<HgChangesetId> = (0..1000).map(|_| hash_ones()).collect();
+        let nodes_str = nodes
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let inp = format!(
+            "known\n\
+             * 0\n\
+             nodes {}\n\
+             {}",
+            nodes_str.len(),
+            nodes_str
+        );
+
+        test_parse(inp, Request::Single(SingleRequest::Known { nodes }));
+    }
+
     fn test_parse_unbundle_with(bundle: &[u8]) {
         let inp = b"unbundle\n\
                     heads 10\n\
@@ -1617,18 +3662,75 @@ mod test_parse {
         test_parse_unbundle_with(bundle);
     }
 
+    #[mononoke::test]
+    fn test_parse_request_with_tail_unbundle() {
+        let bundle: &[u8] = &include_bytes!("../../fixtures/min.bundle")[..];
+        let inp = b"unbundle\n\
+                    heads 10\n\
+                    666f726365"; // "force" hex encoded
+
+        let mut buf = BytesMut::from(&inp[..]);
+        buf.extend_from_slice(bundle);
+
+        let (req, tail) = parse_request_with_tail(&mut buf)
+            .expect("parse should succeed")
+            .expect("request should be complete");
+
+        assert_eq!(
+            req,
+            Request::Single(SingleRequest::Unbundle {
+                heads: vec![String::from("666f726365")],
+            })
+        );
+        assert_eq!(
+            &tail[..], bundle,
+            "bundle payload should be returned as the tail"
+        );
+        assert!(buf.is_empty(), "tail should be drained out of the buffer");
+    }
+
     #[mononoke::test]
     fn test_batch_parse_heads() {
-        match parse_with_params(b"heads\n", batch_params) {
+        match parse_with_params(b"heads\n", batch_params, false) {
             IResult::Done(rest, val) => {
                 assert!(rest.is_empty());
-                assert_eq!(val, SingleRequest::Heads {});
+                assert_eq!(val, SingleRequest::Heads { bookmarks: false });
             }
             IResult::Incomplete(_) => panic!("unexpected incomplete input"),
             IResult::Error(err) => panic!("failed with {:?}", err),
         }
     }
 
+    #[mononoke::test]
+    fn test_parse_with_params_unknown_command_rejected() {
+        match parse_with_params(b"madeupcommand\n* 0\n", params, false) {
+            IResult::Error(_) => (),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_parse_with_params_unknown_command_captured() {
+        let inp = b"madeupcommand\n\
+                    * 1\n\
+                    foo 3\n\
+                    bar";
+
+        match parse_with_params(inp, params, true) {
+            IResult::Done(rest, val) => {
+                assert!(rest.is_empty());
+                assert_eq!(
+                    val,
+                    SingleRequest::Unknown {
+                        name: "madeupcommand".to_string(),
+                        args: hashmap! { b"foo".to_vec() => b"bar".to_vec() },
+                    }
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
     #[mononoke::test]
     fn test_parse_batch_heads() {
         let inp = "batch\n\
@@ -1641,15 +3743,42 @@ mod test_parse {
 
         test_parse(
             inp,
-            Request::Batch(vec![
-                SingleRequest::Heads {},
-                SingleRequest::Lookup {
-                    key: "1234".to_string(),
-                },
-                SingleRequest::Known {
-                    nodes: vec![hash_ones(), hash_twos()],
-                },
-            ]),
+            Request::Batch {
+                cmds: vec![
+                    SingleRequest::Heads { bookmarks: false },
+                    SingleRequest::Lookup {
+                        key: "1234".to_string(),
+                        kind: LookupKind::Key,
+                    },
+                    SingleRequest::Known {
+                        nodes: vec![hash_ones(), hash_twos()],
+                    },
+                ],
+                aborted: false,
+            },
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_stream_out() {
+        let inp = "stream_out\n\
+                   * 0\n";
+
+        test_parse(inp, Request::Single(SingleRequest::StreamOut { tag: None }));
+    }
+
+    #[mononoke::test]
+    fn test_parse_stream_out_with_tag() {
+        let inp = "stream_out\n\
+                   * 1\n\
+                   tag 6\n\
+                   mytag1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::StreamOut {
+                tag: Some("mytag1".to_string()),
+            }),
         );
     }
 
@@ -1662,7 +3791,26 @@ mod test_parse {
 
         test_parse(
             inp,
-            Request::Single(SingleRequest::StreamOutShallow { tag: None }),
+            Request::Single(SingleRequest::StreamOutShallow {
+                tag: None,
+                noflatmanifest: true,
+            }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_stream_out_shallow_false() {
+        let inp = "stream_out_shallow\n\
+                   * 1\n\
+                   noflatmanifest 5\n\
+                   False";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::StreamOutShallow {
+                tag: None,
+                noflatmanifest: false,
+            }),
         );
     }
 
@@ -1693,4 +3841,214 @@ mod test_parse {
             }),
         );
     }
+
+    #[mononoke::test]
+    fn test_parse_getcommitdata_empty() {
+        let input = "getcommitdata\n\
+                     nodes 0\n";
+
+        test_parse(
+            input,
+            Request::Single(SingleRequest::GetCommitData { nodes: vec![] }),
+        );
+    }
+
+    #[mononoke::test]
+    fn test_parse_batch_getcommitdata() {
+        let inp = "batch\n\
+                   * 0\n\
+                   cmds 20\n\
+                   getcommitdata nodes=";
+
+        test_parse(
+            inp,
+            Request::Batch {
+                cmds: vec![SingleRequest::GetCommitData { nodes: vec![] }],
+                aborted: false,
+            },
+        )
+    }
+
+    #[mononoke::test]
+    fn test_parse_batch_abort_mid_stream() {
+        let inp = "batch\n\
+                   * 0\n\
+                   cmds 26\n\
+                   hello ;abort ;known nodes=";
+
+        test_parse(
+            inp,
+            Request::Batch {
+                cmds: vec![SingleRequest::Hello { payload: None }],
+                aborted: true,
+            },
+        )
+    }
+
+    /// Encode `req`, parse the result back, and assert it reproduces `req`.
+    fn test_roundtrip(req: SingleRequest) {
+        let mut buf = BytesMut::new();
+        encode_request(&req, &mut buf);
+        let mut parse_buf = buf.clone();
+        match parse_request(&mut parse_buf) {
+            Ok(Some(Request::Single(parsed))) => assert_eq!(
+                parsed, req,
+                "roundtrip mismatch, encoded as {:?}",
+                Bytes::from(buf.to_vec())
+            ),
+            other => panic!(
+                "expected a single request roundtripping {:?}, got {:?}",
+                req, other
+            ),
+        }
+    }
+
+    #[mononoke::test]
+    fn test_roundtrip_all_variants() {
+        test_roundtrip(SingleRequest::Between {
+            pairs: vec![(hash_ones(), hash_twos())],
+        });
+        test_roundtrip(SingleRequest::Branchmap);
+        test_roundtrip(SingleRequest::Capabilities);
+        test_roundtrip(SingleRequest::ClientTelemetry {
+            args: hashmap! { b"key".to_vec() => b"value".to_vec() },
+        });
+        test_roundtrip(SingleRequest::Debugwireargs {
+            one: b"ONE".to_vec(),
+            two: b"TWO".to_vec(),
+            all_args: hashmap! {
+                b"one".to_vec() => b"ONE".to_vec(),
+                b"two".to_vec() => b"TWO".to_vec(),
+                b"extra".to_vec() => b"stuff".to_vec(),
+            },
+        });
+        test_roundtrip(SingleRequest::Getbundle(GetbundleArgs {
+            heads: Some(vec![hash_ones(), hash_twos()]),
+            common: Some(vec![hash_threes()]),
+            bundlecaps: hashset! { b"HG20".to_vec() },
+            bundle_version: Some("02".to_string()),
+            listkeys: vec![b"bookmarks".to_vec()],
+            phases: true,
+            phase_heads: None,
+            cbattempted: true,
+            obsmarkers: false,
+            cg: true,
+        }));
+        test_roundtrip(SingleRequest::Getbundle(GetbundleArgs {
+            phase_heads: Some(vec![hash_fours()]),
+            cg: false,
+            ..Default::default()
+        }));
+        test_roundtrip(SingleRequest::DebugGetbundle(
+            GetbundleArgs {
+                heads: Some(vec![hash_ones()]),
+                ..Default::default()
+            },
+            true,
+        ));
+        test_roundtrip(SingleRequest::Heads { bookmarks: false });
+        test_roundtrip(SingleRequest::Heads { bookmarks: true });
+        test_roundtrip(SingleRequest::Hello { payload: None });
+        test_roundtrip(SingleRequest::Hello {
+            payload: Some("mercurial-ssh".to_string()),
+        });
+        test_roundtrip(SingleRequest::Listkeys {
+            namespace: "bookmarks".to_string(),
+        });
+        test_roundtrip(SingleRequest::Pushkey {
+            namespace: "bookmarks".to_string(),
+            key: "master".to_string(),
+            old: "".to_string(),
+            new: hash_ones().to_string(),
+        });
+        test_roundtrip(SingleRequest::ListkeysPaged {
+            namespace: "bookmarks".to_string(),
+            offset: 10,
+            limit: 100,
+        });
+        test_roundtrip(SingleRequest::ListKeysPatterns {
+            namespace: "bookmarks".to_string(),
+            patterns: vec!["foo".to_string(), "bar/baz".to_string()],
+        });
+        test_roundtrip(SingleRequest::Lookup {
+            key: "master".to_string(),
+            kind: LookupKind::Key,
+        });
+        test_roundtrip(SingleRequest::Known {
+            nodes: vec![hash_ones(), hash_twos()],
+        });
+        test_roundtrip(SingleRequest::Knownnodes {
+            nodes: vec![hash_ones(), hash_twos()],
+        });
+        test_roundtrip(SingleRequest::Unbundle {
+            heads: vec!["force".to_string()],
+        });
+        test_roundtrip(SingleRequest::UnbundleReplay {
+            heads: vec!["force".to_string()],
+            replaydata: "replay".to_string(),
+            respondlightly: true,
+        });
+        test_roundtrip(SingleRequest::Gettreepack(GettreepackArgs {
+            rootdir: MPath::new("ololo").unwrap(),
+            mfnodes: vec![hash_ones_manifest(), hash_twos_manifest()],
+            basemfnodes: btreeset![hash_ones_manifest()],
+            directories: vec![Bytes::from("foo".as_bytes()), Bytes::from(",".as_bytes())],
+            depth: Some(3),
+        }));
+        test_roundtrip(SingleRequest::Gettreepack(GettreepackArgs::default()));
+        test_roundtrip(SingleRequest::StreamOut { tag: None });
+        test_roundtrip(SingleRequest::StreamOut {
+            tag: Some("mytag".to_string()),
+        });
+        test_roundtrip(SingleRequest::StreamOutShallow {
+            tag: None,
+            noflatmanifest: false,
+        });
+        test_roundtrip(SingleRequest::StreamOutShallow {
+            tag: Some("mytag".to_string()),
+            noflatmanifest: true,
+        });
+        test_roundtrip(SingleRequest::GetpackV1);
+        test_roundtrip(SingleRequest::GetpackV2);
+        test_roundtrip(SingleRequest::GetCommitData {
+            nodes: vec![hash_ones(), hash_twos()],
+        });
+        test_roundtrip(SingleRequest::Getfiles { files: vec![] });
+        test_roundtrip(SingleRequest::Getfiles {
+            files: vec![
+                (hash_ones().into_nodehash(), Bytes::from_static(b"foo/bar")),
+                (hash_twos().into_nodehash(), Bytes::from_static(b"baz")),
+            ],
+        });
+        test_roundtrip(SingleRequest::Protocaps { caps: vec![] });
+        test_roundtrip(SingleRequest::Protocaps {
+            caps: vec!["partre".to_string(), "commondata".to_string()],
+        });
+    }
+
+    #[mononoke::test]
+    fn test_getfiles_list_empty() {
+        assert_eq!(getfiles_list(b""), IResult::Done(&b""[..], vec![]));
+    }
+
+    #[mononoke::test]
+    fn test_getfiles_list_two_entries() {
+        let node1 = hash_ones().into_nodehash();
+        let node2 = hash_twos().into_nodehash();
+        let mut inp = node1.to_string().into_bytes();
+        inp.extend_from_slice(b"foo/bar\n");
+        inp.extend_from_slice(node2.to_string().as_bytes());
+        inp.extend_from_slice(b"baz");
+
+        assert_eq!(
+            getfiles_list(&inp),
+            IResult::Done(
+                &b""[..],
+                vec![
+                    (node1, Bytes::from_static(b"foo/bar")),
+                    (node2, Bytes::from_static(b"baz")),
+                ]
+            )
+        );
+    }
 }
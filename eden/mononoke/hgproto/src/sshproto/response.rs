@@ -131,6 +131,14 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        Pushkey(success) => {
+            if success {
+                Bytes::from(b"1\n".as_ref())
+            } else {
+                Bytes::from(b"0\n".as_ref())
+            }
+        }
+
         ReadyForStream => Bytes::from(b"0\n".as_ref()),
 
         // TODO(luk, T25574469) The response for Unbundle should be chunked stream of bundle2
@@ -138,6 +146,8 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
 
         Getbundle(res) => res,
 
+        DebugGetbundle(res) => res,
+
         Gettreepack(res) => res,
 
         Lookup(res) => res,
@@ -169,6 +179,7 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
             Bytes::new()
         }
 
+        StreamOut(res) => res,
         StreamOutShallow(res) => res,
 
         Getpackv1(res) => res,
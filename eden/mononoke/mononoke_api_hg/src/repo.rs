@@ -462,8 +462,9 @@ impl<R: MononokeRepo> HgRepoContext<R> {
             rootdir: path,
             mfnodes: root_versions.into_iter().collect(),
             basemfnodes: base_versions.into_iter().collect(),
-            directories: vec![], // Not supported.
             depth,
+            // `directories` is not supported here; defaults to empty.
+            ..Default::default()
         };
 
         gettreepack_entries(ctx, repo, args)
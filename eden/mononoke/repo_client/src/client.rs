@@ -505,12 +505,20 @@ impl<R: Repo> RepoClient<R> {
 
         let GetbundleArgs {
             bundlecaps,
+            bundle_version: _,
             common,
             heads,
             phases,
+            phase_heads: _,
             listkeys,
+            cbattempted: _,
+            obsmarkers: _,
+            cg: _,
         } = args;
 
+        let common = common.unwrap_or_default();
+        let heads = heads.unwrap_or_default();
+
         let mut use_phases = phases;
         if use_phases {
             for cap in &bundlecaps {
@@ -391,6 +391,10 @@ impl AddScubaParams for thrift::CommitPathBlameParams {
                 .join("|");
             scuba.add("param_format_options", repr);
         }
+        scuba.add(
+            "blame_follow_renames",
+            self.follow_mutable_file_history.unwrap_or(false),
+        );
         self.identity_schemes.add_scuba_params(scuba);
     }
 }
@@ -659,6 +663,7 @@ impl AddScubaParams for thrift::RepoUpdateSubmoduleExpansionParams {}
 impl AddScubaParams for thrift::RepoUploadNonBlobGitObjectParams {
     fn add_scuba_params(&self, scuba: &mut MononokeScubaSampleBuilder) {
         scuba.add("param_git_object_id", hex_string(&self.git_hash));
+        scuba.add("param_data_len", self.raw_content.len());
     }
 }
 
@@ -687,6 +692,7 @@ impl AddScubaParams for thrift::RepoStackGitBundleStoreParams {
 impl AddScubaParams for thrift::RepoUploadPackfileBaseItemParams {
     fn add_scuba_params(&self, scuba: &mut MononokeScubaSampleBuilder) {
         scuba.add("param_git_object_id", hex_string(&self.git_hash));
+        scuba.add("param_data_len", self.raw_content.len());
     }
 }
 
@@ -13,6 +13,14 @@ use source_control::{self as thrift};
 /// A trait for logging a thrift `Response` struct to scuba.
 pub(crate) trait AddScubaResponse: Send + Sync {
     fn add_scuba_response(&self, _scuba: &mut MononokeScubaSampleBuilder) {}
+
+    /// Log the serialized thrift byte size of this response. This has a single shared
+    /// implementation (rather than being overridden per response type) since the size is
+    /// computed the same way for every response and is called once from the dispatch, with
+    /// the size already known.
+    fn add_serialized_size(&self, scuba: &mut MononokeScubaSampleBuilder, size: usize) {
+        scuba.add("response_serialized_size", size);
+    }
 }
 
 impl AddScubaResponse for bool {}
@@ -44,6 +52,13 @@ impl AddScubaResponse for thrift::RepoCreateStackResponse {
     }
 }
 
+// `RepoCreateBookmarkResponse`, `RepoMoveBookmarkResponse` and
+// `RepoDeleteBookmarkResponse` carry no fields of their own: the bookmark
+// name and target commit are logged from the request params instead (see
+// `AddScubaParams` impls for `RepoCreateBookmarkParams`,
+// `RepoMoveBookmarkParams` and `RepoDeleteBookmarkParams` in
+// `scuba_params.rs`), onto the same scuba sample these responses are logged
+// to.
 impl AddScubaResponse for thrift::RepoCreateBookmarkResponse {}
 
 impl AddScubaResponse for thrift::RepoMoveBookmarkResponse {}
@@ -115,9 +130,34 @@ impl AddScubaResponse for thrift::CommitListDescendantBookmarksResponse {}
 
 impl AddScubaResponse for thrift::CommitRunHooksResponse {}
 
-impl AddScubaResponse for thrift::CommitPathBlameResponse {}
+impl AddScubaResponse for thrift::CommitPathBlameResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        let distinct_range_count = match &self.blame {
+            thrift::Blame::blame_compact(blame_compact) => blame_compact.distinct_range_count,
+            thrift::Blame::UnknownField(_) => return,
+        };
+        if let Some(distinct_range_count) = distinct_range_count {
+            scuba.add("response_blame_distinct_range_count", distinct_range_count);
+        }
+        // Whether the blame followed renames isn't derivable from the response
+        // alone, since it's a request param, not a response field. See
+        // `CommitPathBlameParams`'s `add_scuba_params` for `blame_follow_renames`.
+    }
+}
 
-impl AddScubaResponse for thrift::CommitPathHistoryResponse {}
+impl AddScubaResponse for thrift::CommitPathHistoryResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        let count = match &self.history {
+            thrift::History::commit_infos(commit_infos) => commit_infos.len(),
+            thrift::History::commit_ids(commit_ids) => commit_ids.len(),
+            thrift::History::UnknownField(_) => return,
+        };
+        scuba.add("response_path_history_count", count);
+        // Whether the history was truncated by the requested limit isn't
+        // derivable from the response alone, since the limit lives on the
+        // request params rather than the response.
+    }
+}
 
 impl AddScubaResponse for thrift::CommitPathExistsResponse {}
 
@@ -129,7 +169,25 @@ impl AddScubaResponse for thrift::CommitPathLastChangedResponse {}
 
 impl AddScubaResponse for thrift::CommitMultiplePathLastChangedResponse {}
 
-impl AddScubaResponse for thrift::CommitSparseProfileDeltaResponse {}
+impl AddScubaResponse for thrift::CommitSparseProfileDeltaResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        let Some(changed_sparse_profiles) = &self.changed_sparse_profiles else {
+            return;
+        };
+        let size_changes = &changed_sparse_profiles.size_changes;
+        scuba.add("response_sparse_delta_count", size_changes.len());
+        let total_delta_size: i64 = size_changes
+            .values()
+            .map(|change| match &change.change {
+                thrift::SparseProfileChangeElement::added(added) => added.size,
+                thrift::SparseProfileChangeElement::removed(removed) => removed.previous_size,
+                thrift::SparseProfileChangeElement::changed(changed) => changed.size_change.abs(),
+                thrift::SparseProfileChangeElement::UnknownField(_) => 0,
+            })
+            .sum();
+        scuba.add("response_sparse_delta_size", total_delta_size);
+    }
+}
 
 impl AddScubaResponse for thrift::CommitSparseProfileSizeResponse {}
 
@@ -160,7 +218,21 @@ impl AddScubaResponse for thrift::FileChunk {}
 
 impl AddScubaResponse for thrift::FileInfo {}
 
-impl AddScubaResponse for thrift::FileDiffResponse {}
+impl AddScubaResponse for thrift::FileDiffResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        let thrift::Diff::raw_diff(raw_diff) = &self.diff else {
+            return;
+        };
+        // `raw_diff` is optional on the wire: when it's absent, the diff
+        // itself was omitted from the response (e.g. because it was too
+        // large), as distinct from a present-but-empty diff.
+        scuba.add("diff_truncated", raw_diff.raw_diff.is_none());
+        scuba.add(
+            "response_diff_size",
+            raw_diff.raw_diff.as_ref().map_or(0, Vec::len),
+        );
+    }
+}
 
 impl AddScubaResponse for thrift::TreeListResponse {}
 
@@ -185,7 +257,12 @@ impl AddScubaResponse for thrift::MegarepoAddConfigResponse {}
 
 impl AddScubaResponse for thrift::MegarepoReadConfigResponse {}
 
-impl AddScubaResponse for thrift::CloudWorkspaceInfoResponse {}
+impl AddScubaResponse for thrift::CloudWorkspaceInfoResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        scuba.add("workspace_name", self.workspace_info.specifier.name.clone());
+        // TODO: log heads/bookmarks counts once WorkspaceInfo carries them.
+    }
+}
 
 impl AddScubaResponse for thrift::CloudUserWorkspacesResponse {}
 
@@ -267,8 +344,15 @@ impl AddScubaResponse for thrift::MegarepoSyncChangesetToken {
     }
 }
 
-// TODO(T179531912): Log responses to scuba
-impl AddScubaResponse for thrift::RepoUpdateSubmoduleExpansionResponse {}
+impl AddScubaResponse for thrift::RepoUpdateSubmoduleExpansionResponse {
+    fn add_scuba_response(&self, scuba: &mut MononokeScubaSampleBuilder) {
+        if let Some(id) = self.ids.get(&thrift::CommitIdentityScheme::BONSAI) {
+            scuba.add("commit", id.to_string());
+        }
+        // TODO: log the updated submodule path once it's threaded through from
+        // the request params.
+    }
+}
 
 impl AddScubaResponse for thrift::RepoUploadNonBlobGitObjectResponse {}
 impl AddScubaResponse for thrift::CreateGitTreeResponse {}
@@ -307,3 +391,175 @@ impl AddScubaResponse for thrift::AsyncPingResponse {
         scuba.add("response_payload", self.payload.clone());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Log `response` to a fresh, discarding sample and return its logged
+    /// columns as a JSON string, for substring assertions below (the exact
+    /// shape of `ScubaSample::to_json`'s output isn't part of this crate's
+    /// contract, so we don't assert on it structurally).
+    fn logged_columns<R: AddScubaResponse>(response: &R) -> String {
+        let mut scuba = MononokeScubaSampleBuilder::with_discard();
+        response.add_scuba_response(&mut scuba);
+        scuba
+            .get_sample()
+            .to_json()
+            .expect("scuba sample should serialize to json")
+            .to_string()
+    }
+
+    #[test]
+    fn test_cloud_workspace_info_response_scuba() {
+        let response = thrift::CloudWorkspaceInfoResponse {
+            workspace_info: thrift::WorkspaceInfo {
+                specifier: thrift::WorkspaceSpecifier {
+                    name: "user/foo/default".to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("workspace_name"));
+        assert!(json.contains("user/foo/default"));
+    }
+
+    #[test]
+    fn test_repo_update_submodule_expansion_response_scuba() {
+        let mut ids = BTreeMap::new();
+        ids.insert(
+            thrift::CommitIdentityScheme::BONSAI,
+            thrift::CommitId::bonsai(vec![0xaa; 32]),
+        );
+        let response = thrift::RepoUpdateSubmoduleExpansionResponse {
+            ids,
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("commit"));
+        assert!(json.contains(&faster_hex::hex_string(&[0xaa; 32])));
+    }
+
+    #[test]
+    fn test_commit_path_history_response_scuba_commit_infos() {
+        let response = thrift::CommitPathHistoryResponse {
+            history: thrift::History::commit_infos(vec![
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            ]),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("response_path_history_count"));
+        assert!(json.contains('3'));
+    }
+
+    #[test]
+    fn test_commit_path_history_response_scuba_commit_ids() {
+        let response = thrift::CommitPathHistoryResponse {
+            history: thrift::History::commit_ids(vec![Default::default(), Default::default()]),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("response_path_history_count"));
+        assert!(json.contains('2'));
+    }
+
+    #[test]
+    fn test_bookmark_mutation_responses_defer_to_params() {
+        // These responses carry no fields of their own: the bookmark name
+        // and target commit are logged from the request params instead (see
+        // `AddScubaParams` in `scuba_params.rs`), so logging the response
+        // alone should add nothing to the sample.
+        assert_eq!(
+            logged_columns(&thrift::RepoCreateBookmarkResponse::default()),
+            "{}"
+        );
+        assert_eq!(
+            logged_columns(&thrift::RepoMoveBookmarkResponse::default()),
+            "{}"
+        );
+        assert_eq!(
+            logged_columns(&thrift::RepoDeleteBookmarkResponse::default()),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn test_commit_sparse_profile_delta_response_scuba() {
+        let mut size_changes = BTreeMap::new();
+        size_changes.insert(
+            "my_profile".to_string(),
+            thrift::SparseProfileChange {
+                change: thrift::SparseProfileChangeElement::added(thrift::SparseProfileAdded {
+                    size: 42,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let response = thrift::CommitSparseProfileDeltaResponse {
+            changed_sparse_profiles: Some(thrift::SparseProfileDeltaSizes {
+                size_changes,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("response_sparse_delta_count"));
+        assert!(json.contains("response_sparse_delta_size"));
+        assert!(json.contains('1'));
+        assert!(json.contains("42"));
+    }
+
+    #[test]
+    fn test_commit_path_blame_response_scuba() {
+        let response = thrift::CommitPathBlameResponse {
+            blame: thrift::Blame::blame_compact(thrift::BlameCompact {
+                distinct_range_count: Some(7),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("response_blame_distinct_range_count"));
+        assert!(json.contains('7'));
+    }
+
+    #[test]
+    fn test_file_diff_response_scuba_truncated() {
+        let response = thrift::FileDiffResponse {
+            diff: thrift::Diff::raw_diff(thrift::RawDiff {
+                raw_diff: None,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("diff_truncated"));
+        assert!(json.contains("true"));
+        assert!(json.contains("response_diff_size"));
+    }
+
+    #[test]
+    fn test_file_diff_response_scuba_complete() {
+        let response = thrift::FileDiffResponse {
+            diff: thrift::Diff::raw_diff(thrift::RawDiff {
+                raw_diff: Some(vec![0; 10]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let json = logged_columns(&response);
+        assert!(json.contains("diff_truncated"));
+        assert!(json.contains("false"));
+        assert!(json.contains("response_diff_size"));
+        assert!(json.contains("10"));
+    }
+}
@@ -13,6 +13,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 
 use async_requests::AsyncMethodRequestQueue;
+use bufsize::SizeCounter;
 use clientinfo::ClientEntryPoint;
 use clientinfo::ClientInfo;
 use clientinfo::CLIENT_INFO_HEADER;
@@ -22,6 +23,9 @@ use ephemeral_blobstore::BubbleId;
 use ephemeral_blobstore::RepoEphemeralStore;
 use factory_group::FactoryGroup;
 use fbinit::FacebookInit;
+use fbthrift::compact_protocol;
+use fbthrift::compact_protocol::CompactProtocolSerializer;
+use fbthrift::serialize::Serialize as ThriftSerialize;
 use futures::future::BoxFuture;
 use futures::try_join;
 use futures::FutureExt;
@@ -644,7 +648,7 @@ fn add_request_end_memory_stats(
     }
 }
 
-fn log_result<T: AddScubaResponse>(
+fn log_result<T: AddScubaResponse + ThriftSerialize<CompactProtocolSerializer<SizeCounter>>>(
     ctx: CoreContext,
     method: &str,
     stats: &FutureStats,
@@ -658,6 +662,7 @@ fn log_result<T: AddScubaResponse>(
     let (status, error, invalid_request, internal_failure, overloaded) = match result {
         Ok(response) => {
             response.add_scuba_response(&mut scuba);
+            response.add_serialized_size(&mut scuba, compact_protocol::serialize_size(response));
             ("SUCCESS", None, 0, 0, 0)
         }
         Err(err) => {